@@ -7,3 +7,6 @@ glslc ./shaders/colored_triangle.frag -o ./shaders/colored_triangle.frag.spv
 glslc ./shaders/colored_triangle.vert -o ./shaders/colored_triangle.vert.spv
 
 glslc ./shaders/tri_mesh.vert -o ./shaders/tri_mesh.vert.spv
+
+glslc ./shaders/default_lit.frag -o ./shaders/default_lit.frag.spv
+glslc ./shaders/textured_lit.frag -o ./shaders/textured_lit.frag.spv
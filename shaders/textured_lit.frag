@@ -0,0 +1,28 @@
+#version 450
+
+//shader input
+layout (location = 0) in vec3 inColor;
+layout (location = 1) in vec2 texCoord;
+
+//output write
+layout (location = 0) out vec4 outFragColor;
+
+// global scene parameters, read at a dynamic offset for the current frame
+layout(set = 0, binding = 1) uniform SceneData
+{
+	vec4 fogColor; // w is for exponent
+	vec4 fogDistances; //x for min, y for max, zw unused.
+	vec4 ambientColor;
+	vec4 sunlightDirection; //w for sun power
+	vec4 sunlightColor;
+} sceneData;
+
+// the material's texture
+layout(set = 1, binding = 0) uniform sampler2D tex1;
+
+
+void main()
+{
+	vec3 color = texture(tex1, texCoord).xyz;
+	outFragColor = vec4(color, 1.0f);
+}
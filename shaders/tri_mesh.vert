@@ -3,8 +3,18 @@
 layout (location = 0) in vec3 vPosition;
 layout (location = 1) in vec3 vNormal;
 layout (location = 2) in vec3 vColor;
+layout (location = 3) in vec2 vTexCoord;
 
 layout (location = 0) out vec3 outColor;
+layout (location = 1) out vec2 texCoord;
+
+// camera matrices, written once per frame
+layout(set = 0, binding = 0) uniform CameraBuffer
+{
+	mat4 view;
+	mat4 proj;
+	mat4 viewproj;
+} cameraData;
 
 // push constants block
 layout( push_constant) uniform constants
@@ -15,6 +25,8 @@ layout( push_constant) uniform constants
 
 void main()
 {
-	gl_Position = PushConstants.render_matrix * vec4(vPosition, 1.0f);
+	mat4 transformMatrix = (cameraData.viewproj * PushConstants.render_matrix);
+	gl_Position = transformMatrix * vec4(vPosition, 1.0f);
 	outColor = vColor;
+	texCoord = vTexCoord;
 }
@@ -0,0 +1,191 @@
+use {
+  crate::input::Input,
+  lina::{mat4::Mat4, vec3::Vec3},
+};
+
+// A fly-through camera. Yaw 0 and pitch 0 look down -z with y up, positive yaw turns
+// right and positive pitch looks up. The move_* actions (WASD, Q and E by default) move
+// along where the camera looks and the mouse turns it. The window grabs the mouse in
+// relative mode, so the motion keeps coming when the pointer would have left the window.
+//
+// toggle_orbit switches to orbiting around target: the mouse swings the camera around
+// it, move_forward/move_back and the wheel move closer and further away.
+
+// a little short of straight up and down, the view matrix flips over at 90 degrees
+const MAX_PITCH: f32 = 1.55;
+// the orbit can't get closer than this to its target
+const MIN_DISTANCE: f32 = 0.5;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum CameraMode {
+  Fly,
+  Orbit,
+}
+
+pub struct Camera {
+  pub position: Vec3,
+  pub yaw: f32,   // radians
+  pub pitch: f32, // radians
+  pub fov: f32,   // vertical, degrees
+  pub near: f32,
+  pub far: f32,
+
+  pub mode: CameraMode,
+  pub target: Vec3,  // what the orbit goes around
+  pub distance: f32, // from the target when orbiting
+
+  pub speed: f32,       // units per second, move_fast is four times as fast
+  pub sensitivity: f32, // radians per pixel of mouse motion
+}
+
+impl Camera {
+  // the view the engine always had, from above and behind the origin looking down -z
+  pub fn new() -> Camera {
+    Camera {
+      position: Vec3::new(0.0, 6.0, 10.0),
+      yaw: 0.0,
+      pitch: 0.0,
+      fov: 70.0,
+      near: 0.1,
+      far: 200.0,
+
+      mode: CameraMode::Fly,
+      target: Vec3::new(0.0, 0.0, 0.0),
+      distance: 10.0,
+
+      speed: 5.0,
+      sensitivity: 0.003,
+    }
+  }
+
+  // Moves with this frame's input, dt is the frame time in seconds so the speed doesn't
+  // depend on the frame rate.
+  pub fn update(&mut self, input: &Input, dt: f32) {
+    if input.is_pressed("toggle_orbit") {
+      self.toggle_orbit();
+    }
+    let (dx, dy) = input.mouse_motion();
+    self.turn(dx, dy);
+
+    let speed = if input.is_held("move_fast") {
+      self.speed * 4.0
+    } else {
+      self.speed
+    };
+    let step = speed * dt;
+
+    let forward_amount = input.axis("move_forward", "move_back");
+    match self.mode {
+      CameraMode::Fly => {
+        let forward = self.forward();
+        let right = self.right();
+        let right_amount = input.axis("move_right", "move_left");
+        // up and down go along the world y, not the tilted camera
+        let up_amount = input.axis("move_up", "move_down");
+        self.position.x += (forward.x * forward_amount + right.x * right_amount) * step;
+        self.position.y += (forward.y * forward_amount + up_amount) * step;
+        self.position.z += (forward.z * forward_amount + right.z * right_amount) * step;
+      }
+      CameraMode::Orbit => {
+        self.zoom(input.wheel());
+        self.distance = f32::max(self.distance - forward_amount * step, MIN_DISTANCE);
+        self.place_on_orbit();
+      }
+    }
+  }
+
+  // world to camera, looking along forward with y up
+  pub fn view_matrix(&self) -> Mat4 {
+    let f = self.forward();
+    let r = self.right();
+    // up of the camera, right x forward
+    let u = Vec3::new(
+      r.y * f.z - r.z * f.y,
+      r.z * f.x - r.x * f.z,
+      r.x * f.y - r.y * f.x,
+    );
+    let p = self.position;
+    let dot = |a: Vec3| a.x * p.x + a.y * p.y + a.z * p.z;
+    Mat4 {
+      c0r0: r.x,
+      c0r1: u.x,
+      c0r2: -f.x,
+      c0r3: 0.0,
+      c1r0: r.y,
+      c1r1: u.y,
+      c1r2: -f.y,
+      c1r3: 0.0,
+      c2r0: r.z,
+      c2r1: u.z,
+      c2r2: -f.z,
+      c2r3: 0.0,
+      c3r0: -dot(r),
+      c3r1: -dot(u),
+      c3r2: dot(f),
+      c3r3: 1.0,
+    }
+  }
+
+  // camera to clip space for an image of the given aspect ratio
+  pub fn projection_matrix(&self, aspect: f32) -> Mat4 {
+    let mut projection =
+      Mat4::perspective_matrix(lina::radians!(self.fov), aspect, self.near, self.far);
+    projection.c2r2 *= -1.0;
+    projection
+  }
+
+  // unit vector the camera looks along
+  pub fn forward(&self) -> Vec3 {
+    Vec3::new(
+      f32::sin(self.yaw) * f32::cos(self.pitch),
+      f32::sin(self.pitch),
+      -f32::cos(self.yaw) * f32::cos(self.pitch),
+    )
+  }
+
+  // unit vector to the right of the camera, always level
+  pub fn right(&self) -> Vec3 {
+    Vec3::new(f32::cos(self.yaw), 0.0, f32::sin(self.yaw))
+  }
+
+  // Orbiting starts around the point distance in front of the camera, so the view
+  // doesn't jump. Flying starts from wherever the orbit left the camera.
+  pub fn toggle_orbit(&mut self) {
+    match self.mode {
+      CameraMode::Fly => {
+        let f = self.forward();
+        self.target = Vec3::new(
+          self.position.x + f.x * self.distance,
+          self.position.y + f.y * self.distance,
+          self.position.z + f.z * self.distance,
+        );
+        self.mode = CameraMode::Orbit;
+      }
+      CameraMode::Orbit => self.mode = CameraMode::Fly,
+    }
+  }
+
+  // mouse motion in pixels, moving the mouse up looks up
+  fn turn(&mut self, dx: f32, dy: f32) {
+    self.yaw += dx * self.sensitivity;
+    self.pitch = f32::max(
+      -MAX_PITCH,
+      f32::min(self.pitch - dy * self.sensitivity, MAX_PITCH),
+    );
+  }
+
+  // a wheel step is a tenth of the distance, zooming is as fast close up as far away
+  fn zoom(&mut self, steps: f32) {
+    self.distance = f32::max(self.distance * (1.0 - steps * 0.1), MIN_DISTANCE);
+  }
+
+  // puts the camera distance behind the target, looking at it
+  fn place_on_orbit(&mut self) {
+    let f = self.forward();
+    self.position = Vec3::new(
+      self.target.x - f.x * self.distance,
+      self.target.y - f.y * self.distance,
+      self.target.z - f.z * self.distance,
+    );
+  }
+}
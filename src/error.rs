@@ -1,13 +1,36 @@
-use std::fmt;
+use {
+  std::fmt,
+  vkcapi::core::v1_0::{VkFormat, VkResult, VK_SUCCESS},
+};
 
 // Error handling will be different from the tutorial because Rust.
 #[derive(Debug)]
 pub enum Error {
-  FromGltf(gltf::Error),       // map_err from gltf
-  FromVkcboot(vkcboot::Error), // map_err from vkcboot
-  FromIO(std::io::Error),      // map_err from std::io::Error
-  Str(&'static str),           // error with &str message
-  String(String),              // error with String message
+  FromGltf(gltf::Error),                             // map_err from gltf
+  FromVkcboot(vkcboot::Error),                       // map_err from vkcboot
+  FromIO(std::io::Error),                            // map_err from std::io::Error
+  Str(&'static str),                                 // error with &str message
+  String(String),                                    // error with String message
+  GltfNoMesh(String),                                // gltf file name
+  GltfNoScene(String),                               // gltf file name
+  GltfMissingAttribute(String, usize, &'static str), // mesh, primitive index, attribute
+  GltfAttributeCount(String, usize, &'static str),   // mesh, primitive index, attribute
+  GltfIndexOutOfRange(String, usize, u32),           // mesh, primitive index, offending index
+  GltfUnsupportedMode(String, usize),                // mesh, primitive index
+  GltfIndexCount(String, usize, usize),              // mesh, primitive index, index count
+  FromImage(image::ImageError),                      // map_err from image
+  UnsupportedImageFormat(String, String),            // image name, pixel format
+  TextureFormat(String, VkFormat, &'static str),     // texture name, format, missing feature
+  TextureMissing(String),                            // texture name
+  ShaderCompile(String, u32, u32, String),           // file, line, column, message
+  InvalidSpirv(String, String),                      // shader, what is wrong
+  VertexInputMismatch(String, u32, String),          // shader, location, what is wrong
+  PipelineLayoutMismatch(String, u32, u32, String),  // shader, set, binding, what is wrong
+  PipelineSetLayoutUnknown(u32),                     // set
+  PipelineMissingState(&'static str),                // pipeline builder field
+  PipelineViewportCount(u32, u32),                   // viewports, scissors
+  InputConfig(String, usize, String),                // file, line, what is wrong
+  VkError(VkResult, &'static str, &'static str, &'static str, u32), // result, name, call, file, line
 }
 
 impl std::fmt::Display for Error {
@@ -18,6 +41,76 @@ impl std::fmt::Display for Error {
       Error::FromIO(e) => fmt::Display::fmt(&e, f),
       Error::Str(s) => fmt::Display::fmt(&s, f),
       Error::String(s) => fmt::Display::fmt(&s, f),
+      Error::GltfNoMesh(file) => write!(f, "{} does not contain a mesh", file),
+      Error::GltfNoScene(file) => write!(f, "{} does not contain a scene", file),
+      Error::GltfMissingAttribute(mesh, primitive, attribute) => write!(
+        f,
+        "mesh {} primitive {} has no {} attribute",
+        mesh, primitive, attribute
+      ),
+      Error::GltfAttributeCount(mesh, primitive, attribute) => write!(
+        f,
+        "mesh {} primitive {} has a different number of {} than POSITION values",
+        mesh, primitive, attribute
+      ),
+      Error::GltfIndexOutOfRange(mesh, primitive, index) => write!(
+        f,
+        "mesh {} primitive {} has out of range index {}",
+        mesh, primitive, index
+      ),
+      Error::GltfUnsupportedMode(mesh, primitive) => write!(
+        f,
+        "mesh {} primitive {} is not a triangle list",
+        mesh, primitive
+      ),
+      Error::GltfIndexCount(mesh, primitive, count) => write!(
+        f,
+        "mesh {} primitive {} has {} indices, not a whole number of triangles",
+        mesh, primitive, count
+      ),
+      Error::FromImage(e) => fmt::Display::fmt(&e, f),
+      Error::UnsupportedImageFormat(image, format) => write!(
+        f,
+        "image {} has pixel format {} which can't be made into a texture",
+        image, format
+      ),
+      Error::TextureFormat(texture, format, feature) => write!(
+        f,
+        "texture {} can't be created, format {} doesn't support {}",
+        texture, format, feature
+      ),
+      Error::TextureMissing(texture) => write!(f, "there is no texture named {}", texture),
+      Error::ShaderCompile(file, line, column, message) => {
+        write!(f, "{}:{}:{}: {}", file, line, column, message)
+      }
+      Error::InvalidSpirv(shader, message) => {
+        write!(f, "{} can't be reflected: {}", shader, message)
+      }
+      Error::VertexInputMismatch(shader, location, message) => write!(
+        f,
+        "{} vertex input at location {} {}",
+        shader, location, message
+      ),
+      Error::PipelineLayoutMismatch(shader, set, binding, message) => {
+        write!(f, "{} set {} binding {} {}", shader, set, binding, message)
+      }
+      Error::PipelineSetLayoutUnknown(set) => write!(
+        f,
+        "the layout given for set {} isn't from the descriptor layout cache, it can't be checked against the shaders",
+        set
+      ),
+      Error::PipelineMissingState(field) => {
+        write!(f, "pipeline built without {} set", field)
+      }
+      Error::PipelineViewportCount(viewports, scissors) => write!(
+        f,
+        "pipeline built with {} viewports but {} scissors, it needs one scissor per viewport",
+        viewports, scissors
+      ),
+      Error::InputConfig(file, line, message) => write!(f, "{}:{}: {}", file, line, message),
+      Error::VkError(_, name, call, file, line) => {
+        write!(f, "{}:{}: {} failed with {}", file, line, call, name)
+      }
     }
   }
 }
@@ -30,62 +123,106 @@ impl std::error::Error for Error {
       Error::FromIO(e) => Some(e),
       Error::Str(_) => None,
       Error::String(_) => None,
+      Error::GltfNoMesh(_) => None,
+      Error::GltfNoScene(_) => None,
+      Error::GltfMissingAttribute(..) => None,
+      Error::GltfAttributeCount(..) => None,
+      Error::GltfIndexOutOfRange(..) => None,
+      Error::GltfUnsupportedMode(..) => None,
+      Error::GltfIndexCount(..) => None,
+      Error::FromImage(e) => Some(e),
+      Error::UnsupportedImageFormat(..) => None,
+      Error::TextureFormat(..) => None,
+      Error::TextureMissing(_) => None,
+      Error::ShaderCompile(..) => None,
+      Error::InvalidSpirv(..) => None,
+      Error::VertexInputMismatch(..) => None,
+      Error::PipelineLayoutMismatch(..) => None,
+      Error::PipelineSetLayoutUnknown(_) => None,
+      Error::PipelineMissingState(_) => None,
+      Error::PipelineViewportCount(..) => None,
+      Error::InputConfig(..) => None,
+      Error::VkError(..) => None,
     }
   }
 }
 
-// Expanding on the tutorials macro by showing what the error code means.
+// Checks the VkResult of a Vulkan call. Anything but VK_SUCCESS comes back as
+// Error::VkError with the call and where it was made, so the caller can pass it on with
+// ? and whoever runs the engine decides what to do about it.
 #[macro_export]
 macro_rules! VK_CHECK {
-  ($func:expr) => {
-    let err: i32 = $func;
-    if err != vkcapi::core::v1_0::VK_SUCCESS {
-      let s: &str;
-      match err {
-        VK_NOT_READY => s = "VK_NOT_READY",
-        VK_TIMEOUT => s = "VK_TIMEOUT",
-        VK_EVENT_SET => s = "VK_EVENT_SET",
-        VK_EVENT_RESET => s = "VK_EVENT_RESET",
-        VK_INCOMPLETE => s = "VK_INCOMPLETE",
-        VK_ERROR_OUT_OF_HOST_MEMORY => s = "VK_ERROR_OUT_OF_HOST_MEMORY",
-        VK_ERROR_OUT_OF_DEVICE_MEMORY => s = "VK_ERROR_OUT_OF_DEVICE_MEMORY",
-        VK_ERROR_INITIALIZATION_FAILED => s = "VK_ERROR_INITIALIZATION_FAILED",
-        VK_ERROR_DEVICE_LOST => s = "VK_ERROR_DEVICE_LOST",
-        VK_ERROR_MEMORY_MAP_FAILED => s = "VK_ERROR_MEMORY_MAP_FAILED",
-        VK_ERROR_LAYER_NOT_PRESENT => s = "VK_ERROR_LAYER_NOT_PRESENT",
-        VK_ERROR_EXTENSION_NOT_PRESENT => s = "VK_ERROR_EXTENSION_NOT_PRESENT",
-        VK_ERROR_FEATURE_NOT_PRESENT => s = "VK_ERROR_FEATURE_NOT_PRESENT",
-        VK_ERROR_INCOMPATIBLE_DRIVER => s = "VK_ERROR_INCOMPATIBLE_DRIVER",
-        VK_ERROR_TOO_MANY_OBJECTS => s = "VK_ERROR_TOO_MANY_OBJECTS",
-        VK_ERROR_FORMAT_NOT_SUPPORTED => s = "VK_ERROR_FORMAT_NOT_SUPPORTED",
-        VK_ERROR_FRAGMENTED_POOL => s = "VK_ERROR_FRAGMENTED_POOL",
-        VK_ERROR_UNKNOWN => s = "VK_ERROR_UNKNOWN",
-        VK_ERROR_OUT_OF_POOL_MEMORY => s = "VK_ERROR_OUT_OF_POOL_MEMORY",
-        VK_ERROR_INVALID_EXTERNAL_HANDLE => s = "VK_ERROR_INVALID_EXTERNAL_HANDLE",
-        VK_ERROR_FRAGMENTATION => s = "VK_ERROR_FRAGMENTATION",
-        VK_ERROR_INVALID_OPAQUE_CAPTURE_ADDRESS => s = "VK_ERROR_INVALID_OPAQUE_CAPTURE_ADDRESS",
-        VK_ERROR_SURFACE_LOST_KHR => s = "VK_ERROR_SURFACE_LOST_KHR",
-        VK_ERROR_NATIVE_WINDOW_IN_USE_KHR => s = "VK_ERROR_NATIVE_WINDOW_IN_USE_KHR",
-        VK_SUBOPTIMAL_KHR => s = "VK_SUBOPTIMAL_KHR",
-        VK_ERROR_OUT_OF_DATE_KHR => s = "VK_ERROR_OUT_OF_DATE_KHR",
-        VK_ERROR_INCOMPATIBLE_DISPLAY_KHR => s = "VK_ERROR_INCOMPATIBLE_DISPLAY_KHR",
-        VK_ERROR_VALIDATION_FAILED_EXT => s = "VK_ERROR_VALIDATION_FAILED_EXT",
-        VK_ERROR_INVALID_SHADER_NV => s = "VK_ERROR_INVALID_SHADER_NV",
-        VK_ERROR_INVALID_DRM_FORMAT_MODIFIER_PLANE_LAYOUT_EXT => {
-          s = "VK_ERROR_INVALID_DRM_FORMAT_MODIFIER_PLANE_LAYOUT_EXT"
-        }
-        VK_ERROR_NOT_PERMITTED_EXT => s = "VK_ERROR_NOT_PERMITTED_EXT",
-        VK_ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT => {
-          s = "VK_ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT"
-        }
-        VK_THREAD_IDLE_KHR => s = "VK_THREAD_IDLE_KHR",
-        VK_THREAD_DONE_KHR => s = "VK_THREAD_DONE_KHR",
-        VK_OPERATION_DEFERRED_KHR => s = "VK_OPERATION_DEFERRED_KHR",
-        VK_OPERATION_NOT_DEFERRED_KHR => s = "VK_OPERATION_NOT_DEFERRED_KHR",
-        VK_PIPELINE_COMPILE_REQUIRED_EXT => s = "VK_PIPELINE_COMPILE_REQUIRED_EXT",
-        _ => s = "This should never happen!?!?",
-      }
-      panic!("Vulkan error: {}", s);
-    }
+  ($call:expr) => {
+    $crate::error::vk_check($call, stringify!($call), file!(), line!())
   };
+  // for a result that was looked at first, call names the function it came from
+  ($result:expr, $call:expr) => {
+    $crate::error::vk_check($result, $call, file!(), line!())
+  };
+}
+
+pub fn vk_check(
+  result: VkResult,
+  call: &'static str,
+  file: &'static str,
+  line: u32,
+) -> Result<(), Error> {
+  if result == VK_SUCCESS {
+    Ok(())
+  } else {
+    Err(Error::VkError(
+      result,
+      vk_result_name(result),
+      call,
+      file,
+      line,
+    ))
+  }
+}
+
+// The name of a VkResult as the spec has it. Matched on the numbers, most of the codes
+// come from extensions whose constants aren't imported here, and a name that isn't in
+// scope would quietly turn into a pattern that matches everything.
+pub fn vk_result_name(result: VkResult) -> &'static str {
+  match result {
+    0 => "VK_SUCCESS",
+    1 => "VK_NOT_READY",
+    2 => "VK_TIMEOUT",
+    3 => "VK_EVENT_SET",
+    4 => "VK_EVENT_RESET",
+    5 => "VK_INCOMPLETE",
+    -1 => "VK_ERROR_OUT_OF_HOST_MEMORY",
+    -2 => "VK_ERROR_OUT_OF_DEVICE_MEMORY",
+    -3 => "VK_ERROR_INITIALIZATION_FAILED",
+    -4 => "VK_ERROR_DEVICE_LOST",
+    -5 => "VK_ERROR_MEMORY_MAP_FAILED",
+    -6 => "VK_ERROR_LAYER_NOT_PRESENT",
+    -7 => "VK_ERROR_EXTENSION_NOT_PRESENT",
+    -8 => "VK_ERROR_FEATURE_NOT_PRESENT",
+    -9 => "VK_ERROR_INCOMPATIBLE_DRIVER",
+    -10 => "VK_ERROR_TOO_MANY_OBJECTS",
+    -11 => "VK_ERROR_FORMAT_NOT_SUPPORTED",
+    -12 => "VK_ERROR_FRAGMENTED_POOL",
+    -13 => "VK_ERROR_UNKNOWN",
+    -1000069000 => "VK_ERROR_OUT_OF_POOL_MEMORY",
+    -1000072003 => "VK_ERROR_INVALID_EXTERNAL_HANDLE",
+    -1000161000 => "VK_ERROR_FRAGMENTATION",
+    -1000257000 => "VK_ERROR_INVALID_OPAQUE_CAPTURE_ADDRESS",
+    -1000000000 => "VK_ERROR_SURFACE_LOST_KHR",
+    -1000000001 => "VK_ERROR_NATIVE_WINDOW_IN_USE_KHR",
+    1000001003 => "VK_SUBOPTIMAL_KHR",
+    -1000001004 => "VK_ERROR_OUT_OF_DATE_KHR",
+    -1000003001 => "VK_ERROR_INCOMPATIBLE_DISPLAY_KHR",
+    -1000011001 => "VK_ERROR_VALIDATION_FAILED_EXT",
+    -1000012000 => "VK_ERROR_INVALID_SHADER_NV",
+    -1000158000 => "VK_ERROR_INVALID_DRM_FORMAT_MODIFIER_PLANE_LAYOUT_EXT",
+    -1000174001 => "VK_ERROR_NOT_PERMITTED_EXT",
+    -1000255000 => "VK_ERROR_FULL_SCREEN_EXCLUSIVE_MODE_LOST_EXT",
+    1000268000 => "VK_THREAD_IDLE_KHR",
+    1000268001 => "VK_THREAD_DONE_KHR",
+    1000268002 => "VK_OPERATION_DEFERRED_KHR",
+    1000268003 => "VK_OPERATION_NOT_DEFERRED_KHR",
+    1000297000 => "VK_PIPELINE_COMPILE_REQUIRED_EXT",
+    _ => "unknown VkResult",
+  }
 }
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+
+// Frame times collected over a run, in seconds. The count, average, min and max cover
+// every frame. The percentiles only look at the last SAMPLE_WINDOW frames, so a run
+// of hours doesn't keep every frame around, and sorting them stays cheap.
+
+// a few minutes at 60 fps
+const SAMPLE_WINDOW: usize = 10_000;
+
+pub struct FrameStats {
+  samples: VecDeque<f32>, // the last SAMPLE_WINDOW frame times
+  count: usize,
+  total: f64,
+  min: f32,
+  max: f32,
+}
+
+impl FrameStats {
+  pub fn new() -> FrameStats {
+    FrameStats {
+      samples: VecDeque::with_capacity(SAMPLE_WINDOW),
+      count: 0,
+      total: 0.0,
+      min: f32::INFINITY,
+      max: 0.0,
+    }
+  }
+
+  pub fn record(&mut self, frame_time: f32) {
+    if self.samples.len() == SAMPLE_WINDOW {
+      self.samples.pop_front();
+    }
+    self.samples.push_back(frame_time);
+    self.count += 1;
+    self.total += frame_time as f64;
+    self.min = f32::min(self.min, frame_time);
+    self.max = f32::max(self.max, frame_time);
+  }
+
+  pub fn count(&self) -> usize {
+    self.count
+  }
+
+  // the ones below are zero before the first frame
+  pub fn average(&self) -> f32 {
+    if self.count == 0 {
+      0.0
+    } else {
+      (self.total / self.count as f64) as f32
+    }
+  }
+
+  pub fn min(&self) -> f32 {
+    if self.count == 0 {
+      0.0
+    } else {
+      self.min
+    }
+  }
+
+  pub fn max(&self) -> f32 {
+    self.max
+  }
+
+  // the frame time that percent of the recent frames were at or under, e.g. 99.0 for
+  // the slowest frame left when the worst one in a hundred is ignored
+  pub fn percentile(&self, percent: f32) -> f32 {
+    if self.samples.is_empty() {
+      return 0.0;
+    }
+    let mut sorted: Vec<f32> = self.samples.iter().cloned().collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    // nearest rank
+    let rank = f32::ceil(percent / 100.0 * sorted.len() as f32) as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+  }
+
+  // one line for the log, in milliseconds
+  pub fn summary(&self) -> String {
+    let window = if self.count > self.samples.len() {
+      format!(" of the last {} frames", self.samples.len())
+    } else {
+      String::new()
+    };
+    format!(
+      "{} frames, avg {:.2} ms ({:.1} fps), min {:.2} ms, max {:.2} ms, p50 {:.2} ms, p95 {:.2} ms, p99 {:.2} ms{}",
+      self.count(),
+      self.average() * 1000.0,
+      if self.average() > 0.0 { 1.0 / self.average() } else { 0.0 },
+      self.min() * 1000.0,
+      self.max() * 1000.0,
+      self.percentile(50.0) * 1000.0,
+      self.percentile(95.0) * 1000.0,
+      self.percentile(99.0) * 1000.0,
+      window,
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn empty_stats_are_zero() {
+    let stats = FrameStats::new();
+    assert_eq!(stats.count(), 0);
+    assert_eq!(stats.average(), 0.0);
+    assert_eq!(stats.min(), 0.0);
+    assert_eq!(stats.max(), 0.0);
+    assert_eq!(stats.percentile(99.0), 0.0);
+  }
+
+  #[test]
+  fn percentiles_are_nearest_rank() {
+    let mut stats = FrameStats::new();
+    // 1 to 100 ms, out of order
+    for i in (1..=100).rev() {
+      stats.record(i as f32 / 1000.0);
+    }
+    assert_eq!(stats.count(), 100);
+    assert_eq!(stats.min(), 0.001);
+    assert_eq!(stats.max(), 0.1);
+    assert!((stats.average() - 0.0505).abs() < 1e-6);
+    assert_eq!(stats.percentile(50.0), 0.05);
+    assert_eq!(stats.percentile(99.0), 0.099);
+    assert_eq!(stats.percentile(100.0), 0.1);
+    assert_eq!(stats.percentile(0.0), 0.001);
+  }
+
+  #[test]
+  fn percentiles_only_keep_the_last_frames() {
+    let mut stats = FrameStats::new();
+    // a slow start that drops out of the window
+    for _ in 0..10 {
+      stats.record(1.0);
+    }
+    for _ in 0..SAMPLE_WINDOW {
+      stats.record(0.01);
+    }
+    assert_eq!(stats.count(), SAMPLE_WINDOW + 10);
+    assert_eq!(stats.samples.len(), SAMPLE_WINDOW);
+    assert_eq!(stats.max(), 1.0);
+    assert_eq!(stats.percentile(100.0), 0.01);
+    assert!(stats
+      .summary()
+      .ends_with(&format!("of the last {} frames", SAMPLE_WINDOW)));
+  }
+}
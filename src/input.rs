@@ -0,0 +1,322 @@
+use {
+  crate::error::Error,
+  sdl2::*,
+  std::{
+    collections::{HashMap, HashSet},
+    ffi::CString,
+  },
+};
+
+// Keyboard, mouse and gamepad state for the current frame, and the named actions it's
+// bound to. The bindings come from a config file like input.cfg, so what a key does
+// can change without touching the code asking for the action.
+//
+// Call begin_frame before polling the frame's events and pass every event to
+// handle_event. Pressed and released then hold for that one frame, held for as long as
+// the input stays down.
+
+// used when the config file doesn't exist, the same bindings input.cfg ships with
+const DEFAULT_CONFIG: &str = include_str!("../input.cfg");
+
+// a single key, mouse button or gamepad button
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Binding {
+  Key(i32),          // SDL_Keycode
+  MouseButton(u8),   // SDL_BUTTON_LEFT and so on
+  GamepadButton(u8), // SDL_GameControllerButton
+}
+
+pub struct Input {
+  held: HashSet<Binding>,
+  pressed: HashSet<Binding>,
+  released: HashSet<Binding>,
+  mouse_motion: (f32, f32), // pixels moved this frame
+  wheel: f32,               // wheel steps this frame, away from the user is positive
+
+  actions: HashMap<String, Vec<Binding>>,
+  gamepads: Vec<*mut SDL_GameController>,
+  // the buttons down on each gamepad by instance id, the bindings don't say which
+  gamepad_buttons: HashMap<i32, HashSet<u8>>,
+}
+
+impl Input {
+  // no bindings, every action reads as not pressed
+  pub fn new() -> Input {
+    Input {
+      held: HashSet::new(),
+      pressed: HashSet::new(),
+      released: HashSet::new(),
+      mouse_motion: (0.0, 0.0),
+      wheel: 0.0,
+
+      actions: HashMap::new(),
+      gamepads: Vec::new(),
+      gamepad_buttons: HashMap::new(),
+    }
+  }
+
+  // Reads the bindings from filename. A missing file gets the default bindings, a file
+  // with a mistake is an error naming the line.
+  pub fn load(&mut self, filename: &str) -> Result<(), Error> {
+    let config = match std::fs::read_to_string(filename) {
+      Ok(config) => config,
+      Err(e) if e.kind() == std::io::ErrorKind::NotFound => DEFAULT_CONFIG.to_string(),
+      Err(e) => return Err(Error::FromIO(e)),
+    };
+    self.actions = parse_config(filename, &config)?;
+    Ok(())
+  }
+
+  // replaces whatever the action was bound to
+  pub fn rebind(&mut self, action: &str, bindings: Vec<Binding>) {
+    self.actions.insert(action.to_string(), bindings);
+  }
+
+  // forgets the pressed and released inputs and the motion of the last frame
+  pub fn begin_frame(&mut self) {
+    self.pressed.clear();
+    self.released.clear();
+    self.mouse_motion = (0.0, 0.0);
+    self.wheel = 0.0;
+  }
+
+  pub fn handle_event(&mut self, e: &SDL_Event) {
+    unsafe {
+      match e.type_ {
+        // held keys repeat, that's not another press
+        SDL_KEYDOWN if e.key.repeat == 0 => self.press(Binding::Key(e.key.keysym.sym)),
+        SDL_KEYUP => self.release(Binding::Key(e.key.keysym.sym)),
+        SDL_MOUSEBUTTONDOWN => self.press(Binding::MouseButton(e.button.button)),
+        SDL_MOUSEBUTTONUP => self.release(Binding::MouseButton(e.button.button)),
+        SDL_MOUSEMOTION => {
+          self.mouse_motion.0 += e.motion.xrel as f32;
+          self.mouse_motion.1 += e.motion.yrel as f32;
+        }
+        SDL_MOUSEWHEEL => self.wheel += e.wheel.y as f32,
+        SDL_CONTROLLERBUTTONDOWN => {
+          let buttons = self.gamepad_buttons.entry(e.cbutton.which).or_default();
+          buttons.insert(e.cbutton.button);
+          self.press(Binding::GamepadButton(e.cbutton.button));
+        }
+        SDL_CONTROLLERBUTTONUP => {
+          if let Some(buttons) = self.gamepad_buttons.get_mut(&e.cbutton.which) {
+            buttons.remove(&e.cbutton.button);
+          }
+          self.release_gamepad_button(e.cbutton.button);
+        }
+        // which is the joystick index here, the gamepads that are plugged in at start
+        // get this event too
+        SDL_CONTROLLERDEVICEADDED => {
+          let gamepad = SDL_GameControllerOpen(e.cdevice.which);
+          if !gamepad.is_null() {
+            self.gamepads.push(gamepad);
+          }
+        }
+        // and the instance id here. A pad pulled out with buttons down sends no button
+        // up events, they are let go here
+        SDL_CONTROLLERDEVICEREMOVED => {
+          let gamepad = SDL_GameControllerFromInstanceID(e.cdevice.which);
+          if let Some(i) = self.gamepads.iter().position(|g| *g == gamepad) {
+            SDL_GameControllerClose(self.gamepads.swap_remove(i));
+          }
+          if let Some(buttons) = self.gamepad_buttons.remove(&e.cdevice.which) {
+            for button in buttons {
+              self.release_gamepad_button(button);
+            }
+          }
+        }
+        // what goes up while another window has the focus is never seen here, nothing
+        // stays held once the focus is gone
+        SDL_WINDOWEVENT if e.window.event as u32 == SDL_WINDOWEVENT_FOCUS_LOST => {
+          self.held.clear();
+          self.gamepad_buttons.clear();
+        }
+        _ => {}
+      }
+    }
+  }
+
+  // went down this frame
+  pub fn is_pressed(&self, action: &str) -> bool {
+    self.any_bound(action, &self.pressed)
+  }
+
+  // is down, whether it went down this frame or earlier
+  pub fn is_held(&self, action: &str) -> bool {
+    self.any_bound(action, &self.held)
+  }
+
+  // came back up this frame
+  pub fn is_released(&self, action: &str) -> bool {
+    self.any_bound(action, &self.released)
+  }
+
+  // 1, -1 or 0 for a pair of actions pulling in opposite directions
+  pub fn axis(&self, positive: &str, negative: &str) -> f32 {
+    (self.is_held(positive) as i32 - self.is_held(negative) as i32) as f32
+  }
+
+  pub fn mouse_motion(&self) -> (f32, f32) {
+    self.mouse_motion
+  }
+
+  pub fn wheel(&self) -> f32 {
+    self.wheel
+  }
+
+  fn press(&mut self, binding: Binding) {
+    self.held.insert(binding);
+    self.pressed.insert(binding);
+  }
+
+  fn release(&mut self, binding: Binding) {
+    self.held.remove(&binding);
+    self.released.insert(binding);
+  }
+
+  // the binding stays held while another pad holds the same button
+  fn release_gamepad_button(&mut self, button: u8) {
+    if !self
+      .gamepad_buttons
+      .values()
+      .any(|buttons| buttons.contains(&button))
+    {
+      self.release(Binding::GamepadButton(button));
+    }
+  }
+
+  fn any_bound(&self, action: &str, state: &HashSet<Binding>) -> bool {
+    match self.actions.get(action) {
+      Some(bindings) => bindings.iter().any(|binding| state.contains(binding)),
+      None => false,
+    }
+  }
+}
+
+// has to go before SDL_Quit, the engine drops its window last
+impl Drop for Input {
+  fn drop(&mut self) {
+    for gamepad in self.gamepads.drain(..) {
+      unsafe { SDL_GameControllerClose(gamepad) };
+    }
+  }
+}
+
+// lines of `action = binding, binding`, # starts a comment
+fn parse_config(filename: &str, config: &str) -> Result<HashMap<String, Vec<Binding>>, Error> {
+  let mut actions = HashMap::new();
+  for (i, line) in config.lines().enumerate() {
+    let error = |message: String| Error::InputConfig(filename.to_string(), i + 1, message);
+
+    let line = line.split('#').next().unwrap_or("").trim();
+    if line.is_empty() {
+      continue;
+    }
+    let (action, bindings) = match line.find('=') {
+      Some(equals) => (line[..equals].trim(), line[equals + 1..].trim()),
+      None => {
+        return Err(error(format!(
+          "expected `action = bindings`, got `{}`",
+          line
+        )))
+      }
+    };
+    if action.is_empty() {
+      return Err(error("the action has no name".to_string()));
+    }
+
+    let bindings = bindings
+      .split(',')
+      .map(|binding| parse_binding(binding.trim()).map_err(|message| error(message)))
+      .collect::<Result<Vec<Binding>, Error>>()?;
+    actions.insert(action.to_string(), bindings);
+  }
+  Ok(actions)
+}
+
+// key:<SDL key name>, mouse:<button> or gamepad:<SDL button name>
+fn parse_binding(binding: &str) -> Result<Binding, String> {
+  let (device, name) = match binding.find(':') {
+    Some(colon) => (binding[..colon].trim(), binding[colon + 1..].trim()),
+    None => return Err(format!("`{}` should be key:, mouse: or gamepad:", binding)),
+  };
+  // SDL looks the names up, they can't have a nul in them
+  let c_name = CString::new(name).map_err(|_| format!("bad name in `{}`", binding))?;
+  match device {
+    "key" => match unsafe { SDL_GetKeyFromName(c_name.as_ptr()) } {
+      0 => Err(format!("unknown key `{}`", name)), // SDLK_UNKNOWN
+      key => Ok(Binding::Key(key)),
+    },
+    "mouse" => match name {
+      "left" => Ok(Binding::MouseButton(1)),
+      "middle" => Ok(Binding::MouseButton(2)),
+      "right" => Ok(Binding::MouseButton(3)),
+      "x1" => Ok(Binding::MouseButton(4)),
+      "x2" => Ok(Binding::MouseButton(5)),
+      _ => Err(format!("unknown mouse button `{}`", name)),
+    },
+    "gamepad" => match unsafe { SDL_GameControllerGetButtonFromString(c_name.as_ptr()) } as i32 {
+      -1 => Err(format!("unknown gamepad button `{}`", name)), // SDL_CONTROLLER_BUTTON_INVALID
+      button => Ok(Binding::GamepadButton(button as u8)),
+    },
+    _ => Err(format!("unknown device `{}` in `{}`", device, binding)),
+  }
+}
+
+// only mouse bindings, keys and gamepad buttons are looked up by SDL
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mouse_buttons_by_name() {
+    let names = ["left", "middle", "right", "x1", "x2"];
+    for (i, name) in names.iter().enumerate() {
+      let binding = parse_binding(&format!("mouse:{}", name)).unwrap();
+      assert!(binding == Binding::MouseButton(i as u8 + 1));
+    }
+    // spaces around the colon are fine
+    assert!(parse_binding("mouse : left").unwrap() == Binding::MouseButton(1));
+  }
+
+  #[test]
+  fn bad_bindings_are_errors() {
+    assert_eq!(
+      parse_binding("mouse:wheel").err().unwrap(),
+      "unknown mouse button `wheel`"
+    );
+    assert_eq!(
+      parse_binding("joystick:left").err().unwrap(),
+      "unknown device `joystick` in `joystick:left`"
+    );
+    assert_eq!(
+      parse_binding("left").err().unwrap(),
+      "`left` should be key:, mouse: or gamepad:"
+    );
+  }
+
+  #[test]
+  fn config_is_parsed_into_actions() {
+    let config =
+      "# a comment\n\nshoot = mouse:left, mouse:x1 # trailing comment\naim=mouse:right\n";
+    let actions = parse_config("test.cfg", config).unwrap();
+    assert_eq!(actions.len(), 2);
+    assert!(actions["shoot"] == vec![Binding::MouseButton(1), Binding::MouseButton(4)]);
+    assert!(actions["aim"] == vec![Binding::MouseButton(3)]);
+  }
+
+  #[test]
+  fn config_errors_name_the_line() {
+    let line_of = |config: &str| match parse_config("test.cfg", config) {
+      Err(Error::InputConfig(file, line, _)) => {
+        assert_eq!(file, "test.cfg");
+        line
+      }
+      _ => panic!("expected InputConfig"),
+    };
+    assert_eq!(line_of("aim = mouse:right\nshoot mouse:left"), 2);
+    assert_eq!(line_of("aim = mouse:right\n\n = mouse:left"), 3);
+    assert_eq!(line_of("shoot = mouse:left, mouse:thumb"), 1);
+    assert_eq!(line_of("shoot = mouse:left,"), 1);
+  }
+}
@@ -1,20 +1,200 @@
+mod camera;
 mod error;
+mod frame_stats;
+mod input;
 mod mesh;
+mod vertex_layout;
+mod vk_capture;
+#[cfg(feature = "compute")]
+mod vk_compute;
+mod vk_descriptors;
 mod vk_engine;
+mod vk_headless;
 mod vk_initializers;
 mod vk_pipeline;
+mod vk_pipeline_cache;
+mod vk_reflect;
+mod vk_shaders;
+mod vk_textures;
 mod vk_types;
 
-use {error::Error, vk_engine::VulkanEngine};
+use {
+  error::Error,
+  vk_capture::FrameCapture,
+  vk_engine::{App, VulkanEngine},
+};
+
+// size of the offscreen image and number of frames drawn with --headless
+const HEADLESS_WIDTH: u32 = 800;
+const HEADLESS_HEIGHT: u32 = 600;
+const HEADLESS_FRAMES: u32 = 10;
+
+// the golden test draws the first frame only, the animations haven't moved yet
+const GOLDEN_REFERENCE: &str = "tests/golden/monkey.png";
+const GOLDEN_FRAMES: u32 = 1;
+// how far a channel can be off before the pixel counts as different
+const GOLDEN_TOLERANCE: u8 = 4;
+
+// the engine's own demo, it prints what the engine reports
+struct Demo;
+
+impl App for Demo {
+  #[cfg(feature = "hot_reload")]
+  fn shaders_reloaded(&mut self, result: Result<&[String], &Error>) {
+    match result {
+      Ok(shaders) => println!("reloaded {}", shaders.join(", ")),
+      Err(e) => eprintln!("{}", e),
+    }
+  }
+}
 
 fn main() -> Result<(), Error> {
-  let mut engine = VulkanEngine::new();
+  let args: Vec<String> = std::env::args().collect();
+  let has_arg = |name: &str| args.iter().any(|arg| arg == name);
+  // the value that follows an argument, e.g. the file name of --capture <file>
+  let arg_value = |name: &str| {
+    args
+      .iter()
+      .position(|arg| arg == name)
+      .and_then(|i| args.get(i + 1))
+  };
+
+  // --golden compares a headless frame with the reference, --bless replaces the reference.
+  // With --device-lost the device is lost once before the frame is drawn
+  if has_arg("--golden") || has_arg("--bless") {
+    return golden_test(has_arg("--bless"), has_arg("--device-lost"));
+  }
+
+  // --headless renders offscreen without a window, e.g. on CI with lavapipe
+  let headless = has_arg("--headless");
+
+  let mut engine = if headless {
+    VulkanEngine::new_headless(HEADLESS_WIDTH, HEADLESS_HEIGHT)
+  } else {
+    VulkanEngine::new()
+  };
 
   engine.init()?;
+  // --fps shows the frame rate in the window title from the start, F3 toggles it
+  engine.show_fps(has_arg("--fps"));
 
-  engine.run();
+  if headless {
+    engine.run_headless(HEADLESS_FRAMES)?;
+  } else {
+    engine.run(&mut Demo)?;
+    println!("{}", engine.frame_stats().summary());
+  }
+  // --capture <file> saves the last frame as PNG, or PPM if the name ends in .ppm.
+  // With a window that is one more frame drawn after the window was closed
+  if let Some(filename) = arg_value("--capture") {
+    engine.capture_frame()?.save(filename)?;
+  }
+  // without it the next start only takes longer, not worth failing over
+  if let Err(e) = engine.save_pipeline_cache() {
+    eprintln!("couldn't save the pipeline cache: {}", e);
+  }
+  // the engine carries on after losing the device, but it's worth knowing about
+  if engine.device_losses() > 0 {
+    eprintln!(
+      "the device was lost {} times and created again",
+      engine.device_losses()
+    );
+  }
 
-  engine.cleanup();
+  // everything is destroyed when the engine is dropped
+  Ok(())
+}
 
+// Renders a fixed frame of the monkey scene and checks it against GOLDEN_REFERENCE.
+// On a mismatch the frame is written next to the reference so it can be looked at.
+fn golden_test(bless: bool, lose_device: bool) -> Result<(), Error> {
+  let mut engine = VulkanEngine::new_headless(HEADLESS_WIDTH, HEADLESS_HEIGHT);
+  engine.init()?;
+  if lose_device {
+    // the frame is dropped and everything is made again on a new device, the frame
+    // drawn after that has to look the same as without it
+    engine.simulate_device_lost();
+    engine.run_headless(1)?;
+  }
+  engine.run_headless(GOLDEN_FRAMES)?;
+  let frame = engine.capture_frame()?;
+  drop(engine);
+
+  if bless {
+    println!("writing new reference {}", GOLDEN_REFERENCE);
+    return frame.save_png(GOLDEN_REFERENCE);
+  }
+
+  let actual = GOLDEN_REFERENCE.replace(".png", ".actual.png");
+  if !std::path::Path::new(GOLDEN_REFERENCE).exists() {
+    frame.save_png(&actual)?;
+    return Err(Error::String(format!(
+      "there is no reference {}, the frame was written to {}. If it looks right bless it with `cargo run -- --bless`",
+      GOLDEN_REFERENCE, actual
+    )));
+  }
+  let reference = FrameCapture::load_png(GOLDEN_REFERENCE)?;
+  let diff = frame.compare(&reference, GOLDEN_TOLERANCE)?;
+  if diff.mismatched_pixels > 0 {
+    frame.save_png(&actual)?;
+    return Err(Error::String(format!(
+      "{} pixels differ from {} by more than {} (max difference {}), the frame was written to {}",
+      diff.mismatched_pixels, GOLDEN_REFERENCE, GOLDEN_TOLERANCE, diff.max_difference, actual
+    )));
+  }
+
+  println!("frame matches {}", GOLDEN_REFERENCE);
   Ok(())
 }
+
+// These need a Vulkan driver, lavapipe will do. The golden ones also need the reference,
+// see the README, so they are ignored unless asked for with `cargo test -- --ignored`.
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // a compute only family or the graphics one, either way there is a queue
+  #[cfg(feature = "compute")]
+  #[test]
+  fn compute_queue_is_created() {
+    let mut engine = VulkanEngine::new_headless(HEADLESS_WIDTH, HEADLESS_HEIGHT);
+    engine.init().unwrap();
+    let (queue, family) = engine.compute_queue();
+    assert!(!queue.is_null());
+    assert_ne!(family, u32::MAX);
+  }
+
+  #[test]
+  #[ignore]
+  fn golden_frame() {
+    if let Err(e) = golden_test(false, false) {
+      panic!("{}", e);
+    }
+  }
+
+  // no reference needed, the frame after the loss only has to be drawn
+  #[test]
+  fn frame_is_drawn_after_device_lost() {
+    let mut engine = VulkanEngine::new_headless(HEADLESS_WIDTH, HEADLESS_HEIGHT);
+    engine.init().unwrap();
+    engine.simulate_device_lost();
+    engine.run_headless(1).unwrap();
+    assert_eq!(engine.device_losses(), 1);
+
+    engine.run_headless(1).unwrap();
+    assert_eq!(engine.device_losses(), 1);
+    let frame = engine.capture_frame().unwrap();
+    assert_eq!(
+      (frame.width, frame.height),
+      (HEADLESS_WIDTH, HEADLESS_HEIGHT)
+    );
+  }
+
+  #[test]
+  #[ignore]
+  fn golden_frame_after_device_lost() {
+    if let Err(e) = golden_test(false, true) {
+      panic!("{}", e);
+    }
+  }
+}
@@ -1,97 +1,54 @@
 use {
-  crate::{error::Error, vk_types::AllocatedBuffer},
-  lina::vec3::Vec3,
-  std::mem::size_of,
-  vkcapi::core::v1_0::*,
+  crate::{error::Error, vertex_layout, vk_textures::TextureData, vk_types::Buffer},
+  lina::{mat4::Mat4, vec2::Vec2, vec3::Vec3, vec4::Vec4},
+  std::collections::HashSet,
 };
 
-#[derive(Clone)]
-pub struct VertexInputDescription {
-  pub bindings: Vec<VkVertexInputBindingDescription>,
-  pub attributes: Vec<VkVertexInputAttributeDescription>,
-  pub flags: VkPipelineVertexInputStateCreateFlags,
-}
-
-impl VertexInputDescription {
-  pub fn new() -> VertexInputDescription {
-    VertexInputDescription {
-      bindings: Vec::new(),
-      attributes: Vec::new(),
-      flags: 0,
-    }
-  }
-}
-
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct Vertex {
   pub position: Vec3,
   pub normal: Vec3,
   pub color: Vec3,
+  pub uv: Vec2,
+  pub tangent: Vec4, // w is the handedness of the bitangent, zero when there is no tangent
 }
 
 impl Vertex {
-  pub fn new3v3(position: Vec3, normal: Vec3, color: Vec3) -> Vertex {
+  pub fn new(position: Vec3, normal: Vec3, color: Vec3, uv: Vec2, tangent: Vec4) -> Vertex {
     Vertex {
       position,
       normal,
       color,
+      uv,
+      tangent,
     }
   }
-  pub fn get_vertex_description() -> VertexInputDescription {
-    let mut description = VertexInputDescription::new();
-
-    // we will have just 1 vertex buffer bindin, with a per-vertex rate
-    let main_binding = VkVertexInputBindingDescription {
-      binding: 0,
-      stride: size_of::<Vertex>() as u32,
-      inputRate: VK_VERTEX_INPUT_RATE_VERTEX,
-    };
-
-    description.bindings.push(main_binding);
-
-    // position will be stored at Location 0
-    let position_attribute = VkVertexInputAttributeDescription {
-      location: 0,
-      binding: 0,
-      format: VK_FORMAT_R32G32B32_SFLOAT,
-      offset: 0,
-    };
-
-    // normal will be stored at Location 1
-    let normal_attribute = VkVertexInputAttributeDescription {
-      location: 1,
-      binding: 0,
-      format: VK_FORMAT_R32G32B32_SFLOAT,
-      offset: 12,
-    };
-
-    // color will be stored at Location 2
-    let color_attribute = VkVertexInputAttributeDescription {
-      location: 2,
-      binding: 0,
-      format: VK_FORMAT_R32G32B32_SFLOAT,
-      offset: 24,
-    };
-    description.attributes.push(position_attribute);
-    description.attributes.push(normal_attribute);
-    description.attributes.push(color_attribute);
-
-    description
-  }
 }
 
-#[derive(Clone)]
+// position at location 0, normal 1, color 2, uv 3 and tangent 4
+vertex_layout!(Vertex {
+  position,
+  normal,
+  color,
+  uv,
+  tangent
+});
+
 pub struct Mesh {
   pub vertices: Vec<Vertex>,
-  pub vertex_buffer: AllocatedBuffer,
+  pub indices: Vec<u32>,
+  pub vertex_buffer: Buffer,
+  pub index_buffer: Buffer,
 }
 
 impl Mesh {
   pub fn new() -> Mesh {
     Mesh {
       vertices: Vec::new(),
-      vertex_buffer: AllocatedBuffer::null(),
+      indices: Vec::new(),
+      vertex_buffer: Buffer::null(),
+      index_buffer: Buffer::null(),
     }
   }
 
@@ -100,37 +57,293 @@ impl Mesh {
   pub fn load_gltf(filename: &str) -> Result<Mesh, Error> {
     let (document, buffers, _images) = gltf::import(filename).map_err(|e| Error::FromGltf(e))?;
 
-    let mut result = Mesh::new();
-    // we are depending on the fact that we created the gltf file and know
-    // that it contains 1 mesh and nothing else
+    // we only take the first mesh, use load_gltf_scene for anything else
+    let mesh = match document.meshes().next() {
+      Some(mesh) => mesh,
+      None => return Err(Error::GltfNoMesh(filename.to_string())),
+    };
+    Mesh::from_gltf_mesh(&mesh, &buffers)
+  }
+
+  // loads every mesh of the default scene (or the first scene if there is no default)
+  // and walks the node tree to find out where each of them is placed in the world.
+  pub fn load_gltf_scene(filename: &str) -> Result<GltfScene, Error> {
+    let (document, buffers, images) = gltf::import(filename).map_err(|e| Error::FromGltf(e))?;
+
+    let scene = match document.default_scene() {
+      Some(scene) => scene,
+      None => match document.scenes().next() {
+        Some(scene) => scene,
+        None => return Err(Error::GltfNoScene(filename.to_string())),
+      },
+    };
+
+    // the same gltf mesh can be used by many nodes, so every mesh is loaded once
+    // and the nodes refer to it by index.
+    let mut result = GltfScene::new();
+    for mesh in document.meshes() {
+      result.meshes.push(Mesh::from_gltf_mesh(&mesh, &buffers)?);
+      // the primitives are drawn together, so the first one picks the texture
+      let base_color = mesh.primitives().next().and_then(|primitive| {
+        primitive
+          .material()
+          .pbr_metallic_roughness()
+          .base_color_texture()
+      });
+      result
+        .mesh_images
+        .push(base_color.map(|info| info.texture().source().index()));
+    }
+
+    // base color and emissive textures are colors, every other texture holds data
+    let mut color_images = HashSet::new();
+    for material in document.materials() {
+      let base_color = material.pbr_metallic_roughness().base_color_texture();
+      for info in base_color.iter().chain(material.emissive_texture().iter()) {
+        color_images.insert(info.texture().source().index());
+      }
+    }
+
+    // the images come back in the same order as document.images()
+    for (image, data) in document.images().zip(images.iter()) {
+      let image_label = match image.name() {
+        Some(name) => format!("{} ({})", image.index(), name),
+        None => format!("{}", image.index()),
+      };
+      result.images.push(TextureData::from_gltf(
+        data,
+        &image_label,
+        color_images.contains(&image.index()),
+      )?);
+    }
+
+    let identity = mat4_from_gltf([
+      [1.0, 0.0, 0.0, 0.0],
+      [0.0, 1.0, 0.0, 0.0],
+      [0.0, 0.0, 1.0, 0.0],
+      [0.0, 0.0, 0.0, 1.0],
+    ]);
+    for node in scene.nodes() {
+      walk_gltf_node(&node, identity, &mut result.nodes);
+    }
+    Ok(result)
+  }
+
+  fn from_gltf_mesh(mesh: &gltf::Mesh, buffers: &[gltf::buffer::Data]) -> Result<Mesh, Error> {
+    // used in error messages so the user can find the broken mesh in their tool of choice
+    let mesh_label = match mesh.name() {
+      Some(name) => format!("{} ({})", mesh.index(), name),
+      None => format!("{}", mesh.index()),
+    };
 
-    // get the first mesh or panic if there is no mesh in file
-    let mesh = document.meshes().next().unwrap();
+    let mut result = Mesh::new();
     for primitive in mesh.primitives() {
-      // we are taking an idexed buffer and turning it into a non indexed buffer because
-      // we haven't done indexed drawing in the tutorial yet.
+      let primitive_index = primitive.index();
+
+      // our pipelines only draw triangle lists
+      match primitive.mode() {
+        gltf::mesh::Mode::Triangles => {}
+        _ => return Err(Error::GltfUnsupportedMode(mesh_label, primitive_index)),
+      }
+
+      // all primitives share one vertex buffer, so the indices of each primitive
+      // have to be offset by the number of vertices already in the mesh.
+      let base_vertex = result.vertices.len() as u32;
 
       let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
-      let positions: Vec<Vec3> = reader
-        .read_positions()
-        .unwrap()
-        .map(|v| Vec3::new(v[0], v[1], v[2]))
-        .collect();
-      let normals: Vec<Vec3> = reader
+
+      // position is the only attribute we can't make up
+      let positions: Vec<Vec3> = match reader.read_positions() {
+        Some(positions) => positions.map(|v| Vec3::new(v[0], v[1], v[2])).collect(),
+        None => {
+          return Err(Error::GltfMissingAttribute(
+            mesh_label,
+            primitive_index,
+            "POSITION",
+          ))
+        }
+      };
+      let vertex_count = positions.len();
+
+      // the optional attributes get a default when the file doesn't have them,
+      // white for the color so the mesh shows up, zero for uv and tangent.
+      let normals: Option<Vec<Vec3>> = reader
         .read_normals()
-        .unwrap()
-        .map(|n| Vec3::new(n[0], n[1], n[2]))
-        .collect();
-      let indices: Vec<u32> = reader.read_indices().unwrap().into_u32().collect();
-
-      for index in indices {
-        result.vertices.push(Vertex::new3v3(
-          positions[index as usize],
-          normals[index as usize],
-          normals[index as usize],
+        .map(|normals| normals.map(|n| Vec3::new(n[0], n[1], n[2])).collect());
+      let colors: Vec<Vec3> = match reader.read_colors(0) {
+        Some(colors) => colors
+          .into_rgb_f32()
+          .map(|c| Vec3::new(c[0], c[1], c[2]))
+          .collect(),
+        None => vec![Vec3::new(1.0, 1.0, 1.0); vertex_count],
+      };
+      let uvs: Vec<Vec2> = match reader.read_tex_coords(0) {
+        Some(uvs) => uvs.into_f32().map(|uv| Vec2::new(uv[0], uv[1])).collect(),
+        None => vec![Vec2::new(0.0, 0.0); vertex_count],
+      };
+      let tangents: Vec<Vec4> = match reader.read_tangents() {
+        Some(tangents) => tangents
+          .map(|t| Vec4::new(t[0], t[1], t[2], t[3]))
+          .collect(),
+        None => vec![Vec4::new(0.0, 0.0, 0.0, 0.0); vertex_count],
+      };
+
+      // a broken file can have attributes that don't line up with the positions
+      let counts = [
+        ("NORMAL", normals.as_ref().map_or(vertex_count, |n| n.len())),
+        ("COLOR_0", colors.len()),
+        ("TEXCOORD_0", uvs.len()),
+        ("TANGENT", tangents.len()),
+      ];
+      for (attribute, count) in counts.iter() {
+        if *count != vertex_count {
+          return Err(Error::GltfAttributeCount(
+            mesh_label,
+            primitive_index,
+            *attribute,
+          ));
+        }
+      }
+
+      // primitives without indices draw their vertices in order
+      let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertex_count as u32).collect(),
+      };
+      // a triangle list with a partial triangle at the end is broken, not short
+      if indices.len() % 3 != 0 {
+        return Err(Error::GltfIndexCount(
+          mesh_label,
+          primitive_index,
+          indices.len(),
+        ));
+      }
+      if let Some(index) = indices.iter().find(|i| **i as usize >= vertex_count) {
+        return Err(Error::GltfIndexOutOfRange(
+          mesh_label,
+          primitive_index,
+          *index,
         ));
       }
+
+      match normals {
+        Some(normals) => {
+          for i in 0..vertex_count {
+            result.vertices.push(Vertex::new(
+              positions[i],
+              normals[i],
+              colors[i],
+              uvs[i],
+              tangents[i],
+            ));
+          }
+          result
+            .indices
+            .extend(indices.iter().map(|index| index + base_vertex));
+        }
+        None => {
+          // without normals we generate flat ones. every triangle needs its own
+          // vertices for that, so the primitive ends up de-indexed.
+          for triangle in indices.chunks_exact(3) {
+            let normal = face_normal(
+              positions[triangle[0] as usize],
+              positions[triangle[1] as usize],
+              positions[triangle[2] as usize],
+            );
+            for index in triangle {
+              let i = *index as usize;
+              result.indices.push(result.vertices.len() as u32);
+              result.vertices.push(Vertex::new(
+                positions[i],
+                normal,
+                colors[i],
+                uvs[i],
+                tangents[i],
+              ));
+            }
+          }
+        }
+      }
     }
     Ok(result)
   }
 }
+
+// a node of a gltf scene that has a mesh attached. mesh indexes GltfScene::meshes
+// and transform is the world matrix, all parent transforms already applied.
+#[derive(Clone, Copy)]
+pub struct GltfSceneNode {
+  pub mesh: usize,
+  pub transform: Mat4,
+}
+
+pub struct GltfScene {
+  pub meshes: Vec<Mesh>,
+  pub nodes: Vec<GltfSceneNode>,
+  pub images: Vec<TextureData>, // every image of the file, converted to RGBA
+  pub mesh_images: Vec<Option<usize>>, // per mesh, the index of its base color image
+}
+
+impl GltfScene {
+  pub fn new() -> GltfScene {
+    GltfScene {
+      meshes: Vec::new(),
+      nodes: Vec::new(),
+      images: Vec::new(),
+      mesh_images: Vec::new(),
+    }
+  }
+}
+
+fn walk_gltf_node(node: &gltf::Node, parent: Mat4, nodes: &mut Vec<GltfSceneNode>) {
+  // gltf gives us the local transform as a matrix whether the node
+  // was authored with a matrix or with translation, rotation and scale
+  let world = parent * mat4_from_gltf(node.transform().matrix());
+
+  if let Some(mesh) = node.mesh() {
+    nodes.push(GltfSceneNode {
+      mesh: mesh.index(),
+      transform: world,
+    });
+  }
+
+  for child in node.children() {
+    walk_gltf_node(&child, world, nodes);
+  }
+}
+
+// normal of the triangle abc, counter clockwise is the front face in gltf
+fn face_normal(a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+  let (ux, uy, uz) = (b.x - a.x, b.y - a.y, b.z - a.z);
+  let (vx, vy, vz) = (c.x - a.x, c.y - a.y, c.z - a.z);
+  let (nx, ny, nz) = (uy * vz - uz * vy, uz * vx - ux * vz, ux * vy - uy * vx);
+  let length = f32::sqrt(nx * nx + ny * ny + nz * nz);
+  // degenerate triangles don't have a normal
+  if length > 0.0 {
+    Vec3::new(nx / length, ny / length, nz / length)
+  } else {
+    Vec3::new(0.0, 0.0, 0.0)
+  }
+}
+
+// gltf matrices are column major arrays of columns, same as lina
+fn mat4_from_gltf(m: [[f32; 4]; 4]) -> Mat4 {
+  Mat4 {
+    c0r0: m[0][0],
+    c0r1: m[0][1],
+    c0r2: m[0][2],
+    c0r3: m[0][3],
+    c1r0: m[1][0],
+    c1r1: m[1][1],
+    c1r2: m[1][2],
+    c1r3: m[1][3],
+    c2r0: m[2][0],
+    c2r1: m[2][1],
+    c2r2: m[2][2],
+    c2r3: m[2][3],
+    c3r0: m[3][0],
+    c3r1: m[3][1],
+    c3r2: m[3][2],
+    c3r3: m[3][3],
+  }
+}
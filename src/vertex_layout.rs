@@ -0,0 +1,199 @@
+use {
+  lina::{vec2::Vec2, vec3::Vec3, vec4::Vec4},
+  std::mem::size_of,
+  vkcapi::core::v1_0::*,
+};
+
+// Instead of writing offsets and formats by hand for every vertex type we let the
+// compiler work them out. A #[repr(C)] vertex struct gets its layout with
+//
+//   vertex_layout!(SkinnedVertex { position, normal, joints, weights });
+//
+// which gives every listed field the next shader location, starting at 0, with the
+// offset taken from the struct and the format taken from the field type.
+
+#[derive(Clone)]
+pub struct VertexInputDescription {
+  pub bindings: Vec<VkVertexInputBindingDescription>,
+  pub attributes: Vec<VkVertexInputAttributeDescription>,
+  pub flags: VkPipelineVertexInputStateCreateFlags,
+}
+
+impl VertexInputDescription {
+  pub fn new() -> VertexInputDescription {
+    VertexInputDescription {
+      bindings: Vec::new(),
+      attributes: Vec::new(),
+      flags: 0,
+    }
+  }
+}
+
+pub trait VertexLayout {
+  fn get_vertex_description() -> VertexInputDescription;
+}
+
+// the Vulkan format a type has when it is used as a vertex attribute
+pub trait VertexAttribute {
+  const FORMAT: VkFormat;
+}
+
+impl VertexAttribute for f32 {
+  const FORMAT: VkFormat = VK_FORMAT_R32_SFLOAT;
+}
+impl VertexAttribute for [f32; 2] {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32_SFLOAT;
+}
+impl VertexAttribute for [f32; 3] {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32B32_SFLOAT;
+}
+impl VertexAttribute for [f32; 4] {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32B32A32_SFLOAT;
+}
+impl VertexAttribute for Vec2 {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32_SFLOAT;
+}
+impl VertexAttribute for Vec3 {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32B32_SFLOAT;
+}
+impl VertexAttribute for Vec4 {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32B32A32_SFLOAT;
+}
+impl VertexAttribute for u32 {
+  const FORMAT: VkFormat = VK_FORMAT_R32_UINT;
+}
+impl VertexAttribute for [u32; 4] {
+  const FORMAT: VkFormat = VK_FORMAT_R32G32B32A32_UINT;
+}
+// joint indices for skinning
+impl VertexAttribute for [u16; 4] {
+  const FORMAT: VkFormat = VK_FORMAT_R16G16B16A16_UINT;
+}
+impl VertexAttribute for [u8; 4] {
+  const FORMAT: VkFormat = VK_FORMAT_R8G8B8A8_UINT;
+}
+
+// gets the format of a struct field from a closure that borrows it, so the macro
+// doesn't need to be told the field types.
+pub fn attribute_format<T, A: VertexAttribute>(_field: impl Fn(&T) -> &A) -> VkFormat {
+  A::FORMAT
+}
+
+// Builds a VertexInputDescription one binding and attribute at a time. Locations are
+// handed out in the order attributes are added.
+pub struct VertexInputBuilder {
+  description: VertexInputDescription,
+  next_location: u32,
+}
+
+impl VertexInputBuilder {
+  pub fn new() -> VertexInputBuilder {
+    VertexInputBuilder {
+      description: VertexInputDescription::new(),
+      next_location: 0,
+    }
+  }
+
+  // a buffer binding that advances one T per vertex
+  pub fn per_vertex<T>(&mut self, binding: u32) -> &mut Self {
+    self.binding(binding, size_of::<T>() as u32, VK_VERTEX_INPUT_RATE_VERTEX)
+  }
+
+  pub fn binding(&mut self, binding: u32, stride: u32, input_rate: VkVertexInputRate) -> &mut Self {
+    self
+      .description
+      .bindings
+      .push(VkVertexInputBindingDescription {
+        binding,
+        stride,
+        inputRate: input_rate,
+      });
+    self
+  }
+
+  pub fn attribute(&mut self, binding: u32, format: VkFormat, offset: u32) -> &mut Self {
+    self
+      .description
+      .attributes
+      .push(VkVertexInputAttributeDescription {
+        location: self.next_location,
+        binding,
+        format,
+        offset,
+      });
+    self.next_location += 1;
+    self
+  }
+
+  pub fn build(&self) -> VertexInputDescription {
+    self.description.clone()
+  }
+}
+
+// implements VertexLayout for a #[repr(C)] struct with a single per-vertex binding
+#[macro_export]
+macro_rules! vertex_layout {
+  ($vertex:ty { $($field:ident),+ $(,)? }) => {
+    impl $crate::vertex_layout::VertexLayout for $vertex {
+      fn get_vertex_description() -> $crate::vertex_layout::VertexInputDescription {
+        $crate::vertex_layout::VertexInputBuilder::new()
+          .per_vertex::<$vertex>(0)
+          $(
+            .attribute(
+              0,
+              $crate::vertex_layout::attribute_format(|v: &$vertex| &v.$field),
+              std::mem::offset_of!($vertex, $field) as u32,
+            )
+          )+
+          .build()
+      }
+    }
+  };
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[repr(C)]
+  struct TestVertex {
+    position: [f32; 3],
+    joints: [u16; 4],
+    weights: [f32; 4],
+  }
+
+  crate::vertex_layout!(TestVertex {
+    position,
+    joints,
+    weights
+  });
+
+  #[test]
+  fn layout_follows_the_struct() {
+    let description = TestVertex::get_vertex_description();
+    assert_eq!(description.bindings.len(), 1);
+    assert_eq!(description.bindings[0].binding, 0);
+    assert_eq!(
+      description.bindings[0].stride,
+      size_of::<TestVertex>() as u32
+    );
+    assert_eq!(
+      description.bindings[0].inputRate,
+      VK_VERTEX_INPUT_RATE_VERTEX
+    );
+
+    let attributes: Vec<(u32, VkFormat, u32)> = description
+      .attributes
+      .iter()
+      .map(|a| (a.location, a.format, a.offset))
+      .collect();
+    assert_eq!(
+      attributes,
+      vec![
+        (0, VK_FORMAT_R32G32B32_SFLOAT, 0),
+        (1, VK_FORMAT_R16G16B16A16_UINT, 12),
+        (2, VK_FORMAT_R32G32B32A32_SFLOAT, 20),
+      ]
+    );
+  }
+}
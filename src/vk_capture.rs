@@ -0,0 +1,262 @@
+use {
+  crate::{error::Error, vk_textures::image_barrier},
+  std::{fs::File, io::Write, path::Path},
+  vkcapi::core::v1_0::*,
+};
+
+// A frame read back from the GPU. The pixels are always RGBA, 4 bytes each, rows top
+// to bottom, whatever order the swapchain stored them in.
+pub struct FrameCapture {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<u8>,
+}
+
+// how two captures differ, see FrameCapture::compare
+pub struct CaptureDiff {
+  pub mismatched_pixels: usize, // pixels with a channel further off than the tolerance
+  pub max_difference: u8,       // the biggest channel difference of any pixel
+}
+
+impl FrameCapture {
+  // takes the pixels as they were copied out of an image with the given format
+  pub fn from_image_data(
+    width: u32,
+    height: u32,
+    mut pixels: Vec<u8>,
+    format: VkFormat,
+  ) -> Result<FrameCapture, Error> {
+    match format {
+      VK_FORMAT_R8G8B8A8_UNORM | VK_FORMAT_R8G8B8A8_SRGB => {}
+      // most swapchains are BGRA, swap red and blue
+      VK_FORMAT_B8G8R8A8_UNORM | VK_FORMAT_B8G8R8A8_SRGB => {
+        for pixel in pixels.chunks_exact_mut(4) {
+          pixel.swap(0, 2);
+        }
+      }
+      _ => {
+        return Err(Error::UnsupportedImageFormat(
+          "frame capture".to_string(),
+          format!("VkFormat {}", format),
+        ))
+      }
+    }
+    Ok(FrameCapture {
+      width,
+      height,
+      pixels,
+    })
+  }
+
+  // loads a PNG, e.g. a golden reference image
+  pub fn load_png(filename: &str) -> Result<FrameCapture, Error> {
+    let image = image::open(filename)
+      .map_err(|e| Error::FromImage(e))?
+      .to_rgba8();
+    Ok(FrameCapture {
+      width: image.width(),
+      height: image.height(),
+      pixels: image.into_raw(),
+    })
+  }
+
+  // writes a PPM if the file name ends in .ppm and a PNG otherwise
+  pub fn save(&self, filename: &str) -> Result<(), Error> {
+    match Path::new(filename).extension() {
+      Some(extension) if extension == "ppm" => self.save_ppm(filename),
+      _ => self.save_png(filename),
+    }
+  }
+
+  pub fn save_png(&self, filename: &str) -> Result<(), Error> {
+    image::save_buffer(
+      filename,
+      &self.pixels,
+      self.width,
+      self.height,
+      image::ColorType::Rgba8,
+    )
+    .map_err(|e| Error::FromImage(e))
+  }
+
+  // binary PPM, no dependencies needed to read it and alpha is dropped
+  pub fn save_ppm(&self, filename: &str) -> Result<(), Error> {
+    let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+    data.reserve((self.width * self.height * 3) as usize);
+    for pixel in self.pixels.chunks_exact(4) {
+      data.extend_from_slice(&pixel[..3]);
+    }
+
+    let mut file = File::create(filename).map_err(|e| Error::FromIO(e))?;
+    file.write_all(&data).map_err(|e| Error::FromIO(e))
+  }
+
+  // Compares every channel of every pixel. A pixel matches when none of its channels
+  // differ by more than tolerance, that leaves room for drivers rounding differently.
+  pub fn compare(&self, other: &FrameCapture, tolerance: u8) -> Result<CaptureDiff, Error> {
+    if self.width != other.width || self.height != other.height {
+      return Err(Error::String(format!(
+        "can't compare a {}x{} capture with a {}x{} one",
+        self.width, self.height, other.width, other.height
+      )));
+    }
+
+    let mut diff = CaptureDiff {
+      mismatched_pixels: 0,
+      max_difference: 0,
+    };
+    for (a, b) in self
+      .pixels
+      .chunks_exact(4)
+      .zip(other.pixels.chunks_exact(4))
+    {
+      let pixel_difference = a
+        .iter()
+        .zip(b.iter())
+        .map(|(a, b)| if a > b { a - b } else { b - a })
+        .max()
+        .unwrap_or(0);
+      if pixel_difference > tolerance {
+        diff.mismatched_pixels += 1;
+      }
+      diff.max_difference = u8::max(diff.max_difference, pixel_difference);
+    }
+    Ok(diff)
+  }
+}
+
+// Records the copy of a whole color image into a buffer, tightly packed. layout is the
+// one the render pass left the image in. The copy waits for the color attachment
+// writes, and an image that isn't in TRANSFER_SRC_OPTIMAL is put back in layout after.
+pub unsafe fn cmd_copy_image_to_buffer(
+  cmd: VkCommandBuffer,
+  image: VkImage,
+  layout: VkImageLayout,
+  buffer: VkBuffer,
+  width: u32,
+  height: u32,
+) {
+  image_barrier(
+    cmd,
+    image,
+    0,
+    1,
+    layout,
+    VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+    VK_ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+    VK_ACCESS_TRANSFER_READ_BIT,
+    VK_PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+    VK_PIPELINE_STAGE_TRANSFER_BIT,
+  );
+
+  let copy_region = VkBufferImageCopy {
+    bufferOffset: 0,
+    bufferRowLength: 0,
+    bufferImageHeight: 0,
+    imageSubresource: VkImageSubresourceLayers {
+      aspectMask: VK_IMAGE_ASPECT_COLOR_BIT,
+      mipLevel: 0,
+      baseArrayLayer: 0,
+      layerCount: 1,
+    },
+    imageOffset: VkOffset3D { x: 0, y: 0, z: 0 },
+    imageExtent: VkExtent3D {
+      width,
+      height,
+      depth: 1,
+    },
+  };
+  vkCmdCopyImageToBuffer(
+    cmd,
+    image,
+    VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+    buffer,
+    1,
+    &copy_region,
+  );
+
+  // e.g. a swapchain image that is presented next, the present waits on a semaphore
+  if layout != VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL {
+    image_barrier(
+      cmd,
+      image,
+      0,
+      1,
+      VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+      layout,
+      0,
+      0,
+      VK_PIPELINE_STAGE_TRANSFER_BIT,
+      VK_PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+    );
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn capture(pixels: Vec<u8>) -> FrameCapture {
+    FrameCapture {
+      width: pixels.len() as u32 / 4,
+      height: 1,
+      pixels,
+    }
+  }
+
+  #[test]
+  fn compare_counts_pixels_off_by_more_than_the_tolerance() {
+    let a = capture(vec![10, 10, 10, 255, 10, 10, 10, 255, 10, 10, 10, 255]);
+    // one channel off by 4, one by 5 and one pixel the same
+    let b = capture(vec![14, 10, 10, 255, 10, 10, 5, 255, 10, 10, 10, 255]);
+
+    let diff = a.compare(&b, 4).unwrap();
+    assert_eq!(diff.mismatched_pixels, 1);
+    assert_eq!(diff.max_difference, 5);
+
+    let diff = a.compare(&b, 5).unwrap();
+    assert_eq!(diff.mismatched_pixels, 0);
+    assert_eq!(diff.max_difference, 5);
+
+    let diff = a.compare(&b, 0).unwrap();
+    assert_eq!(diff.mismatched_pixels, 2);
+  }
+
+  #[test]
+  fn compare_rejects_a_different_size() {
+    let a = capture(vec![0; 8]);
+    let b = capture(vec![0; 4]);
+    assert!(a.compare(&b, 255).is_err());
+  }
+
+  #[test]
+  fn bgra_is_swizzled_to_rgba() {
+    let bgra = vec![1, 2, 3, 4, 5, 6, 7, 8];
+    for format in &[VK_FORMAT_B8G8R8A8_UNORM, VK_FORMAT_B8G8R8A8_SRGB] {
+      let capture = FrameCapture::from_image_data(2, 1, bgra.clone(), *format).unwrap();
+      assert_eq!(capture.pixels, vec![3, 2, 1, 4, 7, 6, 5, 8]);
+    }
+    let capture =
+      FrameCapture::from_image_data(2, 1, bgra.clone(), VK_FORMAT_R8G8B8A8_UNORM).unwrap();
+    assert_eq!(capture.pixels, bgra);
+    assert!(FrameCapture::from_image_data(2, 1, bgra, VK_FORMAT_R16G16B16A16_SFLOAT).is_err());
+  }
+
+  #[test]
+  fn ppm_has_a_p6_header_and_drops_alpha() {
+    let capture = FrameCapture {
+      width: 2,
+      height: 1,
+      pixels: vec![1, 2, 3, 4, 5, 6, 7, 8],
+    };
+    let filename = std::env::temp_dir().join(format!("vkguide_capture_{}.ppm", std::process::id()));
+    let filename = filename.to_str().unwrap();
+    capture.save_ppm(filename).unwrap();
+    let data = std::fs::read(filename).unwrap();
+    std::fs::remove_file(filename).unwrap();
+
+    let header = b"P6\n2 1\n255\n";
+    assert_eq!(&data[..header.len()], header);
+    assert_eq!(&data[header.len()..], &[1, 2, 3, 5, 6, 7]);
+  }
+}
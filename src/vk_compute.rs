@@ -0,0 +1,252 @@
+use {
+  crate::error::Error,
+  std::{ffi::c_void, ptr::null},
+  vkcapi::core::v1_0::*,
+};
+
+// Helpers for compute passes, built with the compute feature, e.g. simulating particles
+// or culling into an indirect draw buffer. Pipelines come from ComputePipelineBuilder,
+// their layouts from PipelineLayoutBuilder with the reflected compute shader.
+//
+// The compute work can run on its own queue family. The buffers are created with
+// exclusive sharing, so a buffer handed from one family to the other needs a release
+// barrier recorded on the old queue and a matching acquire barrier on the new one.
+// On a single queue the plain barriers below are enough.
+
+// The first family with compute but no graphics. Work submitted to it can run
+// alongside the rendering.
+pub fn compute_only_family(families: &[VkQueueFamilyProperties]) -> Option<u32> {
+  families
+    .iter()
+    .position(|family| {
+      family.queueFlags & VK_QUEUE_COMPUTE_BIT != 0
+        && family.queueFlags & VK_QUEUE_GRAPHICS_BIT == 0
+    })
+    .map(|index| index as u32)
+}
+
+// number of work groups to cover items with groups of group_size, rounded up
+pub fn group_count(items: u32, group_size: u32) -> Result<u32, Error> {
+  if group_size == 0 {
+    return Err(Error::Str("compute group size can't be 0"));
+  }
+  Ok(items.div_ceil(group_size))
+}
+
+// Binds the pipeline and its descriptor sets starting at set 0 and dispatches enough
+// groups to cover items. group_size is the local_size the shader declares. A size of 0
+// is an error, it's returned before anything is recorded.
+pub unsafe fn cmd_dispatch(
+  cmd: VkCommandBuffer,
+  pipeline: VkPipeline,
+  layout: VkPipelineLayout,
+  descriptor_sets: &[VkDescriptorSet],
+  items: [u32; 3],
+  group_size: [u32; 3],
+) -> Result<(), Error> {
+  let group_counts = [
+    group_count(items[0], group_size[0])?,
+    group_count(items[1], group_size[1])?,
+    group_count(items[2], group_size[2])?,
+  ];
+  vkCmdBindPipeline(cmd, VK_PIPELINE_BIND_POINT_COMPUTE, pipeline);
+  if !descriptor_sets.is_empty() {
+    vkCmdBindDescriptorSets(
+      cmd,
+      VK_PIPELINE_BIND_POINT_COMPUTE,
+      layout,
+      0,
+      descriptor_sets.len() as u32,
+      descriptor_sets.as_ptr(),
+      0,
+      null(),
+    );
+  }
+  vkCmdDispatch(cmd, group_counts[0], group_counts[1], group_counts[2]);
+  Ok(())
+}
+
+// push constants for the compute stage, call before cmd_dispatch
+pub unsafe fn cmd_push_compute_constants<T>(
+  cmd: VkCommandBuffer,
+  layout: VkPipelineLayout,
+  constants: &T,
+) {
+  vkCmdPushConstants(
+    cmd,
+    layout,
+    VK_SHADER_STAGE_COMPUTE_BIT,
+    0,
+    std::mem::size_of::<T>() as u32,
+    constants as *const T as *const c_void,
+  );
+}
+
+// A barrier on a whole buffer. The queue families are VK_QUEUE_FAMILY_IGNORED unless
+// the buffer moves between the graphics and the compute family.
+pub unsafe fn buffer_barrier(
+  cmd: VkCommandBuffer,
+  buffer: VkBuffer,
+  src_access_mask: VkAccessFlags,
+  dst_access_mask: VkAccessFlags,
+  src_stage_mask: VkPipelineStageFlags,
+  dst_stage_mask: VkPipelineStageFlags,
+  src_queue_family: u32,
+  dst_queue_family: u32,
+) {
+  let barrier = VkBufferMemoryBarrier {
+    sType: VK_STRUCTURE_TYPE_BUFFER_MEMORY_BARRIER,
+    pNext: null(),
+    srcAccessMask: src_access_mask,
+    dstAccessMask: dst_access_mask,
+    srcQueueFamilyIndex: src_queue_family,
+    dstQueueFamilyIndex: dst_queue_family,
+    buffer,
+    offset: 0,
+    size: VK_WHOLE_SIZE,
+  };
+  vkCmdPipelineBarrier(
+    cmd,
+    src_stage_mask,
+    dst_stage_mask,
+    0,
+    0,
+    null(),
+    1,
+    &barrier,
+    0,
+    null(),
+  );
+}
+
+// Compute wrote vertices, e.g. particle positions, that are drawn next on the same
+// queue. With a separate compute family use cmd_release_buffer/cmd_acquire_buffer.
+pub unsafe fn cmd_compute_to_vertex_barrier(cmd: VkCommandBuffer, buffer: VkBuffer) {
+  buffer_barrier(
+    cmd,
+    buffer,
+    VK_ACCESS_SHADER_WRITE_BIT,
+    VK_ACCESS_VERTEX_ATTRIBUTE_READ_BIT,
+    VK_PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+    VK_PIPELINE_STAGE_VERTEX_INPUT_BIT,
+    VK_QUEUE_FAMILY_IGNORED,
+    VK_QUEUE_FAMILY_IGNORED,
+  );
+}
+
+// compute wrote draw commands, e.g. the objects left after culling, for vkCmdDrawIndirect
+pub unsafe fn cmd_compute_to_indirect_barrier(cmd: VkCommandBuffer, buffer: VkBuffer) {
+  buffer_barrier(
+    cmd,
+    buffer,
+    VK_ACCESS_SHADER_WRITE_BIT,
+    VK_ACCESS_INDIRECT_COMMAND_READ_BIT,
+    VK_PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+    VK_PIPELINE_STAGE_DRAW_INDIRECT_BIT,
+    VK_QUEUE_FAMILY_IGNORED,
+    VK_QUEUE_FAMILY_IGNORED,
+  );
+}
+
+// the draws are done reading the buffer and compute writes it again, e.g. the next
+// step of a particle simulation
+pub unsafe fn cmd_graphics_to_compute_barrier(cmd: VkCommandBuffer, buffer: VkBuffer) {
+  buffer_barrier(
+    cmd,
+    buffer,
+    0,
+    VK_ACCESS_SHADER_READ_BIT | VK_ACCESS_SHADER_WRITE_BIT,
+    VK_PIPELINE_STAGE_VERTEX_INPUT_BIT | VK_PIPELINE_STAGE_DRAW_INDIRECT_BIT,
+    VK_PIPELINE_STAGE_COMPUTE_SHADER_BIT,
+    VK_QUEUE_FAMILY_IGNORED,
+    VK_QUEUE_FAMILY_IGNORED,
+  );
+}
+
+// Gives a buffer to another queue family, recorded on the queue that had it. The
+// stages of the other queue can't be named here, only the ones that last used it.
+pub unsafe fn cmd_release_buffer(
+  cmd: VkCommandBuffer,
+  buffer: VkBuffer,
+  src_access_mask: VkAccessFlags,
+  src_stage_mask: VkPipelineStageFlags,
+  src_queue_family: u32,
+  dst_queue_family: u32,
+) {
+  buffer_barrier(
+    cmd,
+    buffer,
+    src_access_mask,
+    0,
+    src_stage_mask,
+    VK_PIPELINE_STAGE_BOTTOM_OF_PIPE_BIT,
+    src_queue_family,
+    dst_queue_family,
+  );
+}
+
+// Takes a buffer released by cmd_release_buffer, recorded on the queue that gets it.
+// The submit has to wait on a semaphore signaled after the release.
+pub unsafe fn cmd_acquire_buffer(
+  cmd: VkCommandBuffer,
+  buffer: VkBuffer,
+  dst_access_mask: VkAccessFlags,
+  dst_stage_mask: VkPipelineStageFlags,
+  src_queue_family: u32,
+  dst_queue_family: u32,
+) {
+  buffer_barrier(
+    cmd,
+    buffer,
+    0,
+    dst_access_mask,
+    VK_PIPELINE_STAGE_TOP_OF_PIPE_BIT,
+    dst_stage_mask,
+    src_queue_family,
+    dst_queue_family,
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn group_count_rounds_up() {
+    assert_eq!(group_count(0, 64).unwrap(), 0);
+    assert_eq!(group_count(64, 64).unwrap(), 1);
+    assert_eq!(group_count(65, 64).unwrap(), 2);
+    // no overflow for the last items a u32 can count
+    assert_eq!(group_count(u32::MAX, 256).unwrap(), 16_777_216);
+  }
+
+  #[test]
+  fn group_size_of_0_is_an_error() {
+    assert!(group_count(10, 0).is_err());
+    // checked before the command buffer is touched
+    let result = unsafe { cmd_dispatch(null(), null(), null(), &[], [64, 1, 1], [64, 0, 1]) };
+    assert!(result.is_err());
+  }
+
+  fn family(queue_flags: VkQueueFlags) -> VkQueueFamilyProperties {
+    let mut family: VkQueueFamilyProperties = unsafe { std::mem::zeroed() };
+    family.queueFlags = queue_flags;
+    family.queueCount = 1;
+    family
+  }
+
+  #[test]
+  fn compute_only_family_skips_graphics_families() {
+    let graphics = VK_QUEUE_GRAPHICS_BIT | VK_QUEUE_COMPUTE_BIT | VK_QUEUE_TRANSFER_BIT;
+    let compute = VK_QUEUE_COMPUTE_BIT | VK_QUEUE_TRANSFER_BIT;
+    assert_eq!(
+      compute_only_family(&[
+        family(graphics),
+        family(VK_QUEUE_TRANSFER_BIT),
+        family(compute)
+      ]),
+      Some(2)
+    );
+    assert_eq!(compute_only_family(&[family(graphics)]), None);
+  }
+}
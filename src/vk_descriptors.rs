@@ -0,0 +1,333 @@
+use {
+  crate::{
+    error::Error,
+    vk_types::{DescriptorPool, DescriptorSetLayout, DeviceContext},
+    VK_CHECK,
+  },
+  std::{collections::HashMap, ptr::null, rc::Rc},
+  vkcapi::core::{v1_0::*, v1_1::*},
+};
+
+// Descriptor abstraction from the vkguide extra chapter. Layouts are cached by their
+// bindings so asking for the same layout twice gives back the same handle, sets come
+// out of pools that are added as they fill up, and the builder writes the buffers
+// and images into the sets.
+
+// how many descriptors of each type a pool gets, multiplied by the pool's set count
+const POOL_SIZES: [(VkDescriptorType, f32); 11] = [
+  (VK_DESCRIPTOR_TYPE_SAMPLER, 0.5),
+  (VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER, 4.0),
+  (VK_DESCRIPTOR_TYPE_SAMPLED_IMAGE, 4.0),
+  (VK_DESCRIPTOR_TYPE_STORAGE_IMAGE, 1.0),
+  (VK_DESCRIPTOR_TYPE_UNIFORM_TEXEL_BUFFER, 1.0),
+  (VK_DESCRIPTOR_TYPE_STORAGE_TEXEL_BUFFER, 1.0),
+  (VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER, 2.0),
+  (VK_DESCRIPTOR_TYPE_STORAGE_BUFFER, 2.0),
+  (VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER_DYNAMIC, 1.0),
+  (VK_DESCRIPTOR_TYPE_STORAGE_BUFFER_DYNAMIC, 1.0),
+  (VK_DESCRIPTOR_TYPE_INPUT_ATTACHMENT, 0.5),
+];
+
+// number of sets a single pool can hand out
+const SETS_PER_POOL: u32 = 1000;
+
+pub struct DescriptorAllocator {
+  context: Rc<DeviceContext>,
+  current_pool: VkDescriptorPool,
+  used_pools: Vec<DescriptorPool>, // pools that have handed out sets
+  free_pools: Vec<DescriptorPool>, // pools that were reset and can be used again
+}
+
+impl DescriptorAllocator {
+  pub fn new(context: &Rc<DeviceContext>) -> DescriptorAllocator {
+    DescriptorAllocator {
+      context: context.clone(),
+      current_pool: null(),
+      used_pools: Vec::new(),
+      free_pools: Vec::new(),
+    }
+  }
+
+  pub fn allocate(&mut self, layout: VkDescriptorSetLayout) -> Result<VkDescriptorSet, Error> {
+    // initialize the current pool handle if it's null
+    if self.current_pool.is_null() {
+      self.grab_pool()?;
+    }
+
+    let mut set = null();
+    let result = unsafe { self.allocate_from_current_pool(layout, &mut set) };
+    match result {
+      // the pool is full, we need a new one
+      VK_ERROR_FRAGMENTED_POOL | VK_ERROR_OUT_OF_POOL_MEMORY => {}
+      // it worked, or something else is wrong and a new pool won't help
+      _ => {
+        VK_CHECK!(result, "vkAllocateDescriptorSets")?;
+        return Ok(set);
+      }
+    }
+
+    // try again with a fresh pool, if this fails too we give up
+    self.grab_pool()?;
+    let result = unsafe { self.allocate_from_current_pool(layout, &mut set) };
+    VK_CHECK!(result, "vkAllocateDescriptorSets")?;
+    Ok(set)
+  }
+
+  // frees every set allocated so far, all the pools can be used again
+  pub fn reset_pools(&mut self) -> Result<(), Error> {
+    for pool in self.used_pools.drain(..) {
+      unsafe {
+        VK_CHECK!(vkResetDescriptorPool(self.context.device, pool.handle, 0))?;
+      }
+      self.free_pools.push(pool);
+    }
+    self.current_pool = null();
+    Ok(())
+  }
+
+  unsafe fn allocate_from_current_pool(
+    &self,
+    layout: VkDescriptorSetLayout,
+    set: &mut VkDescriptorSet,
+  ) -> VkResult {
+    let alloc_info = VkDescriptorSetAllocateInfo {
+      sType: VK_STRUCTURE_TYPE_DESCRIPTOR_SET_ALLOCATE_INFO,
+      pNext: null(),
+      descriptorPool: self.current_pool,
+      descriptorSetCount: 1,
+      pSetLayouts: &layout,
+    };
+    vkAllocateDescriptorSets(self.context.device, &alloc_info, set)
+  }
+
+  // makes a reset or a new pool the current one
+  fn grab_pool(&mut self) -> Result<(), Error> {
+    // reuse a pool that was reset if there is one
+    let pool = match self.free_pools.pop() {
+      Some(pool) => pool,
+      None => self.create_pool()?,
+    };
+    self.current_pool = pool.handle;
+    self.used_pools.push(pool);
+    Ok(())
+  }
+
+  fn create_pool(&self) -> Result<DescriptorPool, Error> {
+    let sizes: Vec<VkDescriptorPoolSize> = POOL_SIZES
+      .iter()
+      .map(|(descriptor_type, multiplier)| VkDescriptorPoolSize {
+        type_: *descriptor_type,
+        descriptorCount: (multiplier * SETS_PER_POOL as f32) as u32,
+      })
+      .collect();
+
+    let pool_info = VkDescriptorPoolCreateInfo {
+      sType: VK_STRUCTURE_TYPE_DESCRIPTOR_POOL_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      maxSets: SETS_PER_POOL,
+      poolSizeCount: sizes.len() as u32,
+      pPoolSizes: sizes.as_ptr(),
+    };
+
+    let mut pool = null();
+    unsafe {
+      VK_CHECK!(vkCreateDescriptorPool(
+        self.context.device,
+        &pool_info,
+        null(),
+        &mut pool
+      ))?;
+    }
+    Ok(DescriptorPool::new(&self.context, pool))
+  }
+}
+
+// the parts of the layout bindings that decide if two layouts are the same.
+// binding number, descriptor type, descriptor count and shader stages.
+pub type LayoutKey = Vec<(u32, VkDescriptorType, u32, VkShaderStageFlags)>;
+
+pub struct DescriptorLayoutCache {
+  context: Rc<DeviceContext>,
+  layout_cache: HashMap<LayoutKey, DescriptorSetLayout>,
+}
+
+impl DescriptorLayoutCache {
+  pub fn new(context: &Rc<DeviceContext>) -> DescriptorLayoutCache {
+    DescriptorLayoutCache {
+      context: context.clone(),
+      layout_cache: HashMap::new(),
+    }
+  }
+
+  pub fn create_descriptor_layout(
+    &mut self,
+    bindings: &[VkDescriptorSetLayoutBinding],
+  ) -> Result<VkDescriptorSetLayout, Error> {
+    // the bindings can come in any order, sorting them makes the key the same
+    let mut key: LayoutKey = bindings
+      .iter()
+      .map(|b| (b.binding, b.descriptorType, b.descriptorCount, b.stageFlags))
+      .collect();
+    key.sort();
+
+    if let Some(layout) = self.layout_cache.get(&key) {
+      return Ok(layout.handle);
+    }
+
+    let layout_info = VkDescriptorSetLayoutCreateInfo {
+      sType: VK_STRUCTURE_TYPE_DESCRIPTOR_SET_LAYOUT_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      bindingCount: bindings.len() as u32,
+      pBindings: bindings.as_ptr(),
+    };
+
+    let mut layout = null();
+    unsafe {
+      VK_CHECK!(vkCreateDescriptorSetLayout(
+        self.context.device,
+        &layout_info,
+        null(),
+        &mut layout
+      ))?;
+    }
+    self
+      .layout_cache
+      .insert(key, DescriptorSetLayout::new(&self.context, layout));
+    Ok(layout)
+  }
+
+  // the bindings a layout from this cache was made with, sorted by binding number
+  pub fn layout_bindings(&self, layout: VkDescriptorSetLayout) -> Option<&LayoutKey> {
+    self
+      .layout_cache
+      .iter()
+      .find(|(_, cached)| cached.handle == layout)
+      .map(|(key, _)| key)
+  }
+}
+
+// what gets written into a binding of the set
+enum DescriptorInfo {
+  Buffer(VkDescriptorBufferInfo),
+  Image(VkDescriptorImageInfo),
+}
+
+struct DescriptorWrite {
+  binding: u32,
+  descriptor_type: VkDescriptorType,
+  info: DescriptorInfo,
+}
+
+pub struct DescriptorBuilder {
+  bindings: Vec<VkDescriptorSetLayoutBinding>,
+  writes: Vec<DescriptorWrite>,
+}
+
+impl DescriptorBuilder {
+  pub fn new() -> DescriptorBuilder {
+    DescriptorBuilder {
+      bindings: Vec::new(),
+      writes: Vec::new(),
+    }
+  }
+
+  pub fn bind_buffer(
+    &mut self,
+    binding: u32,
+    buffer_info: VkDescriptorBufferInfo,
+    descriptor_type: VkDescriptorType,
+    stage_flags: VkShaderStageFlags,
+  ) -> &mut Self {
+    self.push_binding(binding, descriptor_type, stage_flags);
+    self.writes.push(DescriptorWrite {
+      binding,
+      descriptor_type,
+      info: DescriptorInfo::Buffer(buffer_info),
+    });
+    self
+  }
+
+  pub fn bind_image(
+    &mut self,
+    binding: u32,
+    image_info: VkDescriptorImageInfo,
+    descriptor_type: VkDescriptorType,
+    stage_flags: VkShaderStageFlags,
+  ) -> &mut Self {
+    self.push_binding(binding, descriptor_type, stage_flags);
+    self.writes.push(DescriptorWrite {
+      binding,
+      descriptor_type,
+      info: DescriptorInfo::Image(image_info),
+    });
+    self
+  }
+
+  // just the layout, for pipeline layouts that are created before any set exists
+  pub fn build_layout(
+    &self,
+    layout_cache: &mut DescriptorLayoutCache,
+  ) -> Result<VkDescriptorSetLayout, Error> {
+    layout_cache.create_descriptor_layout(&self.bindings)
+  }
+
+  // gets the layout from the cache, allocates a set with it and writes the bindings
+  pub fn build(
+    &self,
+    layout_cache: &mut DescriptorLayoutCache,
+    allocator: &mut DescriptorAllocator,
+  ) -> Result<(VkDescriptorSet, VkDescriptorSetLayout), Error> {
+    let layout = self.build_layout(layout_cache)?;
+    let set = allocator.allocate(layout)?;
+    self.write(allocator.device, set);
+    Ok((set, layout))
+  }
+
+  // writes the bindings into a set that already exists
+  pub fn write(&self, device: VkDevice, set: VkDescriptorSet) {
+    // the infos live in self.writes, so the pointers stay valid until the update
+    let writes: Vec<VkWriteDescriptorSet> = self
+      .writes
+      .iter()
+      .map(|write| VkWriteDescriptorSet {
+        sType: VK_STRUCTURE_TYPE_WRITE_DESCRIPTOR_SET,
+        pNext: null(),
+        dstSet: set,
+        dstBinding: write.binding,
+        dstArrayElement: 0,
+        descriptorCount: 1,
+        descriptorType: write.descriptor_type,
+        pImageInfo: match &write.info {
+          DescriptorInfo::Image(info) => info as *const VkDescriptorImageInfo,
+          DescriptorInfo::Buffer(_) => null(),
+        },
+        pBufferInfo: match &write.info {
+          DescriptorInfo::Buffer(info) => info as *const VkDescriptorBufferInfo,
+          DescriptorInfo::Image(_) => null(),
+        },
+        pTexelBufferView: null(),
+      })
+      .collect();
+
+    unsafe {
+      vkUpdateDescriptorSets(device, writes.len() as u32, writes.as_ptr(), 0, null());
+    }
+  }
+
+  fn push_binding(
+    &mut self,
+    binding: u32,
+    descriptor_type: VkDescriptorType,
+    stage_flags: VkShaderStageFlags,
+  ) {
+    self.bindings.push(VkDescriptorSetLayoutBinding {
+      binding,
+      descriptorType: descriptor_type,
+      descriptorCount: 1,
+      stageFlags: stage_flags,
+      pImmutableSamplers: null(),
+    });
+  }
+}
@@ -1,18 +1,35 @@
 use {
   crate::{
+    camera::Camera,
     error::Error,
-    mesh::{Mesh, Vertex},
-    vk_initializers as vkinit,
+    frame_stats::FrameStats,
+    input::Input,
+    mesh::{GltfSceneNode, Mesh, Vertex},
+    vertex_layout::VertexLayout,
+    vk_capture::{self, FrameCapture},
+    vk_descriptors::{DescriptorAllocator, DescriptorBuilder, DescriptorLayoutCache},
+    vk_headless, vk_initializers as vkinit,
     vk_pipeline::PipelineBuilder,
-    vk_types::{AllocatedBuffer, AllocatedImage},
+    vk_pipeline_cache,
+    vk_reflect::{PipelineLayoutBuilder, ShaderReflection},
+    vk_shaders,
+    vk_textures::{self, Texture, TextureData},
+    vk_types::{
+      Buffer, CommandPool, DeletionQueue, DeviceContext, Fence, Framebuffer, Image, ImageView,
+      Pipeline, PipelineCache, PipelineLayout, RenderPass, Sampler, Semaphore, ShaderModule,
+      Swapchain, Window,
+    },
     VK_CHECK,
   },
   lina::{mat4::Mat4, vec3::Vec3, vec4::Vec4},
   sdl2::*,
   std::{
-    ffi::c_void,
+    collections::HashMap,
+    ffi::{c_void, CString},
     mem::{size_of, zeroed},
     ptr::{copy_nonoverlapping, null, null_mut},
+    rc::Rc,
+    time::Instant,
   },
   vkcapi::{
     core::{v1_0::*, v1_1::*},
@@ -21,97 +38,73 @@ use {
   vma::*,
 };
 
-#[cfg(feature = "validation")]
-use vkcapi::ext::vk_ext_debug_utils::*;
+#[cfg(feature = "compute")]
+use crate::vk_compute;
 
-// Don't know what kind of voodoo the C++ is doing but this is my solution for resource cleanup.
-// We will have a vector of enum Resouce(s). The enum carries the info needed for destruction,
-// and we match on the enum in ResourceDestructor flush.
-#[derive(Clone, Copy)]
-pub enum Resource {
-  SdlQuit,
-  SdlWindow(*mut sdl2::SDL_Window),
-  VkInstance(vkcapi::core::v1_0::VkInstance),
-  VkDebugUtilsMessengerEXT(VkDebugUtilsMessengerEXT),
-  VkSurfaceKHR(vkcapi::ext::vk_khr_surface::VkSurfaceKHR),
-  VkDevice(VkDevice),
-  VkSwapchainKHR(VkSwapchainKHR),
-  VkCommandPool(VkCommandPool),
-  VkRenderPass(VkRenderPass),
-  VkImageView(VkImageView),
-  VkFramebuffer(VkFramebuffer),
-  VkSemaphore(VkSemaphore),
-  VkFence(VkFence),
-  VkPipelineLayout(VkPipelineLayout),
-  VkPipeline(VkPipeline),
-  VmaAllocator(VmaAllocator),
-  VmaAllocatedBuffer(AllocatedBuffer),
-  VmaAllocatedImage(AllocatedImage),
-}
+// number of frames the CPU can record while the GPU is still busy with earlier ones
+const FRAME_OVERLAP: usize = 2;
+
+// the pipeline cache is kept in this file next to the executable between runs
+const PIPELINE_CACHE_FILE: &str = "pipeline_cache.bin";
+
+// the action bindings, the ones it ships with are used when it's missing
+const INPUT_CONFIG_FILE: &str = "input.cfg";
+
+// seconds between the updates of the simulation, however fast the frames are drawn
+const FIXED_TIMESTEP: f32 = 1.0 / 60.0;
+// a longer frame, e.g. a breakpoint or dragging the window, is cut to this, otherwise
+// the updates to catch up would make the next frame slow too
+const MAX_FRAME_TIME: f32 = 0.25;
+// seconds between updates of the FPS in the window title
+const FPS_TITLE_INTERVAL: f32 = 0.5;
+
+const WINDOW_TITLE: &str = "Vulkan Engine";
+
+// the materials of the tutorial's first triangles, toggle_shader cycles through them
+const TRIANGLE_MATERIALS: [&str; 2] = ["coloredtriangle", "redtriangle"];
+
+// everything that can't be touched while the GPU is rendering a frame,
+// so we keep one of these per frame in flight.
+struct FrameData {
+  present_semaphore: Semaphore,
+  render_semaphore: Semaphore,
+  render_fence: Fence,
+
+  command_pool: CommandPool,            // the command pool for our commands
+  main_command_buffer: VkCommandBuffer, // the buffer we will record into
+
+  camera_buffer: Buffer,              // GpuCameraData for this frame
+  global_descriptor: VkDescriptorSet, // set 0, camera and scene data
 
-pub struct ResourceDestuctor {
-  resources: Vec<Resource>,
+  // resources to drop once this frame's fence is signaled, see defer_deletion
+  deletion_queue: DeletionQueue,
 }
 
-impl ResourceDestuctor {
-  pub fn new() -> ResourceDestuctor {
-    ResourceDestuctor {
-      resources: Vec::new(),
-    }
-  }
+impl FrameData {
+  fn null() -> FrameData {
+    FrameData {
+      present_semaphore: Semaphore::null(),
+      render_semaphore: Semaphore::null(),
+      render_fence: Fence::null(),
+      command_pool: CommandPool::null(),
+      main_command_buffer: null(),
 
-  pub fn push(&mut self, res: Resource) {
-    self.resources.push(res)
-  }
+      camera_buffer: Buffer::null(),
+      global_descriptor: null(),
 
-  pub fn flush(
-    &mut self,
-    instance: vkcapi::core::v1_0::VkInstance,
-    device: VkDevice,
-    allocator: VmaAllocator,
-  ) {
-    while !self.resources.is_empty() {
-      let res = self.resources.pop().unwrap();
-      match res {
-        Resource::SdlQuit => unsafe { SDL_Quit() },
-        Resource::SdlWindow(window) => unsafe { sdl2::SDL_DestroyWindow(window) },
-        Resource::VkInstance(instance) => unsafe { vkDestroyInstance(instance, null()) },
-        Resource::VkDebugUtilsMessengerEXT(debug_messenger) => unsafe {
-          vkDestroyDebugUtilsMessengerEXT(instance, debug_messenger, null())
-        },
-        Resource::VkSurfaceKHR(surface) => unsafe {
-          vkDestroySurfaceKHR(instance, surface, null())
-        },
-        Resource::VkDevice(dev) => unsafe { vkDestroyDevice(dev, null()) },
-        Resource::VkSwapchainKHR(swapchain) => unsafe {
-          vkDestroySwapchainKHR(device, swapchain, null())
-        },
-        Resource::VkCommandPool(pool) => unsafe { vkDestroyCommandPool(device, pool, null()) },
-        Resource::VkRenderPass(pass) => unsafe { vkDestroyRenderPass(device, pass, null()) },
-        Resource::VkImageView(iv) => unsafe { vkDestroyImageView(device, iv, null()) },
-        Resource::VkFramebuffer(fb) => unsafe { vkDestroyFramebuffer(device, fb, null()) },
-        Resource::VkSemaphore(sem) => unsafe { vkDestroySemaphore(device, sem, null()) },
-        Resource::VkFence(fence) => unsafe { vkDestroyFence(device, fence, null()) },
-        Resource::VkPipelineLayout(pipe_layout) => unsafe {
-          vkDestroyPipelineLayout(device, pipe_layout, null())
-        },
-        Resource::VkPipeline(pipe) => unsafe { vkDestroyPipeline(device, pipe, null()) },
-        Resource::VmaAllocator(allocator) => unsafe { vmaDestroyAllocator(allocator) },
-        Resource::VmaAllocatedBuffer(allocated_buffer) => unsafe {
-          vmaDestroyBuffer(
-            allocator,
-            allocated_buffer.buffer,
-            allocated_buffer.allocation,
-          )
-        },
-        Resource::VmaAllocatedImage(allocated_image) => unsafe {
-          vmaDestroyImage(allocator, allocated_image.image, allocated_image.allocation)
-        },
-      }
+      deletion_queue: DeletionQueue::new(),
     }
   }
 }
 
+// one-off commands, like copying from a staging buffer, are recorded and
+// submitted with these and don't have to wait for the frame to come around.
+struct UploadContext {
+  upload_fence: Fence,
+  command_pool: CommandPool,
+  command_buffer: VkCommandBuffer,
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 struct MeshPushConstants {
@@ -119,132 +112,335 @@ struct MeshPushConstants {
   render_matrix: Mat4,
 }
 
+// a shader module and the interface reflected from its SPIR-V
+struct Shader {
+  module: ShaderModule,
+  reflection: ShaderReflection,
+}
+
+// everything needed to draw with a given look, shared by all the objects that use it
+#[derive(Clone, Copy)]
+pub struct Material {
+  pub texture_set: VkDescriptorSet, // set 1, null if the material has no textures
+  pub pipeline: VkPipeline,
+  pub pipeline_layout: VkPipelineLayout,
+  pub push_constant_stages: VkShaderStageFlags, // of the layout's range at offset 0
+}
+
+// a single thing to draw. The mesh and material are looked up by name in the engine
+pub struct RenderObject {
+  pub mesh: String,
+  pub material: String,
+  pub transform: Mat4,
+}
+
+// matches the CameraBuffer block in the vertex shader
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuCameraData {
+  view: Mat4,
+  proj: Mat4,
+  viewproj: Mat4,
+}
+
+// matches the SceneData block in the fragment shader
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpuSceneData {
+  fog_color: Vec4,     // w is for exponent
+  fog_distances: Vec4, // x for min, y for max, zw unused.
+  ambient_color: Vec4,
+  sunlight_direction: Vec4, // w for sun power
+  sunlight_color: Vec4,
+}
+
+// Whoever runs the engine with run. The engine doesn't print, it hands what happens to
+// the app to show however it likes.
+pub trait App {
+  // Called FIXED_TIMESTEP apart with dt = FIXED_TIMESTEP, after the engine's own
+  // update, for the app's simulation. Several times in a frame when the frames are
+  // slower than that, not at all in a faster one.
+  fn update(&mut self, _engine: &mut VulkanEngine, _dt: f32) {}
+
+  // the .spv names of the shaders that were reloaded, or why one didn't compile
+  #[cfg(feature = "hot_reload")]
+  fn shaders_reloaded(&mut self, _result: Result<&[String], &Error>) {}
+}
+
 pub struct VulkanEngine {
   is_initialized: bool,
   frame_number: i32,
+  // seconds of simulation, advanced by update. The animations are driven by it
+  time: f32,
+  // how long the frames of run took
+  frame_stats: FrameStats,
+  show_fps: bool, // in the window title
+  // frames and seconds since the title was updated
+  title_frames: u32,
+  title_time: f32,
+
+  // no window, surface or swapchain. We render into offscreen_image instead
+  headless: bool,
 
   window_extent: VkExtent2D,
-  window: *mut SDL_Window,
 
-  instance: vkcapi::core::v1_0::VkInstance, // Vulkan library handle
-  #[cfg(feature = "validation")]
-  debug_messenger: VkDebugUtilsMessengerEXT, // Vulkan debug output handle
+  // instance, surface, device and allocator. Everything below holds on to it
+  context: Rc<DeviceContext>,
 
   chosen_gpu: VkPhysicalDevice, // GPU chosen as the default device
-  device: VkDevice,             // Vulkan device for commands
-  surface: vkcapi::ext::vk_khr_surface::VkSurfaceKHR, // Vulkan window surface
+  gpu_properties: VkPhysicalDeviceProperties, // limits of the chosen GPU
 
   graphics_queue: VkQueue,   // Queue for graphics commands
   graphics_queue_index: u32, // index of graphics queue
   present_queue: VkQueue,    // Queue for presentation to surface
   present_queue_index: u32,  // index of presentation queue
+  #[cfg(feature = "compute")]
+  compute_queue: VkQueue, // Queue for compute work, may be the graphics queue
+  #[cfg(feature = "compute")]
+  compute_queue_index: u32, // index of compute queue
 
-  swapchain: VkSwapchainKHR,
+  swapchain: Swapchain,
   swapchain_format: VkFormat, // image format expected by windowing system
   swapchain_images: Vec<VkImage>, // array of images from the swapchain
-  swapchain_image_views: Vec<VkImageView>, // array of image-views from the swapchain
+  swapchain_image_views: Vec<ImageView>, // array of image-views from the swapchain
+  // the swapchain images were made with TRANSFER_SRC usage, so frames can be captured
+  swapchain_capturable: bool,
+  // set by capture_frame, the next frame is copied into it before it's presented
+  capture_buffer: VkBuffer,
 
-  depth_image_view: VkImageView,
-  depth_image: AllocatedImage,
-  depth_format: VkFormat,
-
-  command_pool: VkCommandPool, // the command pool for our commands
-  main_command_buffer: VkCommandBuffer, // the buffer we will record into
-
-  render_pass: VkRenderPass,
-  framebuffers: Vec<VkFramebuffer>,
-
-  present_semaphore: VkSemaphore,
-  render_semaphore: VkSemaphore,
-  render_fence: VkFence,
-
-  triangle_pipeline_layout: VkPipelineLayout,
-  mesh_pipeline_layout: VkPipelineLayout,
+  // the color target in headless mode, it's also the only entry of swapchain_images
+  offscreen_image: Image,
 
-  triangle_pipeline: VkPipeline,
-  red_triangle_pipeline: VkPipeline,
-
-  mesh_pipeline: VkPipeline,
-  triangle_mesh: Mesh,
-  monkey_mesh: Mesh,
-
-  main_deletion_queue: ResourceDestuctor,
-  allocator: VmaAllocator,
+  depth_image_view: ImageView,
+  depth_image: Image,
+  depth_format: VkFormat,
 
-  selected_shader: i32,
+  frames: Vec<FrameData>, // FRAME_OVERLAP of them
+
+  render_pass: RenderPass,
+  framebuffers: Vec<Framebuffer>,
+
+  upload_context: UploadContext,
+
+  // this frame's keyboard, mouse and gamepad state, mapped to actions
+  input: Input,
+  // moved around by the input in run, headless keeps the default view
+  camera: Camera,
+
+  // default array of renderable objects
+  renderables: Vec<RenderObject>,
+  // where the monkey's nodes put its meshes, the first renderables. update turns them
+  monkey_transforms: Vec<Mat4>,
+  // which of TRIANGLE_MATERIALS is drawn over the scene, counting from 1. 0 for none
+  selected_shader: usize,
+
+  materials: HashMap<String, Material>,
+  meshes: HashMap<String, Mesh>,
+  textures: HashMap<String, Texture>,
+  // the pixels of every texture, they are uploaded again after the device was lost
+  texture_data: HashMap<String, TextureData>,
+  // the textured materials and the texture in their set 1, they share one pipeline
+  material_textures: HashMap<String, String>,
+
+  descriptor_allocator: DescriptorAllocator,
+  // the texture sets of the materials, reset when they are all written again
+  texture_set_allocator: DescriptorAllocator,
+  descriptor_layout_cache: DescriptorLayoutCache,
+
+  global_set_layout: VkDescriptorSetLayout,
+  single_texture_set_layout: VkDescriptorSetLayout, // set 1 of textured materials
+
+  scene_parameters: GpuSceneData,
+  // one GpuSceneData per frame in flight, each at a padded offset
+  scene_parameter_buffer: Buffer,
+
+  // the materials refer to these, they are replaced when the shaders change
+  pipelines: Vec<Pipeline>,
+  pipeline_layouts: Vec<PipelineLayout>,
+  // every pipeline is built through it, it's loaded from PIPELINE_CACHE_FILE and saved
+  // there by save_pipeline_cache
+  pipeline_cache: PipelineCache,
+  // the .spv names of the shaders the pipelines were built from
+  pipeline_shaders: Vec<String>,
+  #[cfg(feature = "hot_reload")]
+  shader_watcher: vk_shaders::ShaderWatcher,
+
+  resize_requested: bool, // the swapchain no longer matches the window
+  stop_rendering: bool,   // the window is minimized
+  lose_device: bool,      // set by simulate_device_lost, the next submit fails
+  device_losses: u32,     // times the device was lost and created again
+
+  // last, the surface in the context is made from it and goes with the last handle
+  window: Window,
 }
 
 impl VulkanEngine {
   pub fn new() -> VulkanEngine {
+    // replaced in init_vulkan, the null context doesn't destroy anything
+    let context = Rc::new(DeviceContext::null());
     VulkanEngine {
       is_initialized: false,
       frame_number: 0,
+      time: 0.0,
+      frame_stats: FrameStats::new(),
+      show_fps: false,
+      title_frames: 0,
+      title_time: 0.0,
+
+      headless: false,
 
       window_extent: VkExtent2D {
         width: 1700,
         height: 900,
       },
-      window: null_mut(),
-
-      instance: null(),
-      #[cfg(feature = "validation")]
-      debug_messenger: null(),
 
       chosen_gpu: null(),
-      device: null(),
-      surface: null(),
+      gpu_properties: unsafe { zeroed() },
 
       graphics_queue: null(),
       graphics_queue_index: u32::MAX, // zero is an actual queue index
       present_queue: null(),
       present_queue_index: u32::MAX, // max seems like a reasonable value for un-init
+      #[cfg(feature = "compute")]
+      compute_queue: null(),
+      #[cfg(feature = "compute")]
+      compute_queue_index: u32::MAX,
 
-      swapchain: null(),
+      swapchain: Swapchain::null(),
       swapchain_format: unsafe { zeroed() },
       swapchain_images: Vec::new(),
+      swapchain_capturable: false,
+      capture_buffer: null(),
       swapchain_image_views: Vec::new(),
 
-      depth_image_view: null(),
-      depth_image: AllocatedImage::null(),
+      offscreen_image: Image::null(),
+
+      depth_image_view: ImageView::null(),
+      depth_image: Image::null(),
       depth_format: 0,
 
-      command_pool: null(),
-      main_command_buffer: null(),
+      frames: (0..FRAME_OVERLAP).map(|_| FrameData::null()).collect(),
 
-      render_pass: null(),
+      render_pass: RenderPass::null(),
       framebuffers: Vec::new(),
 
-      present_semaphore: null(),
-      render_semaphore: null(),
-      render_fence: null(),
+      upload_context: UploadContext {
+        upload_fence: Fence::null(),
+        command_pool: CommandPool::null(),
+        command_buffer: null(),
+      },
 
-      triangle_pipeline_layout: null(),
-      mesh_pipeline_layout: null(),
+      input: Input::new(),
+      camera: Camera::new(),
 
-      triangle_pipeline: null(),
-      red_triangle_pipeline: null(),
+      renderables: Vec::new(),
+      monkey_transforms: Vec::new(),
+      selected_shader: 0,
 
-      mesh_pipeline: null(),
-      triangle_mesh: Mesh::new(),
-      monkey_mesh: Mesh::load_gltf("assets/monkey.glb").unwrap(),
+      materials: HashMap::new(),
+      meshes: HashMap::new(),
+      textures: HashMap::new(),
+      texture_data: HashMap::new(),
+      material_textures: HashMap::new(),
 
-      main_deletion_queue: ResourceDestuctor::new(),
-      allocator: null(),
+      descriptor_allocator: DescriptorAllocator::new(&context),
+      texture_set_allocator: DescriptorAllocator::new(&context),
+      descriptor_layout_cache: DescriptorLayoutCache::new(&context),
 
-      selected_shader: 0,
+      global_set_layout: null(),
+      single_texture_set_layout: null(),
+
+      scene_parameters: unsafe { zeroed() },
+      scene_parameter_buffer: Buffer::null(),
+
+      pipelines: Vec::new(),
+      pipeline_layouts: Vec::new(),
+      pipeline_cache: PipelineCache::null(),
+      pipeline_shaders: Vec::new(),
+      #[cfg(feature = "hot_reload")]
+      shader_watcher: vk_shaders::ShaderWatcher::new("shaders"),
+
+      resize_requested: false,
+      stop_rendering: false,
+      lose_device: false,
+      device_losses: 0,
+
+      window: Window::null(),
+
+      // moved in last, the descriptor allocator and layout cache took a reference first
+      context,
     }
   }
 
+  // an engine that renders width x height pixels offscreen, for machines without a display
+  pub fn new_headless(width: u32, height: u32) -> VulkanEngine {
+    let mut engine = VulkanEngine::new();
+    engine.headless = true;
+    engine.window_extent = VkExtent2D { width, height };
+    engine
+  }
+
   // initializes everything in the engine
   pub fn init(&mut self) -> Result<(), Error> {
-    // We initialize SDL and create a window with it.
+    // We initialize SDL and create a window with it. Headless has nothing to show
+    if !self.headless {
+      self.init_window()?;
+      self.input.load(INPUT_CONFIG_FILE)?;
+    }
+
+    self.init_device()?;
+
+    self.load_images()?;
+
+    let (monkey_nodes, monkey_materials) = self.load_meshes()?;
+
+    self.init_scene(&monkey_nodes, &monkey_materials)?;
+
+    // everything went fine
+    self.is_initialized = true;
+
+    Ok(())
+  }
+
+  // Everything on the GPU that doesn't come from the assets, from the instance up to
+  // the pipelines. It's done again when the device is lost.
+  fn init_device(&mut self) -> Result<(), Error> {
+    // load the core Vulkan structures
+    self.init_vulkan()?;
+
+    // create the swapchain, or the image that takes its place
+    if self.headless {
+      self.init_offscreen()?;
+    } else {
+      self.init_swapchain()?;
+    }
+
+    self.init_commands()?;
+
+    self.init_default_renderpass()?;
+
+    self.init_framebuffers()?;
+
+    self.init_sync_structures()?;
+
+    self.init_descriptors()?;
+
+    let pipeline_cache = vk_pipeline_cache::create_pipeline_cache(
+      self.context.device,
+      &self.gpu_properties,
+      &vk_pipeline_cache::cache_path(PIPELINE_CACHE_FILE),
+    )?;
+    self.pipeline_cache = PipelineCache::new(&self.context, pipeline_cache);
+    self.build_pipelines(&HashMap::new())
+  }
+
+  fn init_window(&mut self) -> Result<(), Error> {
     unsafe {
-      SDL_Init(SDL_INIT_VIDEO);
-      self.main_deletion_queue.push(Resource::SdlQuit);
-      let window_flags = SDL_WINDOW_VULKAN;
+      SDL_Init(SDL_INIT_VIDEO | SDL_INIT_GAMECONTROLLER);
+      let window_flags = SDL_WINDOW_VULKAN | SDL_WINDOW_RESIZABLE;
 
       // create blank window for our application
-      self.window = SDL_CreateWindow(
+      let window = SDL_CreateWindow(
         b"Vulkan Engine\0".as_ptr() as *const i8, // window title
         SDL_WINDOWPOS_UNDEFINED_MASK as i32,      // window position x (don't care)
         SDL_WINDOWPOS_UNDEFINED_MASK as i32,      // window position y (don't care)
@@ -252,75 +448,221 @@ impl VulkanEngine {
         self.window_extent.height as i32,         // window height in pixels
         window_flags,
       );
-      self
-        .main_deletion_queue
-        .push(Resource::SdlWindow(self.window));
+      if window.is_null() {
+        SDL_Quit();
+        return Err(Error::Str("Failed to create the window"));
+      }
+      self.window = Window::new(window);
     }
+    Ok(())
+  }
 
-    // load the core Vulkan structures
-    self.init_vulkan()?;
+  // Writes what was compiled this run to PIPELINE_CACHE_FILE, it makes the next startup
+  // faster. Call it before the engine is dropped, dropping can't report a failure.
+  pub fn save_pipeline_cache(&self) -> Result<(), Error> {
+    if !self.is_initialized {
+      return Err(Error::Str("There is no pipeline cache before init"));
+    }
+    vk_pipeline_cache::save_pipeline_cache(
+      self.context.device,
+      self.pipeline_cache.handle,
+      &vk_pipeline_cache::cache_path(PIPELINE_CACHE_FILE),
+    )
+  }
 
-    // create the swapchain
-    self.init_swapchain()?;
+  // Shuts down the engine, called when it's dropped. Everything is destroyed by dropping
+  // its handle, this only waits for the GPU and gets the order of the swapchain right.
+  fn cleanup(&mut self) {
+    // nothing can be destroyed while the GPU is still using it. After a failed init
+    // there might not be a device yet
+    if !self.context.device.is_null() {
+      unsafe {
+        vkDeviceWaitIdle(self.context.device);
+      }
+    }
+    for frame in self.frames.iter_mut() {
+      frame.deletion_queue.flush();
+    }
+    self.destroy_swapchain();
+  }
 
-    self.init_commands()?;
+  // Draws a frame. If the device was lost on the way, the frame is dropped and
+  // everything on the GPU is created again, the next one is drawn on the new device.
+  fn render_frame(&mut self) -> Result<(), Error> {
+    let result = self.draw();
+    self.recover_device_lost(result)
+  }
 
-    self.init_default_renderpass()?;
+  // Passes result on, unless it's VK_ERROR_DEVICE_LOST. Then everything on the GPU
+  // is created again and device_losses counts it. Every call that can report a lost
+  // device outside of teardown goes through here.
+  fn recover_device_lost(&mut self, result: Result<(), Error>) -> Result<(), Error> {
+    match result {
+      Err(Error::VkError(VK_ERROR_DEVICE_LOST, ..)) => {
+        self.device_losses += 1;
+        self.recreate_device()
+      }
+      result => result,
+    }
+  }
 
-    self.init_framebuffers()?;
+  // the queue for compute passes and its family, for the barriers that hand buffers
+  // over when it isn't the graphics family
+  #[cfg(feature = "compute")]
+  pub fn compute_queue(&self) -> (VkQueue, u32) {
+    (self.compute_queue, self.compute_queue_index)
+  }
 
-    self.init_sync_structures()?;
+  pub fn device_losses(&self) -> u32 {
+    self.device_losses
+  }
 
-    self.init_pipelines()?;
+  // For testing the recovery, the next frame's submit reports VK_ERROR_DEVICE_LOST
+  // without touching the GPU.
+  pub fn simulate_device_lost(&mut self) {
+    self.lose_device = true;
+  }
 
-    self.load_meshes()?;
+  // Nothing made from a lost device can be used again, so all of it is dropped and
+  // made from scratch, down to the instance. Meshes and textures are uploaded again
+  // from the CPU side copies, the renderables refer to them by name and stay as they are.
+  fn recreate_device(&mut self) -> Result<(), Error> {
+    // not worth saving the pipeline cache of a lost device
+    self.is_initialized = false;
+    self.destroy_device();
 
-    // everything went fine
-    self.is_initialized = true;
+    self.init_device()?;
+
+    let mut meshes = std::mem::take(&mut self.meshes);
+    for mesh in meshes.values_mut() {
+      self.upload_mesh(mesh)?;
+    }
+    self.meshes = meshes;
 
+    // a clone, load_texture puts every texture back in and a failure mustn't lose them
+    for (name, data) in self.texture_data.clone() {
+      self.load_texture(&name, &data)?;
+    }
+    self.write_texture_sets()?;
+
+    // the new swapchain was made for the window as it is now
+    self.resize_requested = false;
+
+    self.is_initialized = true;
     Ok(())
   }
 
-  // shuts down the engine
-  pub fn cleanup(&mut self) {
-    if self.is_initialized {
-      // using the deletion queue for everything, unlike the tutorial
-      self
-        .main_deletion_queue
-        .flush(self.instance, self.device, self.allocator);
+  // Drops every handle made from the current device and then the context, leaving the
+  // engine as it was before init_device. Destroying is still allowed on a lost device.
+  fn destroy_device(&mut self) {
+    // a really lost device returns right away, a simulated loss can still have frames
+    // in flight on a working one
+    unsafe {
+      vkDeviceWaitIdle(self.context.device);
     }
+    self.destroy_swapchain();
+
+    self.frames = (0..FRAME_OVERLAP).map(|_| FrameData::null()).collect();
+    self.upload_context = UploadContext {
+      upload_fence: Fence::null(),
+      command_pool: CommandPool::null(),
+      command_buffer: null(),
+    };
+    self.render_pass = RenderPass::null();
+
+    for mesh in self.meshes.values_mut() {
+      mesh.vertex_buffer = Buffer::null();
+      mesh.index_buffer = Buffer::null();
+    }
+    self.textures.clear();
+    // build_pipelines registers them again
+    self.materials.clear();
+
+    let null_context = Rc::new(DeviceContext::null());
+    self.descriptor_allocator = DescriptorAllocator::new(&null_context);
+    self.texture_set_allocator = DescriptorAllocator::new(&null_context);
+    self.descriptor_layout_cache = DescriptorLayoutCache::new(&null_context);
+    self.global_set_layout = null();
+    self.single_texture_set_layout = null();
+    self.scene_parameter_buffer = Buffer::null();
+
+    self.pipelines.clear();
+    self.pipeline_layouts.clear();
+    self.pipeline_cache = PipelineCache::null();
+
+    // the last handle is gone, this destroys the device and the instance
+    self.context = null_context;
   }
 
-  // draw loop
-  fn draw(&mut self) {
-    // wait until the GPU has finished rendering the last frame. Timeout of 1 second
+  // the frame data we record into this frame, they take turns
+  fn get_current_frame(&self) -> &FrameData {
+    &self.frames[self.frame_number as usize % FRAME_OVERLAP]
+  }
+
+  // Keeps resources the GPU might still be using until it's done with them. They go with
+  // the last frame submitted, its fence covers everything submitted before it too.
+  fn defer_deletion<T: 'static>(&mut self, resources: T) {
+    let last_frame = (self.frame_number as usize + FRAME_OVERLAP - 1) % FRAME_OVERLAP;
+    self.frames[last_frame].deletion_queue.push(resources);
+  }
+
+  // Draw loop. A Vulkan error comes back to whoever runs the engine, an out of date
+  // swapchain doesn't, it's rebuilt before the next frame.
+  fn draw(&mut self) -> Result<(), Error> {
+    let frame = self.get_current_frame();
+    let render_fence = frame.render_fence.handle;
+    let present_semaphore = frame.present_semaphore.handle;
+    let render_semaphore = frame.render_semaphore.handle;
+    // naming it cmd for shorter writing
+    let cmd = frame.main_command_buffer;
+
+    // wait until the GPU has finished rendering the last time this frame data was used.
+    // Timeout of 1 second
     unsafe {
       VK_CHECK!(vkWaitForFences(
-        self.device,
+        self.context.device,
         1,
-        &self.render_fence,
+        &render_fence,
         VK_TRUE, // true is not an int in rust
         1_000_000_000
-      ));
-      VK_CHECK!(vkResetFences(self.device, 1, &self.render_fence));
+      ))?;
+    }
+    // whatever was waiting on that frame can go now
+    let frame_index = self.frame_number as usize % FRAME_OVERLAP;
+    self.frames[frame_index].deletion_queue.flush();
 
-      // request image from the swapchain, one second timeout
+    unsafe {
+      // request image from the swapchain, one second timeout.
+      // Headless always renders into the one offscreen image
       let mut swapchain_image_index = 0;
-      VK_CHECK!(vkAcquireNextImageKHR(
-        self.device,
-        self.swapchain,
-        1_000_000_000,
-        self.present_semaphore,
-        null(),
-        &mut swapchain_image_index
-      ));
+      if !self.headless {
+        let result = vkAcquireNextImageKHR(
+          self.context.device,
+          self.swapchain.handle,
+          1_000_000_000,
+          present_semaphore,
+          null(),
+          &mut swapchain_image_index,
+        );
+        // out of date means we can't render to this swapchain at all. We leave before
+        // resetting the fence so the wait above doesn't hang when we come back
+        if result == VK_ERROR_OUT_OF_DATE_KHR {
+          self.resize_requested = true;
+          return Ok(());
+        }
+        // suboptimal still gave us an image, so we render this frame and rebuild later
+        if result == VK_SUBOPTIMAL_KHR {
+          self.resize_requested = true;
+        } else {
+          VK_CHECK!(result, "vkAcquireNextImageKHR")?;
+        }
+      }
+
+      VK_CHECK!(vkResetFences(self.context.device, 1, &render_fence))?;
 
       // now that we are sure that the commands finished executing,
       // we can safely reset the command buffer to begin recording again.
-      VK_CHECK!(vkResetCommandBuffer(self.main_command_buffer, 0));
-
-      // naming it cmd for shorter writing
-      let cmd = self.main_command_buffer;
+      VK_CHECK!(vkResetCommandBuffer(cmd, 0))?;
 
       // begin the command buffer recording. We will use this command buffer
       // exactly once, so we want to let Vulkan know that
@@ -330,10 +672,11 @@ impl VulkanEngine {
         flags: VK_COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
         pInheritanceInfo: null(),
       };
-      VK_CHECK!(vkBeginCommandBuffer(cmd, &cmd_begin_info));
+      VK_CHECK!(vkBeginCommandBuffer(cmd, &cmd_begin_info))?;
 
-      // make a clear-color from frame number. This will flash with a 120*pi frame period.
-      let flash = f32::abs(f32::sin(self.frame_number as f32 / 120.0));
+      // make a clear-color from the time. This will flash every 2*pi seconds, at 60 fps
+      // the speed it had when it went by the frame number.
+      let flash = f32::abs(f32::sin(self.time * 0.5));
       let clear_value = vkinit::clear_value_f32(0.0, 0.0, flash, 1.0);
 
       let depth_clear = VkClearValue {
@@ -348,78 +691,81 @@ impl VulkanEngine {
       let rp_info = VkRenderPassBeginInfo {
         sType: VK_STRUCTURE_TYPE_RENDER_PASS_BEGIN_INFO,
         pNext: null(),
-        renderPass: self.render_pass,
-        framebuffer: self.framebuffers[swapchain_image_index as usize],
+        renderPass: self.render_pass.handle,
+        framebuffer: self.framebuffers[swapchain_image_index as usize].handle,
         renderArea: vkinit::rect_2d(0, 0, self.window_extent.width, self.window_extent.height),
         clearValueCount: 2,
         pClearValues: [clear_value, depth_clear].as_ptr(),
       };
       vkCmdBeginRenderPass(cmd, &rp_info, VK_SUBPASS_CONTENTS_INLINE);
 
-      vkCmdBindPipeline(cmd, VK_PIPELINE_BIND_POINT_GRAPHICS, self.mesh_pipeline);
-
-      let offset = 0;
-      vkCmdBindVertexBuffers(cmd, 0, 1, &self.monkey_mesh.vertex_buffer.buffer, &offset);
-
-      // make a model view matrix for rendering the object
-      // camera position
-      let cam_pos = Vec3::new(0.0, 0.0, -2.0);
-      let view = Mat4::translate_matrix(cam_pos.x, cam_pos.y, cam_pos.z);
-      // camera projection
-      let mut projection =
-        Mat4::perspective_matrix(lina::radians!(70.0), 1700.0 / 900.0, 0.1, 200.0);
-      projection.c2r2 *= -1.0;
-      // model rotation
-      let model =
-        Mat4::rotate_vec_angle_matrix(0.0, 1.0, 0.0, lina::radians!(self.frame_number as f32));
-
-      // calculate final mesh matrix
-      let mesh_matrix = projection * view * model;
-
-      let constants = MeshPushConstants {
-        data: Vec4::new(0.0, 0.0, 0.0, 0.0),
-        render_matrix: mesh_matrix,
-      };
-
-      // upload the matrix to the GPU via push constants
-      vkCmdPushConstants(
-        cmd,
-        self.mesh_pipeline_layout,
-        VK_SHADER_STAGE_VERTEX_BIT,
-        0,
-        size_of::<MeshPushConstants>() as u32,
-        &constants as *const MeshPushConstants as *const c_void,
+      // the pipelines leave the viewport and scissor dynamic, they cover the whole image
+      let viewport = vkinit::viewport(
+        0.0,
+        0.0,
+        self.window_extent.width as f32,
+        self.window_extent.height as f32,
+        0.0,
+        1.0,
       );
+      vkCmdSetViewport(cmd, 0, 1, &viewport);
+      let scissor = vkinit::rect_2d(0, 0, self.window_extent.width, self.window_extent.height);
+      vkCmdSetScissor(cmd, 0, 1, &scissor);
+
+      // slowly cycle the ambient color
+      let cycle = self.time * 0.5;
+      self.scene_parameters.ambient_color = Vec4::new(f32::sin(cycle), 0.0, f32::cos(cycle), 1.0);
 
-      vkCmdDraw(cmd, self.monkey_mesh.vertices.len() as u32, 1, 0, 0);
+      self.draw_objects(cmd)?;
 
       // finalize the render render_pass
       vkCmdEndRenderPass(cmd);
+
+      // capture_frame asked for this frame, it's copied before the present gets it
+      if !self.capture_buffer.is_null() {
+        vk_capture::cmd_copy_image_to_buffer(
+          cmd,
+          self.swapchain_images[swapchain_image_index as usize],
+          VK_IMAGE_LAYOUT_PRESENT_SRC_KHR,
+          self.capture_buffer,
+          self.window_extent.width,
+          self.window_extent.height,
+        );
+      }
       // finalize the command buffer (we can no longer add commands, but it can be executed)
-      VK_CHECK!(vkEndCommandBuffer(cmd));
+      VK_CHECK!(vkEndCommandBuffer(cmd))?;
 
       // prepare the submission to the queue. We want to wait on the present_semaphore,
       // as that is signaled when the swapchain is ready.
       // We will signal the render_semaphore, to signal that rendering is finished.
+      // Headless nothing is acquired or presented, so there are no semaphores.
+      let semaphore_count = if self.headless { 0 } else { 1 };
       let submit = VkSubmitInfo {
         sType: VK_STRUCTURE_TYPE_SUBMIT_INFO,
         pNext: null(),
-        waitSemaphoreCount: 1,
-        pWaitSemaphores: &self.present_semaphore,
+        waitSemaphoreCount: semaphore_count,
+        pWaitSemaphores: &present_semaphore,
         pWaitDstStageMask: &VK_PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
         commandBufferCount: 1,
         pCommandBuffers: &cmd,
-        signalSemaphoreCount: 1,
-        pSignalSemaphores: &self.render_semaphore,
+        signalSemaphoreCount: semaphore_count,
+        pSignalSemaphores: &render_semaphore,
       };
       // submit command buffer to the queue and execute it.
       // render_fence will now block until the graphic commands finish execution
-      VK_CHECK!(vkQueueSubmit(
-        self.graphics_queue,
-        1,
-        &submit,
-        self.render_fence
-      ));
+      // simulate_device_lost makes this submit fail the way a GPU hang would
+      let result = if self.lose_device {
+        self.lose_device = false;
+        VK_ERROR_DEVICE_LOST
+      } else {
+        vkQueueSubmit(self.graphics_queue, 1, &submit, render_fence)
+      };
+      VK_CHECK!(result, "vkQueueSubmit")?;
+
+      if self.headless {
+        self.frame_number += 1;
+        return Ok(());
+      }
 
       // this will put the image we just rendered into the visible window.
       // we want to wait on the render_semaphore for that, as it's necessary
@@ -428,99 +774,558 @@ impl VulkanEngine {
         sType: VK_STRUCTURE_TYPE_PRESENT_INFO_KHR,
         pNext: null(),
         waitSemaphoreCount: 1,
-        pWaitSemaphores: &self.render_semaphore,
+        pWaitSemaphores: &render_semaphore,
         swapchainCount: 1,
-        pSwapchains: &self.swapchain,
+        pSwapchains: &self.swapchain.handle,
         pImageIndices: &swapchain_image_index,
         pResults: null_mut(),
       };
-      VK_CHECK!(vkQueuePresentKHR(self.graphics_queue, &present_info));
+      let result = vkQueuePresentKHR(self.graphics_queue, &present_info);
+      if result == VK_ERROR_OUT_OF_DATE_KHR || result == VK_SUBOPTIMAL_KHR {
+        self.resize_requested = true;
+      } else {
+        VK_CHECK!(result, "vkQueuePresentKHR")?;
+      }
 
       // increase the number of frames drawn
       self.frame_number += 1;
     }
+    Ok(())
+  }
+
+  // records the draws of every renderable. Objects that share a material or mesh with
+  // the one before them don't bind it again, so sorting the renderables helps.
+  fn draw_objects(&self, cmd: VkCommandBuffer) -> Result<(), Error> {
+    let frame = self.get_current_frame();
+
+    let view = self.camera.view_matrix();
+    let aspect = self.window_extent.width as f32 / self.window_extent.height as f32;
+    let projection = self.camera.projection_matrix(aspect);
+
+    // fill a GPU camera data struct
+    let cam_data = GpuCameraData {
+      view,
+      proj: projection,
+      viewproj: projection * view,
+    };
+
+    // the scene data of this frame goes at its padded offset
+    let frame_index = self.frame_number as usize % FRAME_OVERLAP;
+    let scene_offset = self.pad_uniform_buffer_size(size_of::<GpuSceneData>()) * frame_index;
+
+    unsafe {
+      // and copy it to the buffer
+      let mut data = null_mut();
+      VK_CHECK!(vmaMapMemory(
+        self.context.allocator,
+        frame.camera_buffer.allocation,
+        &mut data
+      ))?;
+      copy_nonoverlapping(&cam_data, data as *mut GpuCameraData, 1);
+      vmaUnmapMemory(self.context.allocator, frame.camera_buffer.allocation);
+
+      let mut scene_data = null_mut();
+      VK_CHECK!(vmaMapMemory(
+        self.context.allocator,
+        self.scene_parameter_buffer.allocation,
+        &mut scene_data
+      ))?;
+      copy_nonoverlapping(
+        &self.scene_parameters,
+        (scene_data as *mut u8).add(scene_offset) as *mut GpuSceneData,
+        1,
+      );
+      vmaUnmapMemory(
+        self.context.allocator,
+        self.scene_parameter_buffer.allocation,
+      );
+    }
+
+    let mut last_mesh: Option<&str> = None;
+    let mut last_material: Option<&str> = None;
+    for object in &self.renderables {
+      // objects pointing at something that was never registered are skipped
+      let (material, mesh) = match (
+        self.get_material(&object.material),
+        self.get_mesh(&object.mesh),
+      ) {
+        (Some(material), Some(mesh)) => (material, mesh),
+        _ => continue,
+      };
+
+      unsafe {
+        // only bind the pipeline if it doesn't match with the already bound one
+        if last_material != Some(object.material.as_str()) {
+          vkCmdBindPipeline(cmd, VK_PIPELINE_BIND_POINT_GRAPHICS, material.pipeline);
+          last_material = Some(object.material.as_str());
+
+          // bind the global set, the dynamic offset picks this frame's scene data
+          let uniform_offset = scene_offset as u32;
+          vkCmdBindDescriptorSets(
+            cmd,
+            VK_PIPELINE_BIND_POINT_GRAPHICS,
+            material.pipeline_layout,
+            0,
+            1,
+            &frame.global_descriptor,
+            1,
+            &uniform_offset,
+          );
+
+          if !material.texture_set.is_null() {
+            vkCmdBindDescriptorSets(
+              cmd,
+              VK_PIPELINE_BIND_POINT_GRAPHICS,
+              material.pipeline_layout,
+              1,
+              1,
+              &material.texture_set,
+              0,
+              null(),
+            );
+          }
+        }
+
+        // the camera is applied in the shader, only the model matrix is pushed
+        let constants = MeshPushConstants {
+          data: Vec4::new(0.0, 0.0, 0.0, 0.0),
+          render_matrix: object.transform,
+        };
+
+        // upload the matrix to the GPU via push constants, for every stage that shares
+        // the range. Vulkan wants exactly those stages
+        if material.push_constant_stages != 0 {
+          vkCmdPushConstants(
+            cmd,
+            material.pipeline_layout,
+            material.push_constant_stages,
+            0,
+            size_of::<MeshPushConstants>() as u32,
+            &constants as *const MeshPushConstants as *const c_void,
+          );
+        }
+
+        // only bind the mesh if it's a different one from last bind
+        if last_mesh != Some(object.mesh.as_str()) {
+          let offset = 0;
+          vkCmdBindVertexBuffers(cmd, 0, 1, &mesh.vertex_buffer.buffer, &offset);
+          vkCmdBindIndexBuffer(cmd, mesh.index_buffer.buffer, 0, VK_INDEX_TYPE_UINT32);
+          last_mesh = Some(object.mesh.as_str());
+        }
+
+        vkCmdDrawIndexed(cmd, mesh.indices.len() as u32, 1, 0, 0, 0);
+      }
+    }
+
+    // the triangle's shaders make up its vertices, there is nothing to bind but the pipeline
+    if self.selected_shader > 0 {
+      if let Some(material) = self.get_material(TRIANGLE_MATERIALS[self.selected_shader - 1]) {
+        unsafe {
+          vkCmdBindPipeline(cmd, VK_PIPELINE_BIND_POINT_GRAPHICS, material.pipeline);
+          vkCmdDraw(cmd, 3, 1, 0, 0);
+        }
+      }
+    }
+    Ok(())
   }
 
-  // run main loop
-  pub fn run(&mut self) {
+  // headless has no events to wait for, it draws frame_count frames and stops
+  // The frames are FIXED_TIMESTEP apart, so what they show doesn't depend on how fast
+  // the GPU is. The first frame is drawn at time zero.
+  pub fn run_headless(&mut self, frame_count: u32) -> Result<(), Error> {
+    for _ in 0..frame_count {
+      if self.frame_number > 0 {
+        self.update(FIXED_TIMESTEP);
+      }
+      self.render_frame()?;
+    }
+    // we need to wait for rendering to finish before starting cleanup
+    let result = unsafe { VK_CHECK!(vkQueueWaitIdle(self.graphics_queue)) };
+    self.recover_device_lost(result)
+  }
+
+  // Headless, reads back the last frame drawn from the offscreen image. A swapchain
+  // image belongs to the presentation engine once it's presented, so with a window
+  // this draws the next frame and copies it before it's presented.
+  pub fn capture_frame(&mut self) -> Result<FrameCapture, Error> {
+    if self.frame_number == 0 {
+      return Err(Error::Str("Can't capture a frame before one is drawn"));
+    }
+    if !self.headless && !self.swapchain_capturable {
+      return Err(Error::Str("The swapchain images can't be copied from"));
+    }
+
+    let width = self.window_extent.width;
+    let height = self.window_extent.height;
+    let buffer_size = (width * height * 4) as usize;
+    let buffer = self.create_buffer(
+      buffer_size,
+      VK_BUFFER_USAGE_TRANSFER_DST_BIT,
+      VMA_MEMORY_USAGE_GPU_TO_CPU,
+    )?;
+
+    if self.headless {
+      // the copy is submitted to the queue the frame went to, its barrier waits for it
+      let image = self.offscreen_image.image;
+      self.immediate_submit(|cmd| unsafe {
+        vk_capture::cmd_copy_image_to_buffer(
+          cmd,
+          image,
+          VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+          buffer.buffer,
+          width,
+          height,
+        )
+      })?;
+    } else {
+      let frame_number = self.frame_number;
+      self.capture_buffer = buffer.buffer;
+      let result = self.render_frame();
+      self.capture_buffer = null();
+      result?;
+      // an out of date swapchain or a lost device leave nothing to read
+      if self.frame_number == frame_number {
+        return Err(Error::Str("No frame was drawn to capture"));
+      }
+      let frame = &self.frames[(frame_number as usize) % FRAME_OVERLAP];
+      unsafe {
+        VK_CHECK!(vkWaitForFences(
+          self.context.device,
+          1,
+          &frame.render_fence.handle,
+          VK_TRUE,
+          1_000_000_000
+        ))?;
+      }
+    }
+
+    let mut pixels = vec![0u8; buffer_size];
+    unsafe {
+      let allocator = self.context.allocator;
+      let mut mapped = null_mut();
+      VK_CHECK!(vmaMapMemory(allocator, buffer.allocation, &mut mapped))?;
+      // the memory may not be coherent, make the GPU writes visible first
+      vmaInvalidateAllocation(allocator, buffer.allocation, 0, VK_WHOLE_SIZE);
+      copy_nonoverlapping(mapped as *const u8, pixels.as_mut_ptr(), buffer_size);
+      vmaUnmapMemory(allocator, buffer.allocation);
+    }
+
+    FrameCapture::from_image_data(width, height, pixels, self.swapchain_format)
+  }
+
+  pub fn run<A: App>(&mut self, app: &mut A) -> Result<(), Error> {
     let mut e: SDL_Event = unsafe { zeroed() };
     let mut b_quit: bool = false;
 
+    // the mouse turns the camera, it's hidden and doesn't stop at the window border
+    unsafe { SDL_SetRelativeMouseMode(SDL_TRUE) };
+    let mut last_frame = Instant::now();
+    // time that hasn't been simulated yet, less than a FIXED_TIMESTEP after the updates
+    let mut accumulator = 0.0;
+
     // main loop
     while !b_quit {
-      // Handle events on queue
+      // Handle events on queue, the input keeps what this frame's events did
+      self.input.begin_frame();
       while unsafe { SDL_PollEvent(&mut e) } != 0 {
         // close the window when user clicks the X button or alt-f4s
         match unsafe { e.type_ } {
           SDL_QUIT => b_quit = true,
-          SDL_KEYDOWN => match unsafe { e.key.keysym.sym as u32 } {
-            SDLK_SPACE => {
-              self.selected_shader += 1;
-              if self.selected_shader > 1 {
-                self.selected_shader = 0;
-              }
-            }
-            SDLK_ESCAPE => b_quit = true,
+          SDL_WINDOWEVENT => match unsafe { e.window.event as u32 } {
+            SDL_WINDOWEVENT_SIZE_CHANGED => self.resize_requested = true,
+            SDL_WINDOWEVENT_MINIMIZED => self.stop_rendering = true,
+            SDL_WINDOWEVENT_RESTORED => self.stop_rendering = false,
             _ => {}
           },
           _ => {}
         }
+        self.input.handle_event(&e);
+      }
+
+      if self.input.is_pressed("quit") {
+        b_quit = true;
+      }
+      if self.input.is_pressed("toggle_fps") {
+        self.show_fps(!self.show_fps);
+      }
+      if self.input.is_pressed("toggle_shader") {
+        self.selected_shader = (self.selected_shader + 1) % (TRIANGLE_MATERIALS.len() + 1);
+      }
+
+      // nothing to draw to while minimized, don't spin the CPU either
+      if self.stop_rendering {
+        unsafe { SDL_Delay(100) };
+        // the time spent minimized doesn't count, nothing moves while it's hidden
+        last_frame = Instant::now();
+        continue;
+      }
+
+      // the stats and the title get the real time the frame took, the simulation
+      // doesn't catch up more than MAX_FRAME_TIME at once
+      let now = Instant::now();
+      let elapsed = now.duration_since(last_frame).as_secs_f32();
+      last_frame = now;
+      self.frame_stats.record(elapsed);
+      let frame_time = f32::min(elapsed, MAX_FRAME_TIME);
+
+      // the camera follows the input every frame, it would lag behind the mouse otherwise
+      self.camera.update(&self.input, frame_time);
+
+      accumulator += frame_time;
+      while accumulator >= FIXED_TIMESTEP {
+        self.update(FIXED_TIMESTEP);
+        app.update(self, FIXED_TIMESTEP);
+        accumulator -= FIXED_TIMESTEP;
+      }
+
+      self.title_frames += 1;
+      self.title_time += elapsed;
+      if self.show_fps && self.title_time >= FPS_TITLE_INTERVAL {
+        let fps = self.title_frames as f32 / self.title_time;
+        self.set_title(&format!(
+          "{} - {:.1} fps ({:.2} ms)",
+          WINDOW_TITLE,
+          fps,
+          1000.0 / fps
+        ));
+        self.title_frames = 0;
+        self.title_time = 0.0;
       }
-      self.draw();
+
+      if self.resize_requested {
+        let result = self.recreate_swapchain();
+        self.recover_device_lost(result)?;
+      }
+      #[cfg(feature = "hot_reload")]
+      match self.reload_shaders() {
+        Ok(reloaded) if reloaded.is_empty() => {}
+        Ok(reloaded) => app.shaders_reloaded(Ok(&reloaded)),
+        // drawing goes on with the old pipelines until the shader is fixed
+        Err(e @ Error::ShaderCompile(..)) => app.shaders_reloaded(Err(&e)),
+        Err(e) => return Err(e),
+      }
+      self.render_frame()?;
+    }
+    Ok(())
+  }
+
+  // Advances the simulation by dt seconds. run calls it FIXED_TIMESTEP apart however
+  // fast the frames are drawn, so anything that moves goes the same speed on every
+  // machine. The other animations only read the time.
+  fn update(&mut self, dt: f32) {
+    self.time += dt;
+
+    // the monkey turns around y a degree every 1/60th of a second, as fast as it did at
+    // a degree per frame
+    let rotation = Mat4::rotate_vec_angle_matrix(0.0, 1.0, 0.0, lina::radians!(self.time * 60.0));
+    for (object, transform) in self.renderables.iter_mut().zip(&self.monkey_transforms) {
+      object.transform = rotation * *transform;
+    }
+  }
+
+  // this frame's input, for the app to read its own actions
+  pub fn input(&self) -> &Input {
+    &self.input
+  }
+
+  // e.g. to rebind an action from a settings menu
+  pub fn input_mut(&mut self) -> &mut Input {
+    &mut self.input
+  }
+
+  // the frame times of run so far
+  pub fn frame_stats(&self) -> &FrameStats {
+    &self.frame_stats
+  }
+
+  // The FPS replaces the window title every FPS_TITLE_INTERVAL while it's shown. It
+  // starts counting again, frames from while it was hidden don't go into the first one.
+  pub fn show_fps(&mut self, show: bool) {
+    self.show_fps = show;
+    self.title_frames = 0;
+    self.title_time = 0.0;
+    if !show {
+      self.set_title(WINDOW_TITLE);
+    }
+  }
+
+  fn set_title(&self, title: &str) {
+    // headless has no window to put it on
+    if self.window.handle.is_null() {
+      return;
+    }
+    if let Ok(title) = CString::new(title) {
+      unsafe { SDL_SetWindowTitle(self.window.handle, title.as_ptr()) };
     }
+  }
+
+  // builds the swapchain and everything that depends on its size again, after
+  // the window was resized or the surface told us the swapchain is out of date.
+  fn recreate_swapchain(&mut self) -> Result<(), Error> {
+    // the old swapchain might still be in use by frames in flight
     unsafe {
-      // we need to wait for rendering to finish before starting cleanup
-      vkQueueWaitIdle(self.graphics_queue);
+      VK_CHECK!(vkDeviceWaitIdle(self.context.device))?;
     }
+
+    self.destroy_swapchain();
+
+    // init_swapchain picks up the new size of the window. The pipelines have a dynamic
+    // viewport and scissor, they keep working as they are.
+    self.init_swapchain()?;
+    self.init_framebuffers()?;
+
+    self.resize_requested = false;
+    Ok(())
+  }
+
+  // drops everything that depends on the swapchain, the image views before the swapchain
+  // they were made from
+  fn destroy_swapchain(&mut self) {
+    self.framebuffers.clear();
+    self.depth_image_view = ImageView::null();
+    self.depth_image = Image::null();
+    self.swapchain_image_views.clear();
+    self.swapchain_images.clear();
+    self.offscreen_image = Image::null();
+    self.swapchain = Swapchain::null();
+  }
+
+  // Rebuilds the pipelines if one of their shaders was edited and returns the .spv
+  // names of those shaders, none if nothing changed. A shader that doesn't compile or a
+  // pipeline that doesn't build is the error, the old pipelines stay in use then.
+  #[cfg(feature = "hot_reload")]
+  fn reload_shaders(&mut self) -> Result<Vec<String>, Error> {
+    let changed = self.shader_watcher.poll();
+    let affected: Vec<String> = self
+      .pipeline_shaders
+      .iter()
+      .filter(|spv| {
+        changed
+          .iter()
+          .any(|source| source.as_path() == vk_shaders::source_path(spv))
+      })
+      .cloned()
+      .collect();
+    if affected.is_empty() {
+      return Ok(affected);
+    }
+
+    let mut compiled = HashMap::new();
+    for spv in &affected {
+      let code = vk_shaders::compile_glsl(vk_shaders::source_path(spv))?;
+      compiled.insert(spv.clone(), code);
+    }
+
+    // The new pipelines are built next to the old ones. If one of them fails, the ones
+    // already built are dropped, nothing has used them, and the old ones are put back.
+    let old_pipelines = std::mem::take(&mut self.pipelines);
+    let old_pipeline_layouts = std::mem::take(&mut self.pipeline_layouts);
+    let old_materials = self.materials.clone();
+    if let Err(e) = self.build_pipelines(&compiled) {
+      self.pipelines = old_pipelines;
+      self.pipeline_layouts = old_pipeline_layouts;
+      self.materials = old_materials;
+      return Err(e);
+    }
+    // the old pipelines might still be in use by frames in flight, they are kept until
+    // those are done
+    self.defer_deletion(old_pipelines);
+    self.defer_deletion(old_pipeline_layouts);
+
+    // the .spv files are written once they built, so they don't drift from the sources
+    for spv in &affected {
+      let bytes: Vec<u8> = compiled[spv]
+        .iter()
+        .flat_map(|word| word.to_le_bytes())
+        .collect();
+      std::fs::write(spv, bytes).map_err(|e| Error::FromIO(e))?;
+    }
+    Ok(affected)
   }
 
   fn init_vulkan(&mut self) -> Result<(), Error> {
     // vkcboot is somewhat different and probably inferior to vk_bootstrap
     // but it works for the purpose of this turorial. It is based on code from
     // https://vulkan-tutorial.com
-    self.instance = vkcboot::InstanceBuilder::new(self.window)
-      .with_version(1, 1)
-      .build()
-      .map_err(|e| Error::FromVkcboot(e))?;
-    self
-      .main_deletion_queue
-      .push(Resource::VkInstance(self.instance));
+    //
+    // The context is filled in as things get created. If something fails it's dropped
+    // right here and takes everything created before with it.
+    let mut context = DeviceContext::null();
+    context.instance = if self.headless {
+      vk_headless::create_instance()?
+    } else {
+      vkcboot::InstanceBuilder::new(self.window.handle)
+        .with_version(1, 1)
+        .build()
+        .map_err(|e| Error::FromVkcboot(e))?
+    };
 
-    // using validation feature to turn validation layers on/off same as vkcboot
+    // using validation feature to turn validation layers on/off same as vkcboot.
+    // Headless leaves them off when the layer isn't installed
     #[cfg(feature = "validation")]
     {
-      self.debug_messenger =
-        vkcboot::DebugMessenger::new(self.instance).map_err(|e| Error::FromVkcboot(e))?;
-      self
-        .main_deletion_queue
-        .push(Resource::VkDebugUtilsMessengerEXT(self.debug_messenger));
+      if !self.headless || vk_headless::validation_available()? {
+        context.debug_messenger =
+          vkcboot::DebugMessenger::new(context.instance).map_err(|e| Error::FromVkcboot(e))?;
+      }
     }
 
-    // vkcboot uses sdl2 to get surface
-    self.surface = vkcboot::Surface::new(self.window, self.instance);
-    self
-      .main_deletion_queue
-      .push(Resource::VkSurfaceKHR(self.surface));
-
-    let device = vkcboot::DeviceBuilder::new(self.instance, self.surface)
-      .with_version(1, 1)
-      .build()
-      .map_err(|e| Error::FromVkcboot(e))?;
-
-    self.chosen_gpu = device.physical_device;
-    self.device = device.device;
-    self
-      .main_deletion_queue
-      .push(Resource::VkDevice(self.device));
-
-    // we have a separate queue handle for presentation even thought they might
-    // refer to the same queue family. On my machine they are the same but I don't
-    // think they have to be on all devices.
-    self.graphics_queue = device.graphics_queue;
-    self.graphics_queue_index = device.graphics_queue_index;
+    if self.headless {
+      // no surface and no present queue, only graphics
+      let device = vk_headless::create_device(context.instance)?;
+      self.chosen_gpu = device.physical_device;
+      context.device = device.device;
+      self.graphics_queue = device.graphics_queue;
+      self.graphics_queue_index = device.graphics_queue_index;
+      #[cfg(feature = "compute")]
+      {
+        self.compute_queue = device.compute_queue;
+        self.compute_queue_index = device.compute_queue_index;
+      }
+    } else {
+      // vkcboot uses sdl2 to get surface
+      context.surface = vkcboot::Surface::new(self.window.handle, context.instance);
+
+      let device = vkcboot::DeviceBuilder::new(context.instance, context.surface)
+        .with_version(1, 1)
+        .build()
+        .map_err(|e| Error::FromVkcboot(e))?;
+
+      self.chosen_gpu = device.physical_device;
+      context.device = device.device;
+
+      // we have a separate queue handle for presentation even thought they might
+      // refer to the same queue family. On my machine they are the same but I don't
+      // think they have to be on all devices.
+      self.graphics_queue = device.graphics_queue;
+      self.graphics_queue_index = device.graphics_queue_index;
+
+      self.present_queue = device.present_queue;
+      self.present_queue_index = device.present_queue_index;
+
+      // vkcboot only creates the graphics and present queues. A compute only family
+      // gets used when it's the present family, otherwise compute shares the graphics
+      // queue. The graphics family of every GPU can do compute too.
+      #[cfg(feature = "compute")]
+      {
+        let families = vk_headless::queue_families(self.chosen_gpu);
+        match vk_compute::compute_only_family(&families) {
+          Some(index) if index == device.present_queue_index => {
+            self.compute_queue = device.present_queue;
+            self.compute_queue_index = index;
+          }
+          _ => {
+            self.compute_queue = device.graphics_queue;
+            self.compute_queue_index = device.graphics_queue_index;
+          }
+        }
+      }
+    }
 
-    self.present_queue = device.present_queue;
-    self.present_queue_index = device.present_queue_index;
+    // the uniform buffer alignment is needed to place the per-frame scene data
+    unsafe {
+      vkGetPhysicalDeviceProperties(self.chosen_gpu, &mut self.gpu_properties);
+    }
 
     let vulkan_functions = VmaVulkanFunctions {
       vkGetPhysicalDeviceProperties: unsafe { vkGetPhysicalDeviceProperties },
@@ -549,7 +1354,7 @@ impl VulkanEngine {
     let allocator_info = VmaAllocatorCreateInfo {
       flags: 0,
       physicalDevice: self.chosen_gpu,
-      device: self.device,
+      device: context.device,
       preferredLargeHeapBlockSize: 0,
       pAllocationCallbacks: null(),
       pDeviceMemoryCallbacks: null(),
@@ -557,48 +1362,120 @@ impl VulkanEngine {
       pHeapSizeLimit: null(),
       pVulkanFunctions: &vulkan_functions,
       pRecordSettings: null(),
-      instance: self.instance,
+      instance: context.instance,
       vulkanApiVersion: vkcapi::VK_MAKE_VERSION!(1, 1, 0),
     };
     unsafe {
-      vmaCreateAllocator(&allocator_info, &mut self.allocator);
+      VK_CHECK!(vmaCreateAllocator(&allocator_info, &mut context.allocator))?;
     }
-    self
-      .main_deletion_queue
-      .push(Resource::VmaAllocator(self.allocator));
+
+    self.context = Rc::new(context);
     Ok(())
   }
 
   fn init_swapchain(&mut self) -> Result<(), Error> {
+    // capture_frame copies out of the swapchain images, if the surface allows it
+    let mut capabilities: VkSurfaceCapabilitiesKHR = unsafe { zeroed() };
+    unsafe {
+      VK_CHECK!(vkGetPhysicalDeviceSurfaceCapabilitiesKHR(
+        self.chosen_gpu,
+        self.context.surface,
+        &mut capabilities
+      ))?;
+    }
+    self.swapchain_capturable =
+      capabilities.supportedUsageFlags & VK_IMAGE_USAGE_TRANSFER_SRC_BIT != 0;
+    let mut image_usage = VK_IMAGE_USAGE_COLOR_ATTACHMENT_BIT;
+    if self.swapchain_capturable {
+      image_usage |= VK_IMAGE_USAGE_TRANSFER_SRC_BIT;
+    }
+
     // VK_PRESENT_MODE_FIFO is vkcboot default when preferred_present_mode isn't called
     let swapchain = vkcboot::SwapchainBuilder::new(
-      self.window,
-      self.surface,
+      self.window.handle,
+      self.context.surface,
       self.chosen_gpu,
-      self.device,
+      self.context.device,
       self.graphics_queue_index,
       self.present_queue_index,
     )
+    .image_usage_flags(image_usage)
     .build()
     .map_err(|e| Error::FromVkcboot(e))?;
 
     // store the swapchain and it's related stuffs
-    self.swapchain = swapchain.swapchain;
+    self.swapchain = Swapchain::new(&self.context, swapchain.swapchain);
     self.swapchain_images = swapchain.images;
     self.window_extent = swapchain.extent;
     self.swapchain_format = swapchain.format;
-    self.swapchain_image_views = swapchain.image_views;
+    self.swapchain_image_views = swapchain
+      .image_views
+      .into_iter()
+      .map(|image_view| ImageView::new(&self.context, image_view))
+      .collect();
 
-    self
-      .main_deletion_queue
-      .push(Resource::VkSwapchainKHR(self.swapchain));
+    self.init_depth_image()
+  }
 
-    for i in 0..self.swapchain_image_views.len() {
-      self
-        .main_deletion_queue
-        .push(Resource::VkImageView(self.swapchain_image_views[i]));
+  // headless stand-in for the swapchain, a single color image we render into and
+  // copy out of
+  fn init_offscreen(&mut self) -> Result<(), Error> {
+    self.swapchain_format = VK_FORMAT_R8G8B8A8_UNORM;
+
+    let image_extent = VkExtent3D {
+      width: self.window_extent.width,
+      height: self.window_extent.height,
+      depth: 1,
+    };
+    let img_create_info = vkinit::image_create_info(
+      self.swapchain_format,
+      VK_IMAGE_USAGE_COLOR_ATTACHMENT_BIT | VK_IMAGE_USAGE_TRANSFER_SRC_BIT,
+      image_extent,
+    );
+    let img_alloc_info = VmaAllocationCreateInfo {
+      flags: 0,
+      usage: VMA_MEMORY_USAGE_GPU_ONLY,
+      requiredFlags: 0,
+      preferredFlags: 0,
+      memoryTypeBits: 0,
+      pool: null(),
+      pUserData: null_mut(),
+      priority: 0.0,
+    };
+    let mut image = null();
+    let mut allocation = null();
+    unsafe {
+      VK_CHECK!(vmaCreateImage(
+        self.context.allocator,
+        &img_create_info,
+        &img_alloc_info,
+        &mut image,
+        &mut allocation,
+        null_mut()
+      ))?;
     }
+    self.offscreen_image = Image::new(&self.context, image, allocation);
 
+    let view_create_info =
+      vkinit::imageview_create_info(self.swapchain_format, image, VK_IMAGE_ASPECT_COLOR_BIT);
+    let mut image_view = null();
+    unsafe {
+      VK_CHECK!(vkCreateImageView(
+        self.context.device,
+        &view_create_info,
+        null(),
+        &mut image_view
+      ))?;
+    }
+
+    // the rest of the engine sees a swapchain with one image
+    self.swapchain_images = vec![image];
+    self.swapchain_image_views = vec![ImageView::new(&self.context, image_view)];
+
+    self.init_depth_image()
+  }
+
+  fn init_depth_image(&mut self) -> Result<(), Error> {
     // depth image size will match the window
     let depth_image_extent = VkExtent3D {
       width: self.window_extent.width,
@@ -628,38 +1505,33 @@ impl VulkanEngine {
       priority: 0.0,
     };
     // allocate and create the image
+    let mut image = null();
+    let mut allocation = null();
     unsafe {
-      vmaCreateImage(
-        self.allocator,
+      VK_CHECK!(vmaCreateImage(
+        self.context.allocator,
         &d_img_create_info,
         &d_img_alloc_info,
-        &mut self.depth_image.image,
-        &mut self.depth_image.allocation,
-        null_mut(),
-      );
+        &mut image,
+        &mut allocation,
+        null_mut()
+      ))?;
     }
+    self.depth_image = Image::new(&self.context, image, allocation);
+
     // build an image-view for the depth image to use for rendering
-    let d_view_create_info = vkinit::imageview_create_info(
-      self.depth_format,
-      self.depth_image.image,
-      VK_IMAGE_ASPECT_DEPTH_BIT,
-    );
+    let d_view_create_info =
+      vkinit::imageview_create_info(self.depth_format, image, VK_IMAGE_ASPECT_DEPTH_BIT);
+    let mut image_view = null();
     unsafe {
       VK_CHECK!(vkCreateImageView(
-        self.device,
+        self.context.device,
         &d_view_create_info,
         null(),
-        &mut self.depth_image_view
-      ));
+        &mut image_view
+      ))?;
     }
-
-    // add to deletion queue
-    self
-      .main_deletion_queue
-      .push(Resource::VmaAllocatedImage(self.depth_image));
-    self
-      .main_deletion_queue
-      .push(Resource::VkImageView(self.depth_image_view));
+    self.depth_image_view = ImageView::new(&self.context, image_view);
 
     Ok(())
   }
@@ -672,30 +1544,56 @@ impl VulkanEngine {
       // we also want the pool to allow for resetting of individual command buffers
       Some(VK_COMMAND_POOL_CREATE_RESET_COMMAND_BUFFER_BIT),
     );
+    for frame in self.frames.iter_mut() {
+      let mut command_pool = null();
+      unsafe {
+        VK_CHECK!(vkCreateCommandPool(
+          self.context.device,
+          &command_pool_info,
+          null(),
+          &mut command_pool
+        ))?;
+      }
+      frame.command_pool = CommandPool::new(&self.context, command_pool);
+
+      // allocate the default command buffer that we will use for rendering
+      let cmd_alloc_info = vkinit::command_buffer_allocate_info(
+        command_pool, // commands will be made from our command pool
+        1,            // we will allocate 1 command buffer
+        None,         // primary is the default level
+      );
+      unsafe {
+        VK_CHECK!(vkAllocateCommandBuffers(
+          self.context.device,
+          &cmd_alloc_info,
+          &mut frame.main_command_buffer
+        ))?;
+      }
+    }
+
+    // the upload context gets its own pool so resetting it doesn't touch the
+    // command buffers used for drawing
+    let upload_command_pool_info =
+      vkinit::command_pool_create_info(self.graphics_queue_index, None);
+    let mut upload_command_pool = null();
     unsafe {
       VK_CHECK!(vkCreateCommandPool(
-        self.device,
-        &command_pool_info,
+        self.context.device,
+        &upload_command_pool_info,
         null(),
-        &mut self.command_pool
-      ));
+        &mut upload_command_pool
+      ))?;
     }
-    self
-      .main_deletion_queue
-      .push(Resource::VkCommandPool(self.command_pool));
-
-    // allocate the default command buffer that we will use for rendering
-    let cmd_alloc_info = vkinit::command_buffer_allocate_info(
-      self.command_pool, // commands will be made from our command pool
-      1,                 // we will allocate 1 command buffer
-      None,              // primary is the default level
-    );
+    self.upload_context.command_pool = CommandPool::new(&self.context, upload_command_pool);
+
+    // allocate the command buffer for the immediate submits
+    let upload_cmd_alloc_info = vkinit::command_buffer_allocate_info(upload_command_pool, 1, None);
     unsafe {
       VK_CHECK!(vkAllocateCommandBuffers(
-        self.device,
-        &cmd_alloc_info,
-        &mut self.main_command_buffer
-      ));
+        self.context.device,
+        &upload_cmd_alloc_info,
+        &mut self.upload_context.command_buffer
+      ))?;
     }
     Ok(())
   }
@@ -716,8 +1614,13 @@ impl VulkanEngine {
       stencilStoreOp: VK_ATTACHMENT_STORE_OP_DONT_CARE,
       // we don't know or care about the starting layout of the attachment
       initialLayout: VK_IMAGE_LAYOUT_UNDEFINED,
-      // after the renderpass ends, the image has to be on a layout ready for display
-      finalLayout: VK_IMAGE_LAYOUT_PRESENT_SRC_KHR,
+      // after the renderpass ends, the image has to be on a layout ready for display.
+      // Headless there is no display, the image is copied out instead
+      finalLayout: if self.headless {
+        VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL
+      } else {
+        VK_IMAGE_LAYOUT_PRESENT_SRC_KHR
+      },
     };
 
     let color_attachment_ref = VkAttachmentReference {
@@ -756,6 +1659,32 @@ impl VulkanEngine {
       pPreserveAttachments: null(),
     };
 
+    // Both frames in flight render into the same depth image, and headless into the same
+    // color image too. The writes of a frame wait for the ones of the frame before it.
+    let dependencies = [
+      VkSubpassDependency {
+        srcSubpass: VK_SUBPASS_EXTERNAL,
+        dstSubpass: 0,
+        srcStageMask: VK_PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+        dstStageMask: VK_PIPELINE_STAGE_COLOR_ATTACHMENT_OUTPUT_BIT,
+        srcAccessMask: VK_ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+        dstAccessMask: VK_ACCESS_COLOR_ATTACHMENT_READ_BIT | VK_ACCESS_COLOR_ATTACHMENT_WRITE_BIT,
+        dependencyFlags: 0,
+      },
+      VkSubpassDependency {
+        srcSubpass: VK_SUBPASS_EXTERNAL,
+        dstSubpass: 0,
+        srcStageMask: VK_PIPELINE_STAGE_EARLY_FRAGMENT_TESTS_BIT
+          | VK_PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT,
+        dstStageMask: VK_PIPELINE_STAGE_EARLY_FRAGMENT_TESTS_BIT
+          | VK_PIPELINE_STAGE_LATE_FRAGMENT_TESTS_BIT,
+        srcAccessMask: VK_ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
+        dstAccessMask: VK_ACCESS_DEPTH_STENCIL_ATTACHMENT_READ_BIT
+          | VK_ACCESS_DEPTH_STENCIL_ATTACHMENT_WRITE_BIT,
+        dependencyFlags: 0,
+      },
+    ];
+
     let render_pass_info = VkRenderPassCreateInfo {
       sType: VK_STRUCTURE_TYPE_RENDER_PASS_CREATE_INFO,
       pNext: null(),
@@ -766,21 +1695,20 @@ impl VulkanEngine {
       // conntect the subpass to the info
       subpassCount: 1,
       pSubpasses: &subpass,
-      dependencyCount: 0,
-      pDependencies: null(),
+      dependencyCount: dependencies.len() as u32,
+      pDependencies: dependencies.as_ptr(),
     };
 
+    let mut render_pass = null();
     unsafe {
       VK_CHECK!(vkCreateRenderPass(
-        self.device,
+        self.context.device,
         &render_pass_info,
         null(),
-        &mut self.render_pass
-      ));
+        &mut render_pass
+      ))?;
     }
-    self
-      .main_deletion_queue
-      .push(Resource::VkRenderPass(self.render_pass));
+    self.render_pass = RenderPass::new(&self.context, render_pass);
     Ok(())
   }
 
@@ -791,7 +1719,7 @@ impl VulkanEngine {
       sType: VK_STRUCTURE_TYPE_FRAMEBUFFER_CREATE_INFO,
       pNext: null(),
       flags: 0,
-      renderPass: self.render_pass,
+      renderPass: self.render_pass.handle,
       attachmentCount: 2,
       pAttachments: null(),
       width: self.window_extent.width,
@@ -799,25 +1727,26 @@ impl VulkanEngine {
       layers: 1,
     };
 
-    // grab how many images we have in the swapchain
-    self
-      .framebuffers
-      .resize(self.swapchain_images.len(), null());
-
     // create framebuffers for each of the swapchain image views
+    self.framebuffers.clear();
     for i in 0..self.swapchain_image_views.len() {
-      fb_info.pAttachments = [self.swapchain_image_views[i], self.depth_image_view].as_ptr();
+      let attachments = [
+        self.swapchain_image_views[i].handle,
+        self.depth_image_view.handle,
+      ];
+      fb_info.pAttachments = attachments.as_ptr();
+      let mut framebuffer = null();
       unsafe {
         VK_CHECK!(vkCreateFramebuffer(
-          self.device,
+          self.context.device,
           &fb_info,
           null(),
-          &mut self.framebuffers[i]
-        ));
-        self
-          .main_deletion_queue
-          .push(Resource::VkFramebuffer(self.framebuffers[i]));
+          &mut framebuffer
+        ))?;
       }
+      self
+        .framebuffers
+        .push(Framebuffer::new(&self.context, framebuffer));
     }
     Ok(())
   }
@@ -831,17 +1760,6 @@ impl VulkanEngine {
       // so we can wait on it before using it on a GPU command (for the first frame)
       flags: VK_FENCE_CREATE_SIGNALED_BIT,
     };
-    unsafe {
-      VK_CHECK!(vkCreateFence(
-        self.device,
-        &fence_create_info,
-        null(),
-        &mut self.render_fence
-      ));
-    }
-    self
-      .main_deletion_queue
-      .push(Resource::VkFence(self.render_fence));
 
     // for the semaphores we don't need any flags
     let semaphore_create_info = VkSemaphoreCreateInfo {
@@ -849,206 +1767,328 @@ impl VulkanEngine {
       pNext: null(),
       flags: 0,
     };
+
+    for frame in self.frames.iter_mut() {
+      let mut render_fence = null();
+      let mut render_semaphore = null();
+      let mut present_semaphore = null();
+      unsafe {
+        VK_CHECK!(vkCreateFence(
+          self.context.device,
+          &fence_create_info,
+          null(),
+          &mut render_fence
+        ))?;
+        frame.render_fence = Fence::new(&self.context, render_fence);
+
+        VK_CHECK!(vkCreateSemaphore(
+          self.context.device,
+          &semaphore_create_info,
+          null(),
+          &mut render_semaphore
+        ))?;
+        frame.render_semaphore = Semaphore::new(&self.context, render_semaphore);
+        VK_CHECK!(vkCreateSemaphore(
+          self.context.device,
+          &semaphore_create_info,
+          null(),
+          &mut present_semaphore
+        ))?;
+        frame.present_semaphore = Semaphore::new(&self.context, present_semaphore);
+      }
+    }
+
+    // the upload fence starts unsignaled, it is only waited on after a submit
+    let upload_fence_create_info = VkFenceCreateInfo {
+      sType: VK_STRUCTURE_TYPE_FENCE_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+    };
+    let mut upload_fence = null();
     unsafe {
-      VK_CHECK!(vkCreateSemaphore(
-        self.device,
-        &semaphore_create_info,
-        null(),
-        &mut self.render_semaphore
-      ));
-      self
-        .main_deletion_queue
-        .push(Resource::VkSemaphore(self.render_semaphore));
-      VK_CHECK!(vkCreateSemaphore(
-        self.device,
-        &semaphore_create_info,
+      VK_CHECK!(vkCreateFence(
+        self.context.device,
+        &upload_fence_create_info,
         null(),
-        &mut self.present_semaphore
-      ));
-      self
-        .main_deletion_queue
-        .push(Resource::VkSemaphore(self.present_semaphore));
+        &mut upload_fence
+      ))?;
     }
+    self.upload_context.upload_fence = Fence::new(&self.context, upload_fence);
     Ok(())
   }
 
-  fn create_shader_module(&self, path: &str) -> Result<(bool, VkShaderModule), Error> {
-    // Rust has nice things to load file
-    let source = std::fs::read(path).map_err(|e| Error::FromIO(e))?;
+  fn init_descriptors(&mut self) -> Result<(), Error> {
+    // they hold on to the device, everything else they create on demand
+    self.descriptor_allocator = DescriptorAllocator::new(&self.context);
+    self.texture_set_allocator = DescriptorAllocator::new(&self.context);
+    self.descriptor_layout_cache = DescriptorLayoutCache::new(&self.context);
+
+    // one buffer holds the scene data of every frame, each frame reads its own
+    // part of it through a dynamic offset.
+    let scene_param_buffer_size =
+      FRAME_OVERLAP * self.pad_uniform_buffer_size(size_of::<GpuSceneData>());
+    self.scene_parameter_buffer = self.create_buffer(
+      scene_param_buffer_size,
+      VK_BUFFER_USAGE_UNIFORM_BUFFER_BIT,
+      VMA_MEMORY_USAGE_CPU_TO_GPU,
+    )?;
 
-    let create_info = VkShaderModuleCreateInfo {
-      sType: VK_STRUCTURE_TYPE_SHADER_MODULE_CREATE_INFO,
-      pNext: null(),
-      flags: 0,
-      codeSize: source.len(),
-      pCode: source.as_ptr() as *const u32,
-    };
+    for i in 0..FRAME_OVERLAP {
+      self.frames[i].camera_buffer = self.create_buffer(
+        size_of::<GpuCameraData>(),
+        VK_BUFFER_USAGE_UNIFORM_BUFFER_BIT,
+        VMA_MEMORY_USAGE_CPU_TO_GPU,
+      )?;
+
+      let camera_info = VkDescriptorBufferInfo {
+        buffer: self.frames[i].camera_buffer.buffer,
+        offset: 0,
+        range: size_of::<GpuCameraData>() as u64,
+      };
+      // the offset is given when binding, the range covers a single frame's data
+      let scene_info = VkDescriptorBufferInfo {
+        buffer: self.scene_parameter_buffer.buffer,
+        offset: 0,
+        range: size_of::<GpuSceneData>() as u64,
+      };
 
-    // check that the creation goes well
-    let mut shader_module = null();
-    if unsafe { vkCreateShaderModule(self.device, &create_info, null(), &mut shader_module) }
-      != VK_SUCCESS
-    {
-      Ok((false, shader_module))
-    } else {
-      Ok((true, shader_module))
+      let (set, layout) = DescriptorBuilder::new()
+        .bind_buffer(
+          0,
+          camera_info,
+          VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER,
+          VK_SHADER_STAGE_VERTEX_BIT,
+        )
+        .bind_buffer(
+          1,
+          scene_info,
+          VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER_DYNAMIC,
+          VK_SHADER_STAGE_VERTEX_BIT | VK_SHADER_STAGE_FRAGMENT_BIT,
+        )
+        .build(
+          &mut self.descriptor_layout_cache,
+          &mut self.descriptor_allocator,
+        )?;
+      self.frames[i].global_descriptor = set;
+      // the layout comes from the cache, so it's the same one for every frame
+      self.global_set_layout = layout;
     }
+
+    // materials with a texture have it at binding 0 of set 1. There is no set to
+    // write yet, the layout is needed for the pipeline layout.
+    let texture_binding = VkDescriptorSetLayoutBinding {
+      binding: 0,
+      descriptorType: VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER,
+      descriptorCount: 1,
+      stageFlags: VK_SHADER_STAGE_FRAGMENT_BIT,
+      pImmutableSamplers: null(),
+    };
+    self.single_texture_set_layout = self
+      .descriptor_layout_cache
+      .create_descriptor_layout(&[texture_binding])?;
+    Ok(())
   }
 
-  fn init_pipelines(&mut self) -> Result<(), Error> {
-    // a little different than the tutorial, we will be silent if all is well and return
-    // an error &str with the offending file name if there was a problem.
-    let (ok, triangle_vert_shader) =
-      self.create_shader_module("shaders/colored_triangle.vert.spv")?;
-    if !ok {
-      return Err(Error::Str("Error when building colored_triangle.vert.spv"));
-    }
-    let (ok, triangle_frag_shader) =
-      self.create_shader_module("shaders/colored_triangle.frag.spv")?;
-    if !ok {
-      return Err(Error::Str("Error when building colored_triangle.frag.spv"));
+  // rounds a size up to the alignment the GPU wants for uniform buffer offsets
+  fn pad_uniform_buffer_size(&self, original_size: usize) -> usize {
+    let min_ubo_alignment = self.gpu_properties.limits.minUniformBufferOffsetAlignment as usize;
+    if min_ubo_alignment > 0 {
+      (original_size + min_ubo_alignment - 1) & !(min_ubo_alignment - 1)
+    } else {
+      original_size
     }
+  }
 
-    let (ok, red_triangle_vert_shader) = self.create_shader_module("shaders/triangle.vert.spv")?;
-    if !ok {
-      return Err(Error::Str("Error when building triangle.vert.spv"));
-    }
-    let (ok, red_triangle_frag_shader) = self.create_shader_module("shaders/triangle.frag.spv")?;
-    if !ok {
-      return Err(Error::Str("Error when building triangle.frag.spv"));
-    }
+  // compiled has the SPIR-V of shaders that were just compiled, the others are loaded
+  fn create_shader_module(
+    &mut self,
+    path: &str,
+    compiled: &HashMap<String, Vec<u32>>,
+  ) -> Result<Shader, Error> {
+    // compile errors come back with the file and line of the GLSL
+    let code = match compiled.get(path) {
+      Some(code) => code.clone(),
+      None => vk_shaders::load_shader(path)?,
+    };
+    let reflection = ShaderReflection::reflect(path, &code)?;
 
-    let (ok, mesh_vert_shader) = self.create_shader_module("shaders/tri_mesh.vert.spv")?;
-    if !ok {
-      return Err(Error::Str("Error when building tri_mesh.vert.spv"));
-    }
+    let create_info = VkShaderModuleCreateInfo {
+      sType: VK_STRUCTURE_TYPE_SHADER_MODULE_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      codeSize: code.len() * size_of::<u32>(),
+      pCode: code.as_ptr(),
+    };
 
-    // build the pipeline layout that controls the inputs/outputs of the shader
-    // we are not using descriptor sets or other system yet so no need to use
-    // anything other than the empty default.
-    let pipeline_layout_info = vkinit::pipeline_layout_create_info();
+    let mut shader_module = null();
     unsafe {
-      VK_CHECK!(vkCreatePipelineLayout(
-        self.device,
-        &pipeline_layout_info,
+      VK_CHECK!(vkCreateShaderModule(
+        self.context.device,
+        &create_info,
         null(),
-        &mut self.triangle_pipeline_layout
-      ));
+        &mut shader_module
+      ))?;
     }
-    self
-      .main_deletion_queue
-      .push(Resource::VkPipelineLayout(self.triangle_pipeline_layout));
-
-    // we start from just the default empy pipeline layout info
-    let mut mesh_pipeline_layout_info = vkinit::pipeline_layout_create_info();
-    // setup push constants
-    let push_constant = VkPushConstantRange {
-      // this push constant range is accessible only in the vertex shader
-      stageFlags: VK_SHADER_STAGE_VERTEX_BIT,
-      // this push constant range starts at the beginning
-      offset: 0,
-      // this push constant takes up the size of a MeshPushConstants struct
-      size: size_of::<MeshPushConstants>() as u32,
-    };
-    mesh_pipeline_layout_info.pushConstantRangeCount = 1;
-    mesh_pipeline_layout_info.pPushConstantRanges = &push_constant;
-    unsafe {
-      VK_CHECK!(vkCreatePipelineLayout(
-        self.device,
-        &mesh_pipeline_layout_info,
-        null(),
-        &mut self.mesh_pipeline_layout
-      ));
+
+    // remembered so a change to the shader rebuilds the pipelines
+    if !self.pipeline_shaders.iter().any(|shader| shader == path) {
+      self.pipeline_shaders.push(path.to_string());
     }
+    Ok(Shader {
+      module: ShaderModule::new(&self.context, shader_module),
+      reflection,
+    })
+  }
+
+  // builds the pipelines and their layouts, which are made from the shaders. Called
+  // again when the shaders change, with the SPIR-V of the ones that were compiled.
+  fn build_pipelines(&mut self, compiled: &HashMap<String, Vec<u32>>) -> Result<(), Error> {
+    // a little different than the tutorial, we will be silent if all is well and return
+    // an error with the offending file name if there was a problem.
+    let mesh_vert_shader = self.create_shader_module("shaders/tri_mesh.vert.spv", compiled)?;
+    let lit_frag_shader = self.create_shader_module("shaders/default_lit.frag.spv", compiled)?;
+    let textured_frag_shader =
+      self.create_shader_module("shaders/textured_lit.frag.spv", compiled)?;
+    let triangle_vert_shader =
+      self.create_shader_module("shaders/colored_triangle.vert.spv", compiled)?;
+    let triangle_frag_shader =
+      self.create_shader_module("shaders/colored_triangle.frag.spv", compiled)?;
+    let red_triangle_vert_shader =
+      self.create_shader_module("shaders/triangle.vert.spv", compiled)?;
+    let red_triangle_frag_shader =
+      self.create_shader_module("shaders/triangle.frag.spv", compiled)?;
+
+    // the modules are dropped at the end, whether the pipelines got built or not
+    self.build_mesh_pipelines(&mesh_vert_shader, &lit_frag_shader, &textured_frag_shader)?;
+    self.build_triangle_pipeline(
+      &triangle_vert_shader,
+      &triangle_frag_shader,
+      TRIANGLE_MATERIALS[0],
+    )?;
+    self.build_triangle_pipeline(
+      &red_triangle_vert_shader,
+      &red_triangle_frag_shader,
+      TRIANGLE_MATERIALS[1],
+    )
+  }
+
+  // The tutorial's first pipelines, a hardcoded triangle in the middle of the screen.
+  // The shaders use no descriptors or push constants, so the layout is empty.
+  fn build_triangle_pipeline(
+    &mut self,
+    vert_shader: &Shader,
+    frag_shader: &Shader,
+    name: &str,
+  ) -> Result<(), Error> {
+    let pipeline_layout = PipelineLayoutBuilder::new()
+      .shader(&vert_shader.reflection)
+      .shader(&frag_shader.reflection)
+      .build(self.context.device, &mut self.descriptor_layout_cache)?;
     self
-      .main_deletion_queue
-      .push(Resource::VkPipelineLayout(self.mesh_pipeline_layout));
+      .pipeline_layouts
+      .push(PipelineLayout::new(&self.context, pipeline_layout));
 
-    self.triangle_pipeline = PipelineBuilder::new()
+    let pipeline = PipelineBuilder::new()
       // build the stage-create-info for both vertex and fragment stages.
       // This lets the pipeline know the shader modules per stage
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_VERTEX_BIT,
-        triangle_vert_shader,
+        vert_shader.module.handle,
       ))
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_FRAGMENT_BIT,
-        triangle_frag_shader,
+        frag_shader.module.handle,
       ))
-      // vertex input controls how to read vertices from vertes buffers. We aren't using it yet
+      // no vertex buffers, the positions come from the vertex index
       .vertex_input_info(vkinit::vertex_input_state_create_info(
         None, None, None, None,
       ))
-      // input assembly is the configuration for drawing triangle lists, strips, or individual
-      // points. We are just going to draw triangle list.
       .input_assembly(vkinit::input_assembly_state_create_info(
         VK_PRIMITIVE_TOPOLOGY_TRIANGLE_LIST,
       ))
-      // build viewport and scissor from the swapchain extents
-      .viewport(vkinit::viewport(
-        0.0,
-        0.0,
-        self.window_extent.width as f32,
-        self.window_extent.height as f32,
-        0.0,
-        1.0,
-      ))
-      .scissor(vkinit::rect_2d(
-        0,
-        0,
-        self.window_extent.width,
-        self.window_extent.height,
-      ))
-      // configure the rasterizer to draw filled triangles
+      .dynamic_viewport_scissor()
       .rasterizer(vkinit::rasterization_state_create_info(
         VK_POLYGON_MODE_FILL,
       ))
-      // we don't use multisampling, so just run the default one
       .multisampling(vkinit::multisampling_state_create_info())
       .depth_stencil(vkinit::depth_stencil_create_info(
         true,
         true,
         VK_COMPARE_OP_LESS_OR_EQUAL,
       ))
-      // a single blend attachment with no blending and writing to RGBA
       .color_blend_attachment(vkinit::color_blend_attachment_state())
-      // use the triangle layout we created
-      .pipeline_layout(self.triangle_pipeline_layout)
-      // finally build the pipeline
-      .build(self.device, self.render_pass)?;
+      .pipeline_layout(pipeline_layout)
+      .pipeline_cache(self.pipeline_cache.handle)
+      .build(self.context.device, self.render_pass.handle)?;
+    self.pipelines.push(Pipeline::new(&self.context, pipeline));
+    // the triangles are drawn without push constants
+    self.create_material(pipeline, pipeline_layout, 0, name);
+    Ok(())
+  }
+
+  fn build_mesh_pipelines(
+    &mut self,
+    mesh_vert_shader: &Shader,
+    lit_frag_shader: &Shader,
+    textured_frag_shader: &Shader,
+  ) -> Result<(), Error> {
+    let vertex_description = Vertex::get_vertex_description();
+    // a shader reading an attribute Vertex doesn't have is an error, not garbage on screen
+    mesh_vert_shader
+      .reflection
+      .check_vertex_input(&vertex_description)?;
+
+    // Set 0 is allocated with the global layout, so the shaders are checked against it
+    // instead of getting a layout of their own. The push constant range comes from
+    // the shaders.
+    let mut mesh_layout_builder = PipelineLayoutBuilder::new();
+    mesh_layout_builder
+      .shader(&mesh_vert_shader.reflection)
+      .shader(&lit_frag_shader.reflection)
+      .set_layout(0, self.global_set_layout);
+    let mesh_pipeline_layout =
+      mesh_layout_builder.build(self.context.device, &mut self.descriptor_layout_cache)?;
+    let mesh_push_constant_stages = mesh_layout_builder.push_constant_stages();
+    self
+      .pipeline_layouts
+      .push(PipelineLayout::new(&self.context, mesh_pipeline_layout));
+
+    // the textured layout is the mesh layout plus the texture at set 1
+    let mut textured_layout_builder = PipelineLayoutBuilder::new();
+    textured_layout_builder
+      .shader(&mesh_vert_shader.reflection)
+      .shader(&textured_frag_shader.reflection)
+      .set_layout(0, self.global_set_layout)
+      .set_layout(1, self.single_texture_set_layout);
+    let textured_pipeline_layout =
+      textured_layout_builder.build(self.context.device, &mut self.descriptor_layout_cache)?;
+    let textured_push_constant_stages = textured_layout_builder.push_constant_stages();
     self
-      .main_deletion_queue
-      .push(Resource::VkPipeline(self.triangle_pipeline));
+      .pipeline_layouts
+      .push(PipelineLayout::new(&self.context, textured_pipeline_layout));
 
-    self.red_triangle_pipeline = PipelineBuilder::new()
+    // build the mesh pipeline
+    let mesh_pipeline = PipelineBuilder::new()
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_VERTEX_BIT,
-        red_triangle_vert_shader,
+        mesh_vert_shader.module.handle,
       ))
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_FRAGMENT_BIT,
-        red_triangle_frag_shader,
+        lit_frag_shader.module.handle,
       ))
+      // connect the pipeline builder vertex input info to the one we get from Vertex
       .vertex_input_info(vkinit::vertex_input_state_create_info(
-        None, None, None, None,
+        Some(vertex_description.bindings.len() as u32),
+        Some(vertex_description.bindings.as_ptr()),
+        Some(vertex_description.attributes.len() as u32),
+        Some(vertex_description.attributes.as_ptr()),
       ))
       .input_assembly(vkinit::input_assembly_state_create_info(
         VK_PRIMITIVE_TOPOLOGY_TRIANGLE_LIST,
       ))
-      .viewport(vkinit::viewport(
-        0.0,
-        0.0,
-        self.window_extent.width as f32,
-        self.window_extent.height as f32,
-        0.0,
-        1.0,
-      ))
-      .scissor(vkinit::rect_2d(
-        0,
-        0,
-        self.window_extent.width,
-        self.window_extent.height,
-      ))
+      // set in draw, the pipeline doesn't change when the window is resized
+      .dynamic_viewport_scissor()
       .rasterizer(vkinit::rasterization_state_create_info(
         VK_POLYGON_MODE_FILL,
       ))
@@ -1059,23 +2099,29 @@ impl VulkanEngine {
         VK_COMPARE_OP_LESS_OR_EQUAL,
       ))
       .color_blend_attachment(vkinit::color_blend_attachment_state())
-      .pipeline_layout(self.triangle_pipeline_layout)
-      .build(self.device, self.render_pass)?;
+      .pipeline_layout(mesh_pipeline_layout)
+      .pipeline_cache(self.pipeline_cache.handle)
+      .build(self.context.device, self.render_pass.handle)?;
     self
-      .main_deletion_queue
-      .push(Resource::VkPipeline(self.red_triangle_pipeline));
-
-    // build the mesh pipeline
-    let vertex_description = Vertex::get_vertex_description();
+      .pipelines
+      .push(Pipeline::new(&self.context, mesh_pipeline));
+    // registering again after a rebuild replaces the material with the old pipeline
+    self.create_material(
+      mesh_pipeline,
+      mesh_pipeline_layout,
+      mesh_push_constant_stages,
+      "defaultmesh",
+    );
 
-    self.mesh_pipeline = PipelineBuilder::new()
+    // same as the mesh pipeline, but the fragment shader samples a texture
+    let textured_pipeline = PipelineBuilder::new()
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_VERTEX_BIT,
-        mesh_vert_shader,
+        mesh_vert_shader.module.handle,
       ))
       .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
         VK_SHADER_STAGE_FRAGMENT_BIT,
-        triangle_frag_shader,
+        textured_frag_shader.module.handle,
       ))
       // connect the pipeline builder vertex input info to the one we get from Vertex
       .vertex_input_info(vkinit::vertex_input_state_create_info(
@@ -1087,20 +2133,7 @@ impl VulkanEngine {
       .input_assembly(vkinit::input_assembly_state_create_info(
         VK_PRIMITIVE_TOPOLOGY_TRIANGLE_LIST,
       ))
-      .viewport(vkinit::viewport(
-        0.0,
-        0.0,
-        self.window_extent.width as f32,
-        self.window_extent.height as f32,
-        0.0,
-        1.0,
-      ))
-      .scissor(vkinit::rect_2d(
-        0,
-        0,
-        self.window_extent.width,
-        self.window_extent.height,
-      ))
+      .dynamic_viewport_scissor()
       .rasterizer(vkinit::rasterization_state_create_info(
         VK_POLYGON_MODE_FILL,
       ))
@@ -1111,110 +2144,502 @@ impl VulkanEngine {
         VK_COMPARE_OP_LESS_OR_EQUAL,
       ))
       .color_blend_attachment(vkinit::color_blend_attachment_state())
-      .pipeline_layout(self.mesh_pipeline_layout)
-      .build(self.device, self.render_pass)?;
+      .pipeline_layout(textured_pipeline_layout)
+      .pipeline_cache(self.pipeline_cache.handle)
+      .build(self.context.device, self.render_pass.handle)?;
     self
-      .main_deletion_queue
-      .push(Resource::VkPipeline(self.mesh_pipeline));
-
-    unsafe {
-      vkDestroyShaderModule(self.device, triangle_vert_shader, null());
-      vkDestroyShaderModule(self.device, triangle_frag_shader, null());
-
-      vkDestroyShaderModule(self.device, red_triangle_vert_shader, null());
-      vkDestroyShaderModule(self.device, red_triangle_frag_shader, null());
-
-      vkDestroyShaderModule(self.device, mesh_vert_shader, null());
+      .pipelines
+      .push(Pipeline::new(&self.context, textured_pipeline));
+    self.create_material(
+      textured_pipeline,
+      textured_pipeline_layout,
+      textured_push_constant_stages,
+      "texturedmesh",
+    );
+    let textured_materials: Vec<String> = self.material_textures.keys().cloned().collect();
+    for name in textured_materials {
+      self.create_material(
+        textured_pipeline,
+        textured_pipeline_layout,
+        textured_push_constant_stages,
+        &name,
+      );
     }
     Ok(())
   }
 
-  fn load_meshes(&mut self) -> Result<(), Error> {
+  // returns the nodes of the monkey scene, they place the meshes in init_scene, and
+  // the material of each of its meshes
+  fn load_meshes(&mut self) -> Result<(Vec<GltfSceneNode>, Vec<String>), Error> {
+    let mut triangle_mesh = Mesh::new();
     // make the array 3 vertices long
-    self.triangle_mesh.vertices.resize(3, unsafe { zeroed() });
+    triangle_mesh.vertices.resize(3, unsafe { zeroed() });
 
     // vertex positions
-    self.triangle_mesh.vertices[0].position = Vec3::new(1.0, 1.0, 0.0);
-    self.triangle_mesh.vertices[1].position = Vec3::new(-1.0, 1.0, 0.0);
-    self.triangle_mesh.vertices[2].position = Vec3::new(0.0, -1.0, 0.0);
+    triangle_mesh.vertices[0].position = Vec3::new(1.0, 1.0, 0.0);
+    triangle_mesh.vertices[1].position = Vec3::new(-1.0, 1.0, 0.0);
+    triangle_mesh.vertices[2].position = Vec3::new(0.0, -1.0, 0.0);
 
     // vertex colors, all green
-    self.triangle_mesh.vertices[0].color = Vec3::new(0.0, 1.0, 0.0);
-    self.triangle_mesh.vertices[1].color = Vec3::new(0.0, 1.0, 0.0);
-    self.triangle_mesh.vertices[2].color = Vec3::new(0.0, 1.0, 0.0);
-
-    upload_mesh(
-      self.allocator,
-      &mut self.triangle_mesh,
-      &mut self.main_deletion_queue,
-    )?;
+    triangle_mesh.vertices[0].color = Vec3::new(0.0, 1.0, 0.0);
+    triangle_mesh.vertices[1].color = Vec3::new(0.0, 1.0, 0.0);
+    triangle_mesh.vertices[2].color = Vec3::new(0.0, 1.0, 0.0);
+
+    // one triangle, one index per vertex
+    triangle_mesh.indices = vec![0, 1, 2];
+
+    self.upload_mesh(&mut triangle_mesh)?;
+    self.meshes.insert("triangle".to_string(), triangle_mesh);
 
-    upload_mesh(
-      self.allocator,
-      &mut self.monkey_mesh,
-      &mut self.main_deletion_queue,
+    // the meshes and images of the scene are named after their index
+    let monkey_scene = Mesh::load_gltf_scene("assets/monkey.glb")?;
+    for (i, mut mesh) in monkey_scene.meshes.into_iter().enumerate() {
+      self.upload_mesh(&mut mesh)?;
+      self.meshes.insert(format!("monkey_{}", i), mesh);
+    }
+
+    // Only the base color images are uploaded, each with a textured material of the
+    // same name. Meshes without one get the checker texture.
+    let textured = match self.get_material("texturedmesh") {
+      Some(material) => *material,
+      None => return Err(Error::Str("the textured pipeline isn't built")),
+    };
+    let mut monkey_materials = Vec::new();
+    for image in monkey_scene.mesh_images {
+      let name = match image {
+        Some(image) => format!("monkey_image_{}", image),
+        None => "texturedmesh".to_string(),
+      };
+      if let Some(image) = image {
+        if !self.textures.contains_key(&name) {
+          self.load_texture(&name, &monkey_scene.images[image])?;
+          self.create_material(
+            textured.pipeline,
+            textured.pipeline_layout,
+            textured.push_constant_stages,
+            &name,
+          );
+          self.material_textures.insert(name.clone(), name.clone());
+        }
+      }
+      monkey_materials.push(name);
+    }
+    Ok((monkey_scene.nodes, monkey_materials))
+  }
+
+  fn init_scene(
+    &mut self,
+    monkey_nodes: &[GltfSceneNode],
+    monkey_materials: &[String],
+  ) -> Result<(), Error> {
+    for node in monkey_nodes {
+      self.monkey_transforms.push(node.transform);
+      self.renderables.push(RenderObject {
+        mesh: format!("monkey_{}", node.mesh),
+        material: monkey_materials[node.mesh].clone(),
+        transform: node.transform,
+      });
+    }
+
+    // a grid of small triangles around the monkey
+    for x in -20..=20 {
+      for y in -20..=20 {
+        // translation times a uniform scale of 0.2, which only touches the diagonal
+        let mut transform = Mat4::translate_matrix(x as f32, 0.0, y as f32);
+        transform.c0r0 = 0.2;
+        transform.c1r1 = 0.2;
+        transform.c2r2 = 0.2;
+        self.renderables.push(RenderObject {
+          mesh: "triangle".to_string(),
+          material: "defaultmesh".to_string(),
+          transform,
+        });
+      }
+    }
+
+    self.write_texture_sets()
+  }
+
+  // Writes the texture of every textured material into its set. The sets written
+  // before are freed first, so this is only called while no frame is in flight.
+  fn write_texture_sets(&mut self) -> Result<(), Error> {
+    self.texture_set_allocator.reset_pools()?;
+    for (material_name, texture_name) in &self.material_textures {
+      let texture = match self.textures.get(texture_name) {
+        Some(texture) => texture,
+        None => return Err(Error::TextureMissing(texture_name.clone())),
+      };
+      let image_info = vkinit::descriptor_image_info(
+        texture.sampler.handle,
+        texture.image_view.handle,
+        VK_IMAGE_LAYOUT_SHADER_READ_ONLY_OPTIMAL,
+      );
+      let (texture_set, _) = DescriptorBuilder::new()
+        .bind_image(
+          0,
+          image_info,
+          VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER,
+          VK_SHADER_STAGE_FRAGMENT_BIT,
+        )
+        .build(
+          &mut self.descriptor_layout_cache,
+          &mut self.texture_set_allocator,
+        )?;
+      if let Some(material) = self.materials.get_mut(material_name) {
+        material.texture_set = texture_set;
+      }
+    }
+    Ok(())
+  }
+
+  fn load_images(&mut self) -> Result<(), Error> {
+    let checker = TextureData::load("assets/checker.png", true)?;
+    self.load_texture("checker", &checker)?;
+    self
+      .material_textures
+      .insert("texturedmesh".to_string(), "checker".to_string());
+    Ok(())
+  }
+
+  // uploads the pixels into a sampled image with a full mip chain and registers
+  // it under name, replacing any texture that had the name before
+  fn load_texture(&mut self, name: &str, data: &TextureData) -> Result<(), Error> {
+    let format = data.format();
+    let mip_levels = data.mip_levels();
+
+    // the mips are made with linear blits from the level above, not every format can do that
+    let mut format_properties: VkFormatProperties = unsafe { zeroed() };
+    unsafe {
+      vkGetPhysicalDeviceFormatProperties(self.chosen_gpu, format, &mut format_properties);
+    }
+    let required_features = [
+      (
+        VK_FORMAT_FEATURE_BLIT_SRC_BIT,
+        "VK_FORMAT_FEATURE_BLIT_SRC_BIT",
+      ),
+      (
+        VK_FORMAT_FEATURE_BLIT_DST_BIT,
+        "VK_FORMAT_FEATURE_BLIT_DST_BIT",
+      ),
+      (
+        VK_FORMAT_FEATURE_SAMPLED_IMAGE_FILTER_LINEAR_BIT,
+        "VK_FORMAT_FEATURE_SAMPLED_IMAGE_FILTER_LINEAR_BIT",
+      ),
+    ];
+    for (feature, feature_name) in required_features.iter() {
+      if format_properties.optimalTilingFeatures & *feature == 0 {
+        return Err(Error::TextureFormat(name.to_string(), format, feature_name));
+      }
+    }
+
+    // the pixels go to a staging buffer first, just like the meshes
+    let staging_buffer = self.create_buffer(
+      data.pixels.len(),
+      VK_BUFFER_USAGE_TRANSFER_SRC_BIT,
+      VMA_MEMORY_USAGE_CPU_ONLY,
     )?;
+    unsafe {
+      let mut mapped = null_mut();
+      VK_CHECK!(vmaMapMemory(
+        self.context.allocator,
+        staging_buffer.allocation,
+        &mut mapped
+      ))?;
+      copy_nonoverlapping(data.pixels.as_ptr(), mapped as *mut u8, data.pixels.len());
+      vmaUnmapMemory(self.context.allocator, staging_buffer.allocation);
+    }
+
+    let image_extent = VkExtent3D {
+      width: data.width,
+      height: data.height,
+      depth: 1,
+    };
+    // the mip levels are blitted from each other, so the image is a transfer source too
+    let mut img_create_info = vkinit::image_create_info(
+      format,
+      VK_IMAGE_USAGE_SAMPLED_BIT
+        | VK_IMAGE_USAGE_TRANSFER_DST_BIT
+        | VK_IMAGE_USAGE_TRANSFER_SRC_BIT,
+      image_extent,
+    );
+    img_create_info.mipLevels = mip_levels;
+    let img_alloc_info = VmaAllocationCreateInfo {
+      flags: 0,
+      usage: VMA_MEMORY_USAGE_GPU_ONLY,
+      requiredFlags: 0,
+      preferredFlags: 0,
+      memoryTypeBits: 0,
+      pool: null(),
+      pUserData: null_mut(),
+      priority: 0.0,
+    };
+    let mut vk_image = null();
+    let mut allocation = null();
+    unsafe {
+      VK_CHECK!(vmaCreateImage(
+        self.context.allocator,
+        &img_create_info,
+        &img_alloc_info,
+        &mut vk_image,
+        &mut allocation,
+        null_mut()
+      ))?;
+    }
+    let image = Image::new(&self.context, vk_image, allocation);
+
+    // immediate_submit waits for the upload, the staging buffer is dropped at the end
+    self.immediate_submit(|cmd| unsafe {
+      vk_textures::cmd_upload_texture(
+        cmd,
+        staging_buffer.buffer,
+        vk_image,
+        data.width,
+        data.height,
+        mip_levels,
+      )
+    })?;
+
+    // the view covers every mip level
+    let mut view_info = vkinit::imageview_create_info(format, vk_image, VK_IMAGE_ASPECT_COLOR_BIT);
+    view_info.subresourceRange.levelCount = mip_levels;
+    let mut image_view = null();
+    unsafe {
+      VK_CHECK!(vkCreateImageView(
+        self.context.device,
+        &view_info,
+        null(),
+        &mut image_view
+      ))?;
+    }
+    let image_view = ImageView::new(&self.context, image_view);
+
+    let sampler_info =
+      vkinit::sampler_create_info(VK_FILTER_LINEAR, VK_SAMPLER_ADDRESS_MODE_REPEAT);
+    let mut sampler = null();
+    unsafe {
+      VK_CHECK!(vkCreateSampler(
+        self.context.device,
+        &sampler_info,
+        null(),
+        &mut sampler
+      ))?;
+    }
+    let sampler = Sampler::new(&self.context, sampler);
+
+    let texture = Texture {
+      image,
+      image_view,
+      sampler,
+      mip_levels,
+    };
+    // a texture that is replaced may still be sampled by frames in flight
+    if let Some(replaced) = self.textures.insert(name.to_string(), texture) {
+      self.defer_deletion(replaced);
+    }
+    self.texture_data.insert(name.to_string(), data.clone());
     Ok(())
   }
-}
 
-fn upload_mesh(
-  allocator: VmaAllocator,
-  mesh: &mut Mesh,
-  deletion_queue: &mut ResourceDestuctor,
-) -> Result<(), Error> {
-  // allocate vertex buffer
-  let buffer_info = VkBufferCreateInfo {
-    sType: VK_STRUCTURE_TYPE_BUFFER_CREATE_INFO,
-    pNext: null(),
-    flags: 0,
-    // total size in bytes of the buffer
-    size: (size_of::<Vertex>() * mesh.vertices.len()) as u64,
+  // registers a material under a name. If the name is already taken the pipeline is
+  // replaced and the texture set is kept, that's how rebuilt pipelines get swapped in.
+  fn create_material(
+    &mut self,
+    pipeline: VkPipeline,
+    layout: VkPipelineLayout,
+    push_constant_stages: VkShaderStageFlags,
+    name: &str,
+  ) -> Material {
+    let material = self.materials.entry(name.to_string()).or_insert(Material {
+      texture_set: null(),
+      pipeline,
+      pipeline_layout: layout,
+      push_constant_stages,
+    });
+    material.pipeline = pipeline;
+    material.pipeline_layout = layout;
+    material.push_constant_stages = push_constant_stages;
+    *material
+  }
+
+  // returns None if it can't be found
+  fn get_material(&self, name: &str) -> Option<&Material> {
+    self.materials.get(name)
+  }
+
+  // returns None if it can't be found
+  fn get_mesh(&self, name: &str) -> Option<&Mesh> {
+    self.meshes.get(name)
+  }
+
+  fn upload_mesh(&mut self, mesh: &mut Mesh) -> Result<(), Error> {
     // this buffer is going to be used as a Vertex buffer
-    usage: VK_BUFFER_USAGE_VERTEX_BUFFER_BIT,
-    sharingMode: 0,
-    queueFamilyIndexCount: 0,
-    pQueueFamilyIndices: null(),
-  };
-
-  // let the VMA library know that this data should be writeable by CPU,
-  // but also readable by the GPU.
-  let vma_alloc_info = VmaAllocationCreateInfo {
-    flags: 0,
-    usage: VMA_MEMORY_USAGE_CPU_TO_GPU,
-    requiredFlags: 0,
-    preferredFlags: 0,
-    memoryTypeBits: 0,
-    pool: null(),
-    pUserData: null_mut(),
-    priority: 0.0,
-  };
-
-  // allocate the buffer
-  unsafe {
-    VK_CHECK!(vmaCreateBuffer(
-      allocator,
-      &buffer_info,
-      &vma_alloc_info,
-      &mut mesh.vertex_buffer.buffer,
-      &mut mesh.vertex_buffer.allocation,
-      null_mut()
-    ));
+    mesh.vertex_buffer = self.upload_buffer(&mesh.vertices, VK_BUFFER_USAGE_VERTEX_BUFFER_BIT)?;
+
+    // and this one as an Index buffer
+    mesh.index_buffer = self.upload_buffer(&mesh.indices, VK_BUFFER_USAGE_INDEX_BUFFER_BIT)?;
+
+    Ok(())
   }
 
-  deletion_queue.push(Resource::VmaAllocatedBuffer(mesh.vertex_buffer));
+  // copies data into a new GPU only buffer by way of a CPU side staging buffer
+  fn upload_buffer<T: Copy>(
+    &mut self,
+    data: &[T],
+    usage: VkBufferUsageFlags,
+  ) -> Result<Buffer, Error> {
+    // Vulkan has no buffers of zero bytes
+    if data.is_empty() {
+      return Err(Error::Str("Can't upload an empty buffer"));
+    }
+    let buffer_size = size_of::<T>() * data.len();
+
+    // the staging buffer is only the source of a transfer
+    let staging_buffer = self.create_buffer(
+      buffer_size,
+      VK_BUFFER_USAGE_TRANSFER_SRC_BIT,
+      VMA_MEMORY_USAGE_CPU_ONLY,
+    )?;
 
-  // copy vertex data
-  unsafe {
-    let mut data = null_mut();
-    vmaMapMemory(allocator, mesh.vertex_buffer.allocation, &mut data);
-    copy_nonoverlapping(
-      mesh.vertices.as_ptr(),
-      data as *mut Vertex,
-      size_of::<Vertex>() * mesh.vertices.len(),
-    );
-    vmaUnmapMemory(allocator, mesh.vertex_buffer.allocation);
+    // copy the data, copy_nonoverlapping counts in elements of T not bytes
+    unsafe {
+      let mut mapped = null_mut();
+      VK_CHECK!(vmaMapMemory(
+        self.context.allocator,
+        staging_buffer.allocation,
+        &mut mapped
+      ))?;
+      copy_nonoverlapping(data.as_ptr(), mapped as *mut T, data.len());
+      vmaUnmapMemory(self.context.allocator, staging_buffer.allocation);
+    }
+
+    // the real buffer lives in device local memory and is the destination of the transfer
+    let buffer = self.create_buffer(
+      buffer_size,
+      usage | VK_BUFFER_USAGE_TRANSFER_DST_BIT,
+      VMA_MEMORY_USAGE_GPU_ONLY,
+    )?;
+
+    // immediate_submit waits for the copy, the staging buffer is dropped at the end
+    self.immediate_submit(|cmd| {
+      let copy = VkBufferCopy {
+        srcOffset: 0,
+        dstOffset: 0,
+        size: buffer_size as u64,
+      };
+      unsafe { vkCmdCopyBuffer(cmd, staging_buffer.buffer, buffer.buffer, 1, &copy) };
+    })?;
+
+    Ok(buffer)
+  }
+
+  // the buffer is destroyed when the caller drops it
+  fn create_buffer(
+    &self,
+    alloc_size: usize,
+    usage: VkBufferUsageFlags,
+    memory_usage: VmaMemoryUsage,
+  ) -> Result<Buffer, Error> {
+    // allocate buffer
+    let buffer_info = VkBufferCreateInfo {
+      sType: VK_STRUCTURE_TYPE_BUFFER_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      // total size in bytes of the buffer
+      size: alloc_size as u64,
+      usage,
+      sharingMode: 0,
+      queueFamilyIndexCount: 0,
+      pQueueFamilyIndices: null(),
+    };
+
+    let vma_alloc_info = VmaAllocationCreateInfo {
+      flags: 0,
+      usage: memory_usage,
+      requiredFlags: 0,
+      preferredFlags: 0,
+      memoryTypeBits: 0,
+      pool: null(),
+      pUserData: null_mut(),
+      priority: 0.0,
+    };
+
+    // allocate the buffer
+    let mut buffer = null();
+    let mut allocation = null();
+    unsafe {
+      VK_CHECK!(vmaCreateBuffer(
+        self.context.allocator,
+        &buffer_info,
+        &vma_alloc_info,
+        &mut buffer,
+        &mut allocation,
+        null_mut()
+      ))?;
+    }
+    Ok(Buffer::new(&self.context, buffer, allocation))
+  }
+
+  // records commands with the upload command buffer, submits them and waits until the
+  // GPU is done with them. Meant for uploads at load time, not for use every frame.
+  fn immediate_submit<F: FnOnce(VkCommandBuffer)>(&self, function: F) -> Result<(), Error> {
+    let cmd = self.upload_context.command_buffer;
+
+    // this command buffer will be used exactly once before resetting
+    let cmd_begin_info = VkCommandBufferBeginInfo {
+      sType: VK_STRUCTURE_TYPE_COMMAND_BUFFER_BEGIN_INFO,
+      pNext: null(),
+      flags: VK_COMMAND_BUFFER_USAGE_ONE_TIME_SUBMIT_BIT,
+      pInheritanceInfo: null(),
+    };
+    unsafe {
+      VK_CHECK!(vkBeginCommandBuffer(cmd, &cmd_begin_info))?;
+    }
+
+    // execute the function
+    function(cmd);
+
+    unsafe {
+      VK_CHECK!(vkEndCommandBuffer(cmd))?;
+
+      let submit = VkSubmitInfo {
+        sType: VK_STRUCTURE_TYPE_SUBMIT_INFO,
+        pNext: null(),
+        waitSemaphoreCount: 0,
+        pWaitSemaphores: null(),
+        pWaitDstStageMask: null(),
+        commandBufferCount: 1,
+        pCommandBuffers: &cmd,
+        signalSemaphoreCount: 0,
+        pSignalSemaphores: null(),
+      };
+
+      // submit command buffer to the queue and execute it.
+      // upload_fence will now block until the commands finish execution
+      let upload_fence = self.upload_context.upload_fence.handle;
+      VK_CHECK!(vkQueueSubmit(self.graphics_queue, 1, &submit, upload_fence))?;
+
+      VK_CHECK!(vkWaitForFences(
+        self.context.device,
+        1,
+        &upload_fence,
+        VK_TRUE,
+        9_999_999_999
+      ))?;
+      VK_CHECK!(vkResetFences(self.context.device, 1, &upload_fence))?;
+
+      // clear the command pool. This will free the command buffer too
+      VK_CHECK!(vkResetCommandPool(
+        self.context.device,
+        self.upload_context.command_pool.handle,
+        0
+      ))?;
+    }
+    Ok(())
   }
+}
 
-  Ok(())
+impl Drop for VulkanEngine {
+  fn drop(&mut self) {
+    self.cleanup();
+  }
 }
@@ -0,0 +1,223 @@
+use {
+  crate::{error::Error, VK_CHECK},
+  std::{
+    ffi::CStr,
+    os::raw::c_char,
+    ptr::{null, null_mut},
+  },
+  vkcapi::core::v1_0::*,
+};
+
+#[cfg(feature = "compute")]
+use crate::vk_compute;
+
+// vkcboot needs an SDL window to find the instance extensions and a surface to pick
+// the device, neither exist when rendering offscreen. This does the same setup by
+// hand with nothing but a graphics queue, which is all a driver like lavapipe needs.
+
+const VALIDATION_LAYER: &[u8] = b"VK_LAYER_KHRONOS_validation\0";
+// the debug messenger is created on the instance, so it needs the extension
+const DEBUG_UTILS_EXTENSION: &[u8] = b"VK_EXT_debug_utils\0";
+
+// The validation layer comes with the Vulkan SDK, a machine that only has a driver
+// doesn't have it. There the instance is created without validation instead of failing.
+pub fn validation_available() -> Result<bool, Error> {
+  let mut layer_count = 0;
+  unsafe {
+    VK_CHECK!(vkEnumerateInstanceLayerProperties(
+      &mut layer_count,
+      null_mut()
+    ))?;
+  }
+  let mut layers: Vec<VkLayerProperties> = Vec::with_capacity(layer_count as usize);
+  unsafe {
+    VK_CHECK!(vkEnumerateInstanceLayerProperties(
+      &mut layer_count,
+      layers.as_mut_ptr()
+    ))?;
+    layers.set_len(layer_count as usize);
+  }
+  Ok(layers.iter().any(|layer| {
+    unsafe { CStr::from_ptr(layer.layerName.as_ptr()) }.to_bytes_with_nul() == VALIDATION_LAYER
+  }))
+}
+
+// validation is on with the validation feature, if the layer is there
+pub fn create_instance() -> Result<VkInstance, Error> {
+  let mut layers: Vec<*const c_char> = Vec::new();
+  let mut extensions: Vec<*const c_char> = Vec::new();
+  if cfg!(feature = "validation") && validation_available()? {
+    layers.push(VALIDATION_LAYER.as_ptr() as *const c_char);
+    extensions.push(DEBUG_UTILS_EXTENSION.as_ptr() as *const c_char);
+  }
+
+  let app_info = VkApplicationInfo {
+    sType: VK_STRUCTURE_TYPE_APPLICATION_INFO,
+    pNext: null(),
+    pApplicationName: b"Vulkan Engine\0".as_ptr() as *const c_char,
+    applicationVersion: vkcapi::VK_MAKE_VERSION!(0, 0, 1),
+    pEngineName: b"No Engine\0".as_ptr() as *const c_char,
+    engineVersion: vkcapi::VK_MAKE_VERSION!(0, 0, 1),
+    apiVersion: vkcapi::VK_MAKE_VERSION!(1, 1, 0),
+  };
+
+  let create_info = VkInstanceCreateInfo {
+    sType: VK_STRUCTURE_TYPE_INSTANCE_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    pApplicationInfo: &app_info,
+    enabledLayerCount: layers.len() as u32,
+    ppEnabledLayerNames: layers.as_ptr(),
+    enabledExtensionCount: extensions.len() as u32,
+    ppEnabledExtensionNames: extensions.as_ptr(),
+  };
+
+  let mut instance = null();
+  unsafe {
+    VK_CHECK!(vkCreateInstance(&create_info, null(), &mut instance))?;
+  }
+  Ok(instance)
+}
+
+// the queue families of a GPU, in the order their indices refer to
+pub fn queue_families(gpu: VkPhysicalDevice) -> Vec<VkQueueFamilyProperties> {
+  let mut family_count = 0;
+  unsafe {
+    vkGetPhysicalDeviceQueueFamilyProperties(gpu, &mut family_count, null_mut());
+  }
+  let mut families: Vec<VkQueueFamilyProperties> = Vec::with_capacity(family_count as usize);
+  unsafe {
+    vkGetPhysicalDeviceQueueFamilyProperties(gpu, &mut family_count, families.as_mut_ptr());
+    families.set_len(family_count as usize);
+  }
+  families
+}
+
+// what the engine needs from the device, same as the parts of vkcboot::Device it uses
+pub struct HeadlessDevice {
+  pub physical_device: VkPhysicalDevice,
+  pub device: VkDevice,
+  pub graphics_queue: VkQueue,
+  pub graphics_queue_index: u32,
+  // a queue of a compute only family if there is one, the graphics queue otherwise
+  #[cfg(feature = "compute")]
+  pub compute_queue: VkQueue,
+  #[cfg(feature = "compute")]
+  pub compute_queue_index: u32,
+}
+
+// Picks the first GPU with a graphics queue family. With the compute feature, compute
+// gets its own queue when the GPU has a family with compute but no graphics, work
+// submitted there can run alongside the rendering.
+pub fn create_device(instance: VkInstance) -> Result<HeadlessDevice, Error> {
+  let mut gpu_count = 0;
+  unsafe {
+    VK_CHECK!(vkEnumeratePhysicalDevices(
+      instance,
+      &mut gpu_count,
+      null_mut()
+    ))?;
+  }
+  let mut gpus = vec![null(); gpu_count as usize];
+  unsafe {
+    VK_CHECK!(vkEnumeratePhysicalDevices(
+      instance,
+      &mut gpu_count,
+      gpus.as_mut_ptr()
+    ))?;
+  }
+
+  let mut chosen = None;
+  #[cfg(feature = "compute")]
+  let mut compute_queue_index = None;
+  for gpu in gpus {
+    let families = queue_families(gpu);
+
+    if let Some(index) = families
+      .iter()
+      .position(|family| family.queueFlags & VK_QUEUE_GRAPHICS_BIT != 0)
+    {
+      chosen = Some((gpu, index as u32));
+      #[cfg(feature = "compute")]
+      {
+        compute_queue_index = vk_compute::compute_only_family(&families);
+      }
+      break;
+    }
+  }
+  let (physical_device, graphics_queue_index) = match chosen {
+    Some(chosen) => chosen,
+    None => return Err(Error::Str("No GPU with a graphics queue")),
+  };
+
+  #[cfg(feature = "compute")]
+  let compute_queue_index = compute_queue_index.unwrap_or(graphics_queue_index);
+
+  let queue_priority = 1.0;
+  #[allow(unused_mut)]
+  let mut queue_infos = vec![VkDeviceQueueCreateInfo {
+    sType: VK_STRUCTURE_TYPE_DEVICE_QUEUE_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    queueFamilyIndex: graphics_queue_index,
+    queueCount: 1,
+    pQueuePriorities: &queue_priority,
+  }];
+  #[cfg(feature = "compute")]
+  if compute_queue_index != graphics_queue_index {
+    queue_infos.push(VkDeviceQueueCreateInfo {
+      sType: VK_STRUCTURE_TYPE_DEVICE_QUEUE_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      queueFamilyIndex: compute_queue_index,
+      queueCount: 1,
+      pQueuePriorities: &queue_priority,
+    });
+  }
+
+  // no swapchain extension, there is nothing to present to
+  let device_info = VkDeviceCreateInfo {
+    sType: VK_STRUCTURE_TYPE_DEVICE_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    queueCreateInfoCount: queue_infos.len() as u32,
+    pQueueCreateInfos: queue_infos.as_ptr(),
+    enabledLayerCount: 0,
+    ppEnabledLayerNames: null(),
+    enabledExtensionCount: 0,
+    ppEnabledExtensionNames: null(),
+    pEnabledFeatures: null(),
+  };
+
+  let mut device = null();
+  unsafe {
+    VK_CHECK!(vkCreateDevice(
+      physical_device,
+      &device_info,
+      null(),
+      &mut device
+    ))?;
+  }
+
+  let mut graphics_queue = null();
+  unsafe {
+    vkGetDeviceQueue(device, graphics_queue_index, 0, &mut graphics_queue);
+  }
+  #[cfg(feature = "compute")]
+  let mut compute_queue = null();
+  #[cfg(feature = "compute")]
+  unsafe {
+    vkGetDeviceQueue(device, compute_queue_index, 0, &mut compute_queue);
+  }
+
+  Ok(HeadlessDevice {
+    physical_device,
+    device,
+    graphics_queue,
+    graphics_queue_index,
+    #[cfg(feature = "compute")]
+    compute_queue,
+    #[cfg(feature = "compute")]
+    compute_queue_index,
+  })
+}
@@ -167,6 +167,33 @@ pub fn color_blend_attachment_state() -> VkPipelineColorBlendAttachmentState {
   color_blend_attachment
 }
 
+// Blend presets, all write every channel. Straight alpha, the color is multiplied by
+// its alpha when blended.
+pub fn alpha_blend_attachment_state() -> VkPipelineColorBlendAttachmentState {
+  let mut color_blend_attachment = color_blend_attachment_state();
+  color_blend_attachment.blendEnable = VK_TRUE;
+  color_blend_attachment.srcColorBlendFactor = VK_BLEND_FACTOR_SRC_ALPHA;
+  color_blend_attachment.dstColorBlendFactor = VK_BLEND_FACTOR_ONE_MINUS_SRC_ALPHA;
+  color_blend_attachment.srcAlphaBlendFactor = VK_BLEND_FACTOR_ONE;
+  color_blend_attachment.dstAlphaBlendFactor = VK_BLEND_FACTOR_ONE_MINUS_SRC_ALPHA;
+  color_blend_attachment
+}
+
+// for colors that already have alpha multiplied in
+pub fn premultiplied_alpha_blend_attachment_state() -> VkPipelineColorBlendAttachmentState {
+  let mut color_blend_attachment = alpha_blend_attachment_state();
+  color_blend_attachment.srcColorBlendFactor = VK_BLEND_FACTOR_ONE;
+  color_blend_attachment
+}
+
+// adds the color on top, for glows and particles
+pub fn additive_blend_attachment_state() -> VkPipelineColorBlendAttachmentState {
+  let mut color_blend_attachment = alpha_blend_attachment_state();
+  color_blend_attachment.dstColorBlendFactor = VK_BLEND_FACTOR_ONE;
+  color_blend_attachment.dstAlphaBlendFactor = VK_BLEND_FACTOR_ONE;
+  color_blend_attachment
+}
+
 pub fn depth_stencil_create_info(
   b_depth_test: bool,
   b_depth_write: bool,
@@ -204,6 +231,17 @@ pub fn pipeline_layout_create_info() -> VkPipelineLayoutCreateInfo {
   }
 }
 
+pub fn tessellation_state_create_info(
+  patch_control_points: u32,
+) -> VkPipelineTessellationStateCreateInfo {
+  VkPipelineTessellationStateCreateInfo {
+    sType: VK_STRUCTURE_TYPE_PIPELINE_TESSELLATION_STATE_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    patchControlPoints: patch_control_points,
+  }
+}
+
 pub fn viewport(
   x: f32,
   y: f32,
@@ -268,3 +306,69 @@ pub fn imageview_create_info(
     },
   }
 }
+
+pub fn sampler_create_info(
+  filters: VkFilter,
+  sampler_address_mode: VkSamplerAddressMode,
+) -> VkSamplerCreateInfo {
+  VkSamplerCreateInfo {
+    sType: VK_STRUCTURE_TYPE_SAMPLER_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    magFilter: filters,
+    minFilter: filters,
+    mipmapMode: VK_SAMPLER_MIPMAP_MODE_LINEAR,
+    addressModeU: sampler_address_mode,
+    addressModeV: sampler_address_mode,
+    addressModeW: sampler_address_mode,
+    mipLodBias: 0.0,
+    anisotropyEnable: VK_FALSE,
+    maxAnisotropy: 1.0,
+    compareEnable: VK_FALSE,
+    compareOp: VK_COMPARE_OP_ALWAYS,
+    minLod: 0.0,
+    maxLod: VK_LOD_CLAMP_NONE,
+    borderColor: VK_BORDER_COLOR_INT_OPAQUE_BLACK,
+    unnormalizedCoordinates: VK_FALSE,
+  }
+}
+
+pub fn descriptor_image_info(
+  sampler: VkSampler,
+  image_view: VkImageView,
+  image_layout: VkImageLayout,
+) -> VkDescriptorImageInfo {
+  VkDescriptorImageInfo {
+    sampler,
+    imageView: image_view,
+    imageLayout: image_layout,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn blend_presets() {
+    let alpha = alpha_blend_attachment_state();
+    assert_eq!(alpha.blendEnable, VK_TRUE);
+    assert_eq!(alpha.srcColorBlendFactor, VK_BLEND_FACTOR_SRC_ALPHA);
+    assert_eq!(
+      alpha.dstColorBlendFactor,
+      VK_BLEND_FACTOR_ONE_MINUS_SRC_ALPHA
+    );
+
+    let premultiplied = premultiplied_alpha_blend_attachment_state();
+    assert_eq!(premultiplied.srcColorBlendFactor, VK_BLEND_FACTOR_ONE);
+    assert_eq!(
+      premultiplied.dstColorBlendFactor,
+      VK_BLEND_FACTOR_ONE_MINUS_SRC_ALPHA
+    );
+
+    let additive = additive_blend_attachment_state();
+    assert_eq!(additive.srcColorBlendFactor, VK_BLEND_FACTOR_SRC_ALPHA);
+    assert_eq!(additive.dstColorBlendFactor, VK_BLEND_FACTOR_ONE);
+    assert_eq!(additive.dstAlphaBlendFactor, VK_BLEND_FACTOR_ONE);
+  }
+}
@@ -1,16 +1,34 @@
-use {crate::error::Error, std::ptr::null, vkcapi::core::v1_0::*};
+use {
+  crate::{error::Error, VK_CHECK},
+  std::ptr::null,
+  vkcapi::core::v1_0::*,
+};
 
+#[cfg(feature = "compute")]
+use crate::vk_initializers as vkinit;
+
+// Everything but the depth stencil and tessellation state has to be set before build,
+// a missing one is an error naming it. Tessellation is needed with tessellation
+// shaders. Viewports and scissors can be left dynamic instead, then they are set with
+// vkCmdSetViewport/vkCmdSetScissor and the pipeline doesn't have to be built again
+// when the window changes size. There has to be a scissor for every viewport.
 pub struct PipelineBuilder {
   shader_stages: Option<Vec<VkPipelineShaderStageCreateInfo>>,
   vertex_input_info: Option<VkPipelineVertexInputStateCreateInfo>,
   input_assembly: Option<VkPipelineInputAssemblyStateCreateInfo>,
-  viewport: Option<VkViewport>,
-  scissor: Option<VkRect2D>,
+  tessellation: Option<VkPipelineTessellationStateCreateInfo>,
+  viewports: Vec<VkViewport>,
+  scissors: Vec<VkRect2D>,
   rasterizer: Option<VkPipelineRasterizationStateCreateInfo>,
-  color_blend_attachment: Option<VkPipelineColorBlendAttachmentState>,
+  color_blend_attachments: Vec<VkPipelineColorBlendAttachmentState>,
+  logic_op: Option<VkLogicOp>,
+  blend_constants: [f32; 4],
   multisampling: Option<VkPipelineMultisampleStateCreateInfo>,
   depth_stencil: Option<VkPipelineDepthStencilStateCreateInfo>,
+  dynamic_states: Vec<VkDynamicState>,
   pipeline_layout: Option<VkPipelineLayout>,
+  subpass: u32,
+  pipeline_cache: VkPipelineCache,
 }
 
 impl PipelineBuilder {
@@ -19,13 +37,19 @@ impl PipelineBuilder {
       shader_stages: None,
       vertex_input_info: None,
       input_assembly: None,
-      viewport: None,
-      scissor: None,
+      tessellation: None,
+      viewports: Vec::new(),
+      scissors: Vec::new(),
       rasterizer: None,
-      color_blend_attachment: None,
+      color_blend_attachments: Vec::new(),
+      logic_op: None,
+      blend_constants: [0.0, 0.0, 0.0, 0.0],
       multisampling: None,
       depth_stencil: None,
+      dynamic_states: Vec::new(),
       pipeline_layout: None,
+      subpass: 0,
+      pipeline_cache: null(),
     }
   }
 
@@ -49,13 +73,21 @@ impl PipelineBuilder {
     self
   }
 
+  // needed when there are tessellation shaders, the topology has to be a patch list
+  pub fn tessellation(&mut self, info: VkPipelineTessellationStateCreateInfo) -> &mut Self {
+    self.tessellation = Some(info);
+    self
+  }
+
+  // adds a viewport, more than one needs the multiViewport feature
   pub fn viewport(&mut self, viewport: VkViewport) -> &mut Self {
-    self.viewport = Some(viewport);
+    self.viewports.push(viewport);
     self
   }
 
+  // adds a scissor, there has to be one for every viewport
   pub fn scissor(&mut self, scissor: VkRect2D) -> &mut Self {
-    self.scissor = Some(scissor);
+    self.scissors.push(scissor);
     self
   }
 
@@ -64,11 +96,13 @@ impl PipelineBuilder {
     self
   }
 
+  // adds the blend state of the next color attachment of the subpass, see
+  // vk_initializers for the presets
   pub fn color_blend_attachment(
     &mut self,
     color_blend_attachment: VkPipelineColorBlendAttachmentState,
   ) -> &mut Self {
-    self.color_blend_attachment = Some(color_blend_attachment);
+    self.color_blend_attachments.push(color_blend_attachment);
     self
   }
 
@@ -88,35 +122,122 @@ impl PipelineBuilder {
     self
   }
 
+  // state that is set in the command buffer instead of baked into the pipeline
+  pub fn dynamic_state(&mut self, state: VkDynamicState) -> &mut Self {
+    if !self.dynamic_states.contains(&state) {
+      self.dynamic_states.push(state);
+    }
+    self
+  }
+
+  // a logic op replaces blending for all attachments
+  pub fn logic_op(&mut self, logic_op: VkLogicOp) -> &mut Self {
+    self.logic_op = Some(logic_op);
+    self
+  }
+
+  // used by the CONSTANT_COLOR and CONSTANT_ALPHA blend factors
+  pub fn blend_constants(&mut self, blend_constants: [f32; 4]) -> &mut Self {
+    self.blend_constants = blend_constants;
+    self
+  }
+
+  // the usual case, the viewport and scissor follow the swapchain
+  pub fn dynamic_viewport_scissor(&mut self) -> &mut Self {
+    self
+      .dynamic_state(VK_DYNAMIC_STATE_VIEWPORT)
+      .dynamic_state(VK_DYNAMIC_STATE_SCISSOR)
+  }
+
   pub fn pipeline_layout(&mut self, pipeline_layout: VkPipelineLayout) -> &mut Self {
     self.pipeline_layout = Some(pipeline_layout);
     self
   }
 
+  // the subpass of the render pass the pipeline is used in, 0 by default
+  pub fn subpass(&mut self, subpass: u32) -> &mut Self {
+    self.subpass = subpass;
+    self
+  }
+
+  // lets the driver reuse what it compiled for earlier pipelines or runs
+  pub fn pipeline_cache(&mut self, pipeline_cache: VkPipelineCache) -> &mut Self {
+    self.pipeline_cache = pipeline_cache;
+    self
+  }
+
   pub fn build(&self, device: VkDevice, pass: VkRenderPass) -> Result<VkPipeline, Error> {
-    // make viewport state from our stored viewport and scissor.
-    // at the moment we won't support multiple viewports or scissors
+    let shader_stages = required(&self.shader_stages, "shader_stages")?;
+    let vertex_input_info = required(&self.vertex_input_info, "vertex_input_info")?;
+    let input_assembly = required(&self.input_assembly, "input_assembly")?;
+    let rasterizer = required(&self.rasterizer, "rasterizer")?;
+    let multisampling = required(&self.multisampling, "multisampling")?;
+    let pipeline_layout = *required(&self.pipeline_layout, "pipeline_layout")?;
+
+    let tessellated = shader_stages.iter().any(|stage| {
+      stage.stage
+        & (VK_SHADER_STAGE_TESSELLATION_CONTROL_BIT | VK_SHADER_STAGE_TESSELLATION_EVALUATION_BIT)
+        != 0
+    });
+    if tessellated && self.tessellation.is_none() {
+      return Err(Error::PipelineMissingState("tessellation"));
+    }
+
+    // A dynamic viewport or scissor only needs the count, which is the number given
+    // or 1. A static one has to be given.
+    let viewport_dynamic = self.dynamic_states.contains(&VK_DYNAMIC_STATE_VIEWPORT);
+    let scissor_dynamic = self.dynamic_states.contains(&VK_DYNAMIC_STATE_SCISSOR);
+    if !viewport_dynamic && self.viewports.is_empty() {
+      return Err(Error::PipelineMissingState("viewport"));
+    }
+    if !scissor_dynamic && self.scissors.is_empty() {
+      return Err(Error::PipelineMissingState("scissor"));
+    }
+    let viewport_count = usize::max(self.viewports.len(), 1) as u32;
+    let scissor_count = usize::max(self.scissors.len(), 1) as u32;
+    if viewport_count != scissor_count {
+      return Err(Error::PipelineViewportCount(viewport_count, scissor_count));
+    }
     let viewport_state = VkPipelineViewportStateCreateInfo {
       sType: VK_STRUCTURE_TYPE_PIPELINE_VIEWPORT_STATE_CREATE_INFO,
       pNext: null(),
       flags: 0,
-      viewportCount: 1,
-      pViewports: self.viewport.as_ref().unwrap(),
-      scissorCount: 1,
-      pScissors: self.scissor.as_ref().unwrap(),
+      viewportCount: viewport_count,
+      pViewports: if viewport_dynamic {
+        null()
+      } else {
+        self.viewports.as_ptr()
+      },
+      scissorCount: scissor_count,
+      pScissors: if scissor_dynamic {
+        null()
+      } else {
+        self.scissors.as_ptr()
+      },
     };
 
-    // setup dummy color blending. We aren't using transparent objects yet
-    // the blending is just "no blend", but we do write to the color attachment
+    // one blend state per color attachment, a depth only pass has none
     let color_blending = VkPipelineColorBlendStateCreateInfo {
       sType: VK_STRUCTURE_TYPE_PIPELINE_COLOR_BLEND_STATE_CREATE_INFO,
       pNext: null(),
       flags: 0,
-      logicOpEnable: VK_FALSE,
-      logicOp: VK_LOGIC_OP_COPY,
-      attachmentCount: 1,
-      pAttachments: self.color_blend_attachment.as_ref().unwrap(),
-      blendConstants: [0.0, 0.0, 0.0, 0.0],
+      logicOpEnable: if self.logic_op.is_some() {
+        VK_TRUE
+      } else {
+        VK_FALSE
+      },
+      logicOp: self.logic_op.unwrap_or(VK_LOGIC_OP_COPY),
+      attachmentCount: self.color_blend_attachments.len() as u32,
+      pAttachments: self.color_blend_attachments.as_ptr(),
+      blendConstants: self.blend_constants,
+    };
+
+    let dynamic_state = VkPipelineDynamicStateCreateInfo {
+      sType: VK_STRUCTURE_TYPE_PIPELINE_DYNAMIC_STATE_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      dynamicStateCount: self.dynamic_states.len() as u32,
+      pDynamicStates: self.dynamic_states.as_ptr(),
     };
 
     // build the actual pipeline
@@ -126,37 +247,211 @@ impl PipelineBuilder {
       sType: VK_STRUCTURE_TYPE_GRAPHICS_PIPELINE_CREATE_INFO,
       pNext: null(),
       flags: 0,
-      stageCount: self.shader_stages.as_ref().unwrap().len() as u32,
-      pStages: self.shader_stages.as_ref().unwrap().as_ptr(),
-      pVertexInputState: self.vertex_input_info.as_ref().unwrap(),
-      pInputAssemblyState: self.input_assembly.as_ref().unwrap(),
-      pTessellationState: null(),
+      stageCount: shader_stages.len() as u32,
+      pStages: shader_stages.as_ptr(),
+      pVertexInputState: vertex_input_info,
+      pInputAssemblyState: input_assembly,
+      pTessellationState: match &self.tessellation {
+        Some(tessellation) => tessellation,
+        None => null(),
+      },
       pViewportState: &viewport_state,
-      pRasterizationState: self.rasterizer.as_ref().unwrap(),
-      pMultisampleState: self.multisampling.as_ref().unwrap(),
-      pDepthStencilState: if self.depth_stencil.is_some() {
-        self.depth_stencil.as_ref().unwrap()
-      } else {
-        null()
+      pRasterizationState: rasterizer,
+      pMultisampleState: multisampling,
+      pDepthStencilState: match &self.depth_stencil {
+        Some(depth_stencil) => depth_stencil,
+        None => null(),
       },
       pColorBlendState: &color_blending,
-      pDynamicState: null(),
-      layout: self.pipeline_layout.unwrap(),
+      pDynamicState: if self.dynamic_states.is_empty() {
+        null()
+      } else {
+        &dynamic_state
+      },
+      layout: pipeline_layout,
       renderPass: pass,
-      subpass: 0,
+      subpass: self.subpass,
       basePipelineHandle: null(),
       basePipelineIndex: 0,
     };
-    // it's easy to error out on create graphics pipeline,
-    // so we handle it a bit better than VK_CHECK
     let mut pipeline: VkPipeline = null();
-    unsafe {
-      if vkCreateGraphicsPipelines(device, null(), 1, &pipeline_info, null(), &mut pipeline)
-        != VK_SUCCESS
-      {
-        return Err(Error::Str("Failed vkCreateGraphicsPipelines"));
-      }
+    let result = unsafe {
+      vkCreateGraphicsPipelines(
+        device,
+        self.pipeline_cache,
+        1,
+        &pipeline_info,
+        null(),
+        &mut pipeline,
+      )
+    };
+    VK_CHECK!(result, "vkCreateGraphicsPipelines")?;
+    Ok(pipeline)
+  }
+}
+
+// the state if it was set, an error naming the builder field otherwise
+fn required<'a, T>(state: &'a Option<T>, name: &'static str) -> Result<&'a T, Error> {
+  state.as_ref().ok_or(Error::PipelineMissingState(name))
+}
+
+// A compute pipeline is a single shader and a layout, see vk_compute for dispatching
+#[cfg(feature = "compute")]
+pub struct ComputePipelineBuilder {
+  shader_module: Option<VkShaderModule>,
+  pipeline_layout: Option<VkPipelineLayout>,
+  pipeline_cache: VkPipelineCache,
+}
+
+#[cfg(feature = "compute")]
+impl ComputePipelineBuilder {
+  pub fn new() -> ComputePipelineBuilder {
+    ComputePipelineBuilder {
+      shader_module: None,
+      pipeline_layout: None,
+      pipeline_cache: null(),
     }
+  }
+
+  // the module of the compute shader, its entry point is main
+  pub fn shader(&mut self, shader_module: VkShaderModule) -> &mut Self {
+    self.shader_module = Some(shader_module);
+    self
+  }
+
+  pub fn pipeline_layout(&mut self, pipeline_layout: VkPipelineLayout) -> &mut Self {
+    self.pipeline_layout = Some(pipeline_layout);
+    self
+  }
+
+  pub fn pipeline_cache(&mut self, pipeline_cache: VkPipelineCache) -> &mut Self {
+    self.pipeline_cache = pipeline_cache;
+    self
+  }
+
+  pub fn build(&self, device: VkDevice) -> Result<VkPipeline, Error> {
+    let shader_module = *required(&self.shader_module, "shader")?;
+    let pipeline_layout = *required(&self.pipeline_layout, "pipeline_layout")?;
+
+    let pipeline_info = VkComputePipelineCreateInfo {
+      sType: VK_STRUCTURE_TYPE_COMPUTE_PIPELINE_CREATE_INFO,
+      pNext: null(),
+      flags: 0,
+      stage: vkinit::pipeline_shader_stage_create_info(VK_SHADER_STAGE_COMPUTE_BIT, shader_module),
+      layout: pipeline_layout,
+      basePipelineHandle: null(),
+      basePipelineIndex: 0,
+    };
+    let mut pipeline: VkPipeline = null();
+    let result = unsafe {
+      vkCreateComputePipelines(
+        device,
+        self.pipeline_cache,
+        1,
+        &pipeline_info,
+        null(),
+        &mut pipeline,
+      )
+    };
+    VK_CHECK!(result, "vkCreateComputePipelines")?;
     Ok(pipeline)
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use {super::*, crate::vk_initializers as vkinit};
+
+  // everything build needs, the checks fail before the device is used
+  fn builder() -> PipelineBuilder {
+    let mut builder = PipelineBuilder::new();
+    builder
+      .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
+        VK_SHADER_STAGE_VERTEX_BIT,
+        null(),
+      ))
+      .vertex_input_info(vkinit::vertex_input_state_create_info(
+        None, None, None, None,
+      ))
+      .input_assembly(vkinit::input_assembly_state_create_info(
+        VK_PRIMITIVE_TOPOLOGY_TRIANGLE_LIST,
+      ))
+      .rasterizer(vkinit::rasterization_state_create_info(
+        VK_POLYGON_MODE_FILL,
+      ))
+      .multisampling(vkinit::multisampling_state_create_info())
+      .pipeline_layout(null());
+    builder
+  }
+
+  fn viewport() -> VkViewport {
+    vkinit::viewport(0.0, 0.0, 64.0, 64.0, 0.0, 1.0)
+  }
+
+  #[test]
+  fn missing_state_is_named() {
+    match PipelineBuilder::new().build(null(), null()) {
+      Err(Error::PipelineMissingState(field)) => assert_eq!(field, "shader_stages"),
+      _ => panic!("expected PipelineMissingState"),
+    }
+    match builder()
+      .scissor(vkinit::rect_2d(0, 0, 64, 64))
+      .build(null(), null())
+    {
+      Err(Error::PipelineMissingState(field)) => assert_eq!(field, "viewport"),
+      _ => panic!("expected PipelineMissingState"),
+    }
+  }
+
+  #[test]
+  fn every_viewport_needs_a_scissor() {
+    let mut two_viewports = builder();
+    two_viewports
+      .viewport(viewport())
+      .viewport(viewport())
+      .scissor(vkinit::rect_2d(0, 0, 64, 64));
+    match two_viewports.build(null(), null()) {
+      Err(Error::PipelineViewportCount(2, 1)) => {}
+      _ => panic!("expected PipelineViewportCount"),
+    }
+
+    // a dynamic viewport counts as one
+    let mut dynamic_viewport = builder();
+    dynamic_viewport
+      .dynamic_state(VK_DYNAMIC_STATE_VIEWPORT)
+      .scissor(vkinit::rect_2d(0, 0, 32, 64))
+      .scissor(vkinit::rect_2d(32, 0, 32, 64));
+    match dynamic_viewport.build(null(), null()) {
+      Err(Error::PipelineViewportCount(1, 2)) => {}
+      _ => panic!("expected PipelineViewportCount"),
+    }
+  }
+
+  #[cfg(feature = "compute")]
+  #[test]
+  fn compute_missing_state_is_named() {
+    match ComputePipelineBuilder::new().build(null()) {
+      Err(Error::PipelineMissingState(field)) => assert_eq!(field, "shader"),
+      _ => panic!("expected PipelineMissingState"),
+    }
+    match ComputePipelineBuilder::new().shader(null()).build(null()) {
+      Err(Error::PipelineMissingState(field)) => assert_eq!(field, "pipeline_layout"),
+      _ => panic!("expected PipelineMissingState"),
+    }
+  }
+
+  #[test]
+  fn tessellation_shaders_need_tessellation_state() {
+    let mut tessellated = builder();
+    tessellated
+      .push_shader_stage(vkinit::pipeline_shader_stage_create_info(
+        VK_SHADER_STAGE_TESSELLATION_EVALUATION_BIT,
+        null(),
+      ))
+      .dynamic_viewport_scissor();
+    match tessellated.build(null(), null()) {
+      Err(Error::PipelineMissingState(field)) => assert_eq!(field, "tessellation"),
+      _ => panic!("expected PipelineMissingState"),
+    }
+  }
+}
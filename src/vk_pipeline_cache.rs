@@ -0,0 +1,172 @@
+use {
+  crate::{error::Error, VK_CHECK},
+  std::{
+    ffi::c_void,
+    path::{Path, PathBuf},
+    ptr::{null, null_mut},
+  },
+  vkcapi::core::v1_0::*,
+};
+
+// The pipeline cache is kept in a file between runs, so the driver only compiles the
+// pipelines the first time. The data starts with a header saying which device and
+// driver made it. Drivers are supposed to ignore data that isn't theirs, but not all
+// of them do, so data from another GPU or driver version is dropped before it gets
+// to the driver.
+//
+// The file goes next to the executable, not into whatever directory the engine was
+// started from.
+
+// header length, header version, vendor id, device id, then the 16 byte cache UUID
+const HEADER_SIZE: usize = 32;
+
+// where a cache file with this name is kept, the working directory if the location
+// of the executable can't be found
+pub fn cache_path(filename: &str) -> PathBuf {
+  match std::env::current_exe() {
+    Ok(exe) => match exe.parent() {
+      Some(directory) => directory.join(filename),
+      None => PathBuf::from(filename),
+    },
+    Err(_) => PathBuf::from(filename),
+  }
+}
+
+pub fn create_pipeline_cache(
+  device: VkDevice,
+  properties: &VkPhysicalDeviceProperties,
+  filename: &Path,
+) -> Result<VkPipelineCache, Error> {
+  // A missing file is the first run, the cache starts out empty. So does one from
+  // another device or driver, it gets overwritten when the engine shuts down.
+  let data = match std::fs::read(filename) {
+    Ok(data) if header_matches(&data, properties) => data,
+    _ => Vec::new(),
+  };
+
+  let create_info = VkPipelineCacheCreateInfo {
+    sType: VK_STRUCTURE_TYPE_PIPELINE_CACHE_CREATE_INFO,
+    pNext: null(),
+    flags: 0,
+    initialDataSize: data.len(),
+    pInitialData: data.as_ptr() as *const c_void,
+  };
+  let mut pipeline_cache = null();
+  unsafe {
+    VK_CHECK!(vkCreatePipelineCache(
+      device,
+      &create_info,
+      null(),
+      &mut pipeline_cache
+    ))?;
+  }
+  Ok(pipeline_cache)
+}
+
+// writes everything the cache has collected, including what it was loaded with
+pub fn save_pipeline_cache(
+  device: VkDevice,
+  pipeline_cache: VkPipelineCache,
+  filename: &Path,
+) -> Result<(), Error> {
+  let mut size = 0;
+  unsafe {
+    VK_CHECK!(vkGetPipelineCacheData(
+      device,
+      pipeline_cache,
+      &mut size,
+      null_mut()
+    ))?;
+  }
+  let mut data = vec![0u8; size];
+  unsafe {
+    VK_CHECK!(vkGetPipelineCacheData(
+      device,
+      pipeline_cache,
+      &mut size,
+      data.as_mut_ptr() as *mut c_void
+    ))?;
+  }
+  data.truncate(size);
+
+  // written next to the old file first, a half written cache would be read next run
+  let mut temporary = filename.as_os_str().to_owned();
+  temporary.push(".tmp");
+  std::fs::write(&temporary, &data).map_err(|e| Error::FromIO(e))?;
+  std::fs::rename(&temporary, filename).map_err(|e| Error::FromIO(e))
+}
+
+fn header_matches(data: &[u8], properties: &VkPhysicalDeviceProperties) -> bool {
+  if data.len() < HEADER_SIZE {
+    return false;
+  }
+  let word = |offset: usize| {
+    u32::from_ne_bytes([
+      data[offset],
+      data[offset + 1],
+      data[offset + 2],
+      data[offset + 3],
+    ])
+  };
+  word(0) as usize >= HEADER_SIZE
+    && word(4) == VK_PIPELINE_CACHE_HEADER_VERSION_ONE as u32
+    && word(8) == properties.vendorID
+    && word(12) == properties.deviceID
+    && data[16..HEADER_SIZE] == properties.pipelineCacheUUID[..]
+}
+
+#[cfg(test)]
+mod tests {
+  use {super::*, std::mem::zeroed};
+
+  fn properties() -> VkPhysicalDeviceProperties {
+    let mut properties: VkPhysicalDeviceProperties = unsafe { zeroed() };
+    properties.vendorID = 0x10de;
+    properties.deviceID = 0x2484;
+    properties.pipelineCacheUUID = [7; 16];
+    properties
+  }
+
+  // the header the driver writes for properties
+  fn header(properties: &VkPhysicalDeviceProperties) -> Vec<u8> {
+    let mut data = Vec::new();
+    data.extend_from_slice(&(HEADER_SIZE as u32).to_ne_bytes());
+    data.extend_from_slice(&(VK_PIPELINE_CACHE_HEADER_VERSION_ONE as u32).to_ne_bytes());
+    data.extend_from_slice(&properties.vendorID.to_ne_bytes());
+    data.extend_from_slice(&properties.deviceID.to_ne_bytes());
+    data.extend_from_slice(&properties.pipelineCacheUUID);
+    // some cache data after it
+    data.extend_from_slice(&[1, 2, 3, 4]);
+    data
+  }
+
+  #[test]
+  fn header_of_the_same_device_matches() {
+    let properties = properties();
+    assert!(header_matches(&header(&properties), &properties));
+  }
+
+  #[test]
+  fn short_file_does_not_match() {
+    let properties = properties();
+    let data = header(&properties);
+    assert!(!header_matches(&data[..HEADER_SIZE - 1], &properties));
+    assert!(!header_matches(&[], &properties));
+  }
+
+  #[test]
+  fn other_vendor_does_not_match() {
+    let properties = properties();
+    let mut other = properties();
+    other.vendorID = 0x1002;
+    assert!(!header_matches(&header(&other), &properties));
+  }
+
+  #[test]
+  fn other_cache_uuid_does_not_match() {
+    let properties = properties();
+    let mut other = properties();
+    other.pipelineCacheUUID[15] = 8;
+    assert!(!header_matches(&header(&other), &properties));
+  }
+}
@@ -0,0 +1,1120 @@
+use {
+  crate::{
+    error::Error, vertex_layout::VertexInputDescription, vk_descriptors::DescriptorLayoutCache,
+    vk_initializers as vkinit, VK_CHECK,
+  },
+  std::{
+    collections::{BTreeMap, HashMap},
+    ptr::null,
+  },
+  vkcapi::core::v1_0::*,
+};
+
+// Reads the interface of a shader straight out of its SPIR-V: the stage, the push
+// constant block, the descriptor bindings and the stage inputs. Only the few
+// instructions that describe those are looked at, everything else is skipped by
+// its word count.
+//
+// A texture and a sampler sharing a binding (naga can't compile sampler2D) are
+// reported as a single combined image sampler, that is what the engine binds.
+//
+// naga can read SPIR-V too, but it's only pulled in with hot_reload and the .spv files
+// are reflected without it. Its module also describes the whole shader, where this
+// needs a handful of decorations and types, and a few hundred lines cover those.
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const HEADER_WORDS: usize = 5;
+
+// opcodes
+const OP_NAME: u32 = 5;
+const OP_ENTRY_POINT: u32 = 15;
+const OP_TYPE_INT: u32 = 21;
+const OP_TYPE_FLOAT: u32 = 22;
+const OP_TYPE_VECTOR: u32 = 23;
+const OP_TYPE_MATRIX: u32 = 24;
+const OP_TYPE_IMAGE: u32 = 25;
+const OP_TYPE_SAMPLER: u32 = 26;
+const OP_TYPE_SAMPLED_IMAGE: u32 = 27;
+const OP_TYPE_ARRAY: u32 = 28;
+const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+const OP_TYPE_STRUCT: u32 = 30;
+const OP_TYPE_POINTER: u32 = 32;
+const OP_CONSTANT: u32 = 43;
+const OP_VARIABLE: u32 = 59;
+const OP_DECORATE: u32 = 71;
+const OP_MEMBER_DECORATE: u32 = 72;
+
+// decorations
+const DECORATION_BUFFER_BLOCK: u32 = 3;
+const DECORATION_ARRAY_STRIDE: u32 = 6;
+const DECORATION_MATRIX_STRIDE: u32 = 7;
+const DECORATION_BUILT_IN: u32 = 11;
+const DECORATION_LOCATION: u32 = 30;
+const DECORATION_BINDING: u32 = 33;
+const DECORATION_DESCRIPTOR_SET: u32 = 34;
+const DECORATION_OFFSET: u32 = 35;
+
+// storage classes
+const STORAGE_UNIFORM_CONSTANT: u32 = 0;
+const STORAGE_INPUT: u32 = 1;
+const STORAGE_UNIFORM: u32 = 2;
+const STORAGE_PUSH_CONSTANT: u32 = 9;
+const STORAGE_STORAGE_BUFFER: u32 = 12;
+
+// image dimensions that change the descriptor type
+const DIM_BUFFER: u32 = 5;
+const DIM_SUBPASS_DATA: u32 = 6;
+
+// how a value is read, a vertex attribute format has to give the shader the same
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NumericType {
+  Float,
+  Sint,
+  Uint,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReflectedBinding {
+  pub set: u32,
+  pub binding: u32,
+  pub descriptor_type: VkDescriptorType,
+  pub count: u32,
+  pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ReflectedInput {
+  pub location: u32,
+  pub numeric_type: NumericType,
+  pub format: VkFormat,
+  pub name: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderReflection {
+  pub name: String, // the file, for error messages
+  pub stage: VkShaderStageFlags,
+  pub push_constants: Option<(u32, u32)>, // offset and size of the push constant block
+  pub bindings: Vec<ReflectedBinding>,
+  pub inputs: Vec<ReflectedInput>,
+}
+
+// the types the interface is built from, the others aren't recorded
+enum SpirvType {
+  Scalar(NumericType, u32), // width in bits
+  Vector(u32, u32),         // component type, count
+  Matrix(u32, u32),         // column type, count
+  Image(u32, u32),          // dimension, sampled (1 sampled, 2 storage)
+  Sampler,
+  SampledImage,
+  Array(u32, Option<u32>), // element type, id of the length constant
+  Struct(Vec<u32>),
+  Pointer(u32, u32), // storage class, pointee
+}
+
+#[derive(Default)]
+struct Decorations {
+  location: Option<u32>,
+  binding: Option<u32>,
+  set: Option<u32>,
+  array_stride: Option<u32>,
+  built_in: bool,
+  buffer_block: bool,
+}
+
+// what the first pass over the instructions collects
+struct Module<'a> {
+  name: &'a str,
+  types: HashMap<u32, SpirvType>,
+  constants: HashMap<u32, u32>,
+  names: HashMap<u32, String>,
+  decorations: HashMap<u32, Decorations>,
+  member_offsets: HashMap<(u32, u32), u32>,
+  member_matrix_strides: HashMap<(u32, u32), u32>,
+  variables: Vec<(u32, u32, u32)>, // id, pointer type, storage class
+  execution_model: Option<u32>,
+}
+
+impl ShaderReflection {
+  // name is only used to say which shader an error is about
+  pub fn reflect(name: &str, code: &[u32]) -> Result<ShaderReflection, Error> {
+    let module = Module::parse(name, code)?;
+
+    let stage = match module.execution_model {
+      Some(0) => VK_SHADER_STAGE_VERTEX_BIT,
+      Some(1) => VK_SHADER_STAGE_TESSELLATION_CONTROL_BIT,
+      Some(2) => VK_SHADER_STAGE_TESSELLATION_EVALUATION_BIT,
+      Some(3) => VK_SHADER_STAGE_GEOMETRY_BIT,
+      Some(4) => VK_SHADER_STAGE_FRAGMENT_BIT,
+      Some(5) => VK_SHADER_STAGE_COMPUTE_BIT,
+      Some(model) => return Err(module.error(format!("unsupported execution model {}", model))),
+      None => return Err(module.error("no entry point".to_string())),
+    };
+
+    let mut reflection = ShaderReflection {
+      name: name.to_string(),
+      stage,
+      push_constants: None,
+      bindings: Vec::new(),
+      inputs: Vec::new(),
+    };
+
+    for &(id, pointer_type, storage_class) in &module.variables {
+      let pointee = match module.types.get(&pointer_type) {
+        Some(SpirvType::Pointer(_, pointee)) => *pointee,
+        _ => return Err(module.error(format!("variable %{} is not a pointer", id))),
+      };
+      let decorations = module.decorations.get(&id);
+      let variable_name = module.names.get(&id).cloned().unwrap_or_default();
+
+      match storage_class {
+        STORAGE_PUSH_CONSTANT => {
+          reflection.push_constants = Some(module.block_range(pointee)?);
+        }
+        STORAGE_UNIFORM | STORAGE_UNIFORM_CONSTANT | STORAGE_STORAGE_BUFFER => {
+          let (set, binding) = match decorations {
+            Some(Decorations {
+              set: Some(set),
+              binding: Some(binding),
+              ..
+            }) => (*set, *binding),
+            _ => continue,
+          };
+          let (element, count) = module.array_element(pointee);
+          let descriptor_type = match module.descriptor_type(storage_class, element) {
+            Some(descriptor_type) => descriptor_type,
+            None => continue,
+          };
+          reflection.push_binding(ReflectedBinding {
+            set,
+            binding,
+            descriptor_type,
+            count,
+            name: variable_name,
+          });
+        }
+        STORAGE_INPUT => {
+          let location = match decorations {
+            Some(Decorations {
+              built_in: false,
+              location: Some(location),
+              ..
+            }) => *location,
+            _ => continue,
+          };
+          module.push_inputs(&mut reflection.inputs, pointee, location, &variable_name)?;
+        }
+        _ => {}
+      }
+    }
+
+    reflection.bindings.sort_by_key(|b| (b.set, b.binding));
+    reflection.inputs.sort_by_key(|i| i.location);
+    Ok(reflection)
+  }
+
+  // Checks that every input of a vertex shader gets an attribute and that the
+  // attribute is read as the same kind of number. The component counts may differ,
+  // Vulkan fills in or drops components as needed.
+  pub fn check_vertex_input(&self, description: &VertexInputDescription) -> Result<(), Error> {
+    if self.stage != VK_SHADER_STAGE_VERTEX_BIT {
+      return Ok(());
+    }
+
+    for input in &self.inputs {
+      let attribute = match description
+        .attributes
+        .iter()
+        .find(|a| a.location == input.location)
+      {
+        Some(attribute) => attribute,
+        None => {
+          return Err(Error::VertexInputMismatch(
+            self.name.clone(),
+            input.location,
+            format!("{}has no vertex attribute", named(&input.name)),
+          ))
+        }
+      };
+      match format_numeric_type(attribute.format) {
+        Some(numeric_type) if numeric_type != input.numeric_type => {
+          return Err(Error::VertexInputMismatch(
+            self.name.clone(),
+            input.location,
+            format!(
+              "{}is read as {:?} like format {} but the attribute format {} gives {:?}",
+              named(&input.name),
+              input.numeric_type,
+              input.format,
+              attribute.format,
+              numeric_type
+            ),
+          ))
+        }
+        _ => {}
+      }
+    }
+    Ok(())
+  }
+
+  fn push_binding(&mut self, binding: ReflectedBinding) {
+    // the separate texture and sampler of a combined image sampler
+    if let Some(other) = self
+      .bindings
+      .iter_mut()
+      .find(|b| b.set == binding.set && b.binding == binding.binding)
+    {
+      match (other.descriptor_type, binding.descriptor_type) {
+        (VK_DESCRIPTOR_TYPE_SAMPLED_IMAGE, VK_DESCRIPTOR_TYPE_SAMPLER) => {}
+        (VK_DESCRIPTOR_TYPE_SAMPLER, VK_DESCRIPTOR_TYPE_SAMPLED_IMAGE) => {
+          other.name = binding.name;
+        }
+        // let the layout builder complain about anything else
+        _ => {
+          self.bindings.push(binding);
+          return;
+        }
+      }
+      other.descriptor_type = VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER;
+      return;
+    }
+    self.bindings.push(binding);
+  }
+}
+
+impl<'a> Module<'a> {
+  fn parse(name: &'a str, code: &[u32]) -> Result<Module<'a>, Error> {
+    let mut module = Module {
+      name,
+      types: HashMap::new(),
+      constants: HashMap::new(),
+      names: HashMap::new(),
+      decorations: HashMap::new(),
+      member_offsets: HashMap::new(),
+      member_matrix_strides: HashMap::new(),
+      variables: Vec::new(),
+      execution_model: None,
+    };
+
+    if code.len() < HEADER_WORDS || code[0] != SPIRV_MAGIC {
+      return Err(module.error("not SPIR-V, the magic number is missing".to_string()));
+    }
+
+    let mut position = HEADER_WORDS;
+    while position < code.len() {
+      let word_count = (code[position] >> 16) as usize;
+      let opcode = code[position] & 0xffff;
+      if word_count == 0 || position + word_count > code.len() {
+        return Err(module.error(format!("truncated instruction at word {}", position)));
+      }
+      let operands = &code[position + 1..position + word_count];
+      position += word_count;
+
+      // OpTypeSampler is the only instruction we read that has a single operand
+      if opcode == OP_TYPE_SAMPLER && operands.len() == 1 {
+        module.types.insert(operands[0], SpirvType::Sampler);
+        continue;
+      }
+      if operands.len() < 2 {
+        continue;
+      }
+      match opcode {
+        OP_NAME => {
+          module
+            .names
+            .insert(operands[0], literal_string(&operands[1..]));
+        }
+        OP_ENTRY_POINT => {
+          // the first entry point is the shader, glslc and naga only write one
+          if module.execution_model.is_none() {
+            module.execution_model = Some(operands[0]);
+          }
+        }
+        OP_TYPE_INT if operands.len() >= 3 => {
+          let numeric_type = if operands[2] == 0 {
+            NumericType::Uint
+          } else {
+            NumericType::Sint
+          };
+          module
+            .types
+            .insert(operands[0], SpirvType::Scalar(numeric_type, operands[1]));
+        }
+        OP_TYPE_FLOAT => {
+          module.types.insert(
+            operands[0],
+            SpirvType::Scalar(NumericType::Float, operands[1]),
+          );
+        }
+        OP_TYPE_VECTOR if operands.len() >= 3 => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Vector(operands[1], operands[2]));
+        }
+        OP_TYPE_MATRIX if operands.len() >= 3 => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Matrix(operands[1], operands[2]));
+        }
+        OP_TYPE_IMAGE if operands.len() >= 7 => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Image(operands[2], operands[6]));
+        }
+        OP_TYPE_SAMPLED_IMAGE => {
+          module.types.insert(operands[0], SpirvType::SampledImage);
+        }
+        OP_TYPE_ARRAY if operands.len() >= 3 => {
+          module.types.insert(
+            operands[0],
+            SpirvType::Array(operands[1], Some(operands[2])),
+          );
+        }
+        OP_TYPE_RUNTIME_ARRAY => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Array(operands[1], None));
+        }
+        OP_TYPE_STRUCT => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Struct(operands[1..].to_vec()));
+        }
+        OP_TYPE_POINTER if operands.len() >= 3 => {
+          module
+            .types
+            .insert(operands[0], SpirvType::Pointer(operands[1], operands[2]));
+        }
+        OP_CONSTANT if operands.len() >= 3 => {
+          module.constants.insert(operands[1], operands[2]);
+        }
+        OP_VARIABLE if operands.len() >= 3 => {
+          module
+            .variables
+            .push((operands[1], operands[0], operands[2]));
+        }
+        OP_DECORATE => {
+          let value = operands.get(2).copied();
+          let decorations = module.decorations.entry(operands[0]).or_default();
+          match operands[1] {
+            DECORATION_BUFFER_BLOCK => decorations.buffer_block = true,
+            DECORATION_ARRAY_STRIDE => decorations.array_stride = value,
+            DECORATION_BUILT_IN => decorations.built_in = true,
+            DECORATION_LOCATION => decorations.location = value,
+            DECORATION_BINDING => decorations.binding = value,
+            DECORATION_DESCRIPTOR_SET => decorations.set = value,
+            _ => {}
+          }
+        }
+        OP_MEMBER_DECORATE if operands.len() >= 4 => match operands[2] {
+          DECORATION_OFFSET => {
+            module
+              .member_offsets
+              .insert((operands[0], operands[1]), operands[3]);
+          }
+          DECORATION_MATRIX_STRIDE => {
+            module
+              .member_matrix_strides
+              .insert((operands[0], operands[1]), operands[3]);
+          }
+          _ => {}
+        },
+        _ => {}
+      }
+    }
+    Ok(module)
+  }
+
+  fn error(&self, message: String) -> Error {
+    Error::InvalidSpirv(self.name.to_string(), message)
+  }
+
+  // the element type and the number of descriptors of a possibly arrayed binding
+  fn array_element(&self, type_id: u32) -> (u32, u32) {
+    match self.types.get(&type_id) {
+      Some(SpirvType::Array(element, Some(length))) => {
+        (*element, self.constants.get(length).copied().unwrap_or(1))
+      }
+      // runtime arrays need descriptor indexing, count them as one
+      Some(SpirvType::Array(element, None)) => (*element, 1),
+      _ => (type_id, 1),
+    }
+  }
+
+  fn descriptor_type(&self, storage_class: u32, type_id: u32) -> Option<VkDescriptorType> {
+    match (storage_class, self.types.get(&type_id)?) {
+      (STORAGE_STORAGE_BUFFER, _) => Some(VK_DESCRIPTOR_TYPE_STORAGE_BUFFER),
+      // old style storage buffers are uniforms with BufferBlock
+      (STORAGE_UNIFORM, _) => match self.decorations.get(&type_id) {
+        Some(decorations) if decorations.buffer_block => Some(VK_DESCRIPTOR_TYPE_STORAGE_BUFFER),
+        _ => Some(VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER),
+      },
+      (_, SpirvType::Sampler) => Some(VK_DESCRIPTOR_TYPE_SAMPLER),
+      (_, SpirvType::SampledImage) => Some(VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER),
+      (_, SpirvType::Image(DIM_SUBPASS_DATA, _)) => Some(VK_DESCRIPTOR_TYPE_INPUT_ATTACHMENT),
+      (_, SpirvType::Image(DIM_BUFFER, 2)) => Some(VK_DESCRIPTOR_TYPE_STORAGE_TEXEL_BUFFER),
+      (_, SpirvType::Image(DIM_BUFFER, _)) => Some(VK_DESCRIPTOR_TYPE_UNIFORM_TEXEL_BUFFER),
+      (_, SpirvType::Image(_, 2)) => Some(VK_DESCRIPTOR_TYPE_STORAGE_IMAGE),
+      (_, SpirvType::Image(..)) => Some(VK_DESCRIPTOR_TYPE_SAMPLED_IMAGE),
+      _ => None,
+    }
+  }
+
+  // the bytes of a push constant block that are used, from the first member to the
+  // end of the last one
+  fn block_range(&self, type_id: u32) -> Result<(u32, u32), Error> {
+    let members = match self.types.get(&type_id) {
+      Some(SpirvType::Struct(members)) => members,
+      _ => return Err(self.error("the push constant block is not a struct".to_string())),
+    };
+    let mut start = u32::MAX;
+    let mut end = 0;
+    for (index, member) in members.iter().enumerate() {
+      let offset = self
+        .member_offsets
+        .get(&(type_id, index as u32))
+        .copied()
+        .unwrap_or(0);
+      let stride = self
+        .member_matrix_strides
+        .get(&(type_id, index as u32))
+        .copied();
+      start = u32::min(start, offset);
+      end = u32::max(end, offset + self.type_size(*member, stride)?);
+    }
+    if members.is_empty() {
+      start = 0;
+    }
+    Ok((start, end - start))
+  }
+
+  // size in bytes with the offsets and strides the shader was compiled with
+  fn type_size(&self, type_id: u32, matrix_stride: Option<u32>) -> Result<u32, Error> {
+    Ok(match self.types.get(&type_id) {
+      Some(SpirvType::Scalar(_, width)) => width / 8,
+      Some(SpirvType::Vector(component, count)) => count * self.type_size(*component, None)?,
+      Some(SpirvType::Matrix(column, count)) => match matrix_stride {
+        Some(stride) => count * stride,
+        None => count * self.type_size(*column, None)?,
+      },
+      Some(SpirvType::Array(element, Some(length))) => {
+        let length = self.constants.get(length).copied().unwrap_or(1);
+        let stride = match self.decorations.get(&type_id).and_then(|d| d.array_stride) {
+          Some(stride) => stride,
+          None => self.type_size(*element, matrix_stride)?,
+        };
+        length * stride
+      }
+      Some(SpirvType::Struct(_)) => {
+        let (start, size) = self.block_range(type_id)?;
+        start + size
+      }
+      _ => {
+        return Err(self.error(format!(
+          "can't work out the size of type %{} in a block",
+          type_id
+        )))
+      }
+    })
+  }
+
+  // a matrix or array input takes a location per column or element
+  fn push_inputs(
+    &self,
+    inputs: &mut Vec<ReflectedInput>,
+    type_id: u32,
+    location: u32,
+    name: &str,
+  ) -> Result<u32, Error> {
+    let (numeric_type, width, count) = match self.types.get(&type_id) {
+      Some(SpirvType::Scalar(numeric_type, width)) => (*numeric_type, *width, 1),
+      Some(SpirvType::Vector(component, count)) => match self.types.get(component) {
+        Some(SpirvType::Scalar(numeric_type, width)) => (*numeric_type, *width, *count),
+        _ => return Err(self.error(format!("input {} has an unknown component type", name))),
+      },
+      Some(SpirvType::Matrix(column, columns)) => {
+        let mut next = location;
+        for _ in 0..*columns {
+          next = self.push_inputs(inputs, *column, next, name)?;
+        }
+        return Ok(next);
+      }
+      Some(SpirvType::Array(element, Some(_))) => {
+        let (_, length) = self.array_element(type_id);
+        let mut next = location;
+        for _ in 0..length {
+          next = self.push_inputs(inputs, *element, next, name)?;
+        }
+        return Ok(next);
+      }
+      // interface blocks between stages, nothing a vertex attribute could feed
+      _ => return Ok(location + 1),
+    };
+
+    inputs.push(ReflectedInput {
+      location,
+      numeric_type,
+      format: input_format(numeric_type, width, count),
+      name: name.to_string(),
+    });
+    // 64 bit vectors with more than 2 components take two locations
+    Ok(location + if width == 64 && count > 2 { 2 } else { 1 })
+  }
+}
+
+// the variable name for error messages, if the shader kept its debug names
+fn named(name: &str) -> String {
+  if name.is_empty() {
+    String::new()
+  } else {
+    format!("({}) ", name)
+  }
+}
+
+// a nul terminated string packed 4 bytes to a word
+fn literal_string(words: &[u32]) -> String {
+  let bytes: Vec<u8> = words
+    .iter()
+    .flat_map(|word| word.to_le_bytes())
+    .take_while(|byte| *byte != 0)
+    .collect();
+  String::from_utf8_lossy(&bytes).into_owned()
+}
+
+// the format of a vertex attribute that gives the shader exactly what it reads
+fn input_format(numeric_type: NumericType, width: u32, count: u32) -> VkFormat {
+  let formats = match (numeric_type, width) {
+    (NumericType::Float, 32) => [
+      VK_FORMAT_R32_SFLOAT,
+      VK_FORMAT_R32G32_SFLOAT,
+      VK_FORMAT_R32G32B32_SFLOAT,
+      VK_FORMAT_R32G32B32A32_SFLOAT,
+    ],
+    (NumericType::Float, 16) => [
+      VK_FORMAT_R16_SFLOAT,
+      VK_FORMAT_R16G16_SFLOAT,
+      VK_FORMAT_R16G16B16_SFLOAT,
+      VK_FORMAT_R16G16B16A16_SFLOAT,
+    ],
+    (NumericType::Float, 64) => [
+      VK_FORMAT_R64_SFLOAT,
+      VK_FORMAT_R64G64_SFLOAT,
+      VK_FORMAT_R64G64B64_SFLOAT,
+      VK_FORMAT_R64G64B64A64_SFLOAT,
+    ],
+    (NumericType::Sint, 32) => [
+      VK_FORMAT_R32_SINT,
+      VK_FORMAT_R32G32_SINT,
+      VK_FORMAT_R32G32B32_SINT,
+      VK_FORMAT_R32G32B32A32_SINT,
+    ],
+    (NumericType::Uint, 32) => [
+      VK_FORMAT_R32_UINT,
+      VK_FORMAT_R32G32_UINT,
+      VK_FORMAT_R32G32B32_UINT,
+      VK_FORMAT_R32G32B32A32_UINT,
+    ],
+    _ => return VK_FORMAT_UNDEFINED,
+  };
+  match count {
+    1..=4 => formats[count as usize - 1],
+    _ => VK_FORMAT_UNDEFINED,
+  }
+}
+
+// How the shader sees an attribute of this format. Normalized and scaled formats
+// are read as floats. None for formats that can't be vertex attributes.
+pub fn format_numeric_type(format: VkFormat) -> Option<NumericType> {
+  match format {
+    VK_FORMAT_R8_UNORM
+    | VK_FORMAT_R8_SNORM
+    | VK_FORMAT_R8_USCALED
+    | VK_FORMAT_R8_SSCALED
+    | VK_FORMAT_R8G8_UNORM
+    | VK_FORMAT_R8G8_SNORM
+    | VK_FORMAT_R8G8_USCALED
+    | VK_FORMAT_R8G8_SSCALED
+    | VK_FORMAT_R8G8B8A8_UNORM
+    | VK_FORMAT_R8G8B8A8_SNORM
+    | VK_FORMAT_R8G8B8A8_USCALED
+    | VK_FORMAT_R8G8B8A8_SSCALED
+    | VK_FORMAT_R8G8B8A8_SRGB
+    | VK_FORMAT_B8G8R8A8_UNORM
+    | VK_FORMAT_A2B10G10R10_UNORM_PACK32
+    | VK_FORMAT_A2B10G10R10_SNORM_PACK32
+    | VK_FORMAT_R16_UNORM
+    | VK_FORMAT_R16_SNORM
+    | VK_FORMAT_R16_SFLOAT
+    | VK_FORMAT_R16G16_UNORM
+    | VK_FORMAT_R16G16_SNORM
+    | VK_FORMAT_R16G16_SFLOAT
+    | VK_FORMAT_R16G16B16A16_UNORM
+    | VK_FORMAT_R16G16B16A16_SNORM
+    | VK_FORMAT_R16G16B16A16_SFLOAT
+    | VK_FORMAT_R32_SFLOAT
+    | VK_FORMAT_R32G32_SFLOAT
+    | VK_FORMAT_R32G32B32_SFLOAT
+    | VK_FORMAT_R32G32B32A32_SFLOAT
+    | VK_FORMAT_R64_SFLOAT
+    | VK_FORMAT_R64G64_SFLOAT
+    | VK_FORMAT_R64G64B64_SFLOAT
+    | VK_FORMAT_R64G64B64A64_SFLOAT => Some(NumericType::Float),
+    VK_FORMAT_R8_SINT
+    | VK_FORMAT_R8G8_SINT
+    | VK_FORMAT_R8G8B8A8_SINT
+    | VK_FORMAT_R16_SINT
+    | VK_FORMAT_R16G16_SINT
+    | VK_FORMAT_R16G16B16A16_SINT
+    | VK_FORMAT_R32_SINT
+    | VK_FORMAT_R32G32_SINT
+    | VK_FORMAT_R32G32B32_SINT
+    | VK_FORMAT_R32G32B32A32_SINT => Some(NumericType::Sint),
+    VK_FORMAT_R8_UINT
+    | VK_FORMAT_R8G8_UINT
+    | VK_FORMAT_R8G8B8A8_UINT
+    | VK_FORMAT_R16_UINT
+    | VK_FORMAT_R16G16_UINT
+    | VK_FORMAT_R16G16B16A16_UINT
+    | VK_FORMAT_R32_UINT
+    | VK_FORMAT_R32G32_UINT
+    | VK_FORMAT_R32G32B32_UINT
+    | VK_FORMAT_R32G32B32A32_UINT => Some(NumericType::Uint),
+    _ => None,
+  }
+}
+
+// Builds a pipeline layout from the reflection of its shaders. The bindings of all
+// stages are merged, a binding used by two stages gets both stage flags. Sets the
+// engine already has a layout for are given with set_layout, the shaders are checked
+// against that layout instead of making a new one, so sets allocated with it can be
+// bound. Every other set gets its layout from the cache.
+pub struct PipelineLayoutBuilder {
+  shaders: Vec<ShaderReflection>,
+  set_layouts: HashMap<u32, VkDescriptorSetLayout>,
+}
+
+// a binding merged from all shaders and the first shader that declared it
+struct MergedBinding {
+  descriptor_type: VkDescriptorType,
+  count: u32,
+  stages: VkShaderStageFlags,
+  shader: String,
+  name: String,
+}
+
+impl PipelineLayoutBuilder {
+  pub fn new() -> PipelineLayoutBuilder {
+    PipelineLayoutBuilder {
+      shaders: Vec::new(),
+      set_layouts: HashMap::new(),
+    }
+  }
+
+  pub fn shader(&mut self, reflection: &ShaderReflection) -> &mut Self {
+    self.shaders.push(reflection.clone());
+    self
+  }
+
+  pub fn set_layout(&mut self, set: u32, layout: VkDescriptorSetLayout) -> &mut Self {
+    self.set_layouts.insert(set, layout);
+    self
+  }
+
+  pub fn build(
+    &self,
+    device: VkDevice,
+    layout_cache: &mut DescriptorLayoutCache,
+  ) -> Result<VkPipelineLayout, Error> {
+    let sets = self.merge_bindings()?;
+
+    // set layouts have to be given for every set up to the last one used
+    let set_count = sets
+      .keys()
+      .chain(self.set_layouts.keys())
+      .map(|set| set + 1)
+      .max()
+      .unwrap_or(0);
+    let mut set_layouts = Vec::with_capacity(set_count as usize);
+    let empty = BTreeMap::new();
+    for set in 0..set_count {
+      let bindings = sets.get(&set).unwrap_or(&empty);
+      let layout = match self.set_layouts.get(&set) {
+        Some(layout) => {
+          self.check_set_layout(set, *layout, bindings, layout_cache)?;
+          *layout
+        }
+        None => {
+          let layout_bindings: Vec<VkDescriptorSetLayoutBinding> = bindings
+            .iter()
+            .map(|(binding, merged)| VkDescriptorSetLayoutBinding {
+              binding: *binding,
+              descriptorType: merged.descriptor_type,
+              descriptorCount: merged.count,
+              stageFlags: merged.stages,
+              pImmutableSamplers: null(),
+            })
+            .collect();
+          layout_cache.create_descriptor_layout(&layout_bindings)?
+        }
+      };
+      set_layouts.push(layout);
+    }
+
+    let push_constant_ranges = self.push_constant_ranges();
+
+    let mut layout_info = vkinit::pipeline_layout_create_info();
+    layout_info.setLayoutCount = set_layouts.len() as u32;
+    layout_info.pSetLayouts = set_layouts.as_ptr();
+    layout_info.pushConstantRangeCount = push_constant_ranges.len() as u32;
+    layout_info.pPushConstantRanges = push_constant_ranges.as_ptr();
+    let mut layout = null();
+    unsafe {
+      VK_CHECK!(vkCreatePipelineLayout(
+        device,
+        &layout_info,
+        null(),
+        &mut layout
+      ))?;
+    }
+    Ok(layout)
+  }
+
+  fn merge_bindings(&self) -> Result<BTreeMap<u32, BTreeMap<u32, MergedBinding>>, Error> {
+    let mut sets: BTreeMap<u32, BTreeMap<u32, MergedBinding>> = BTreeMap::new();
+    for shader in &self.shaders {
+      for reflected in &shader.bindings {
+        let descriptor_type = reflected.descriptor_type;
+        let bindings = sets.entry(reflected.set).or_default();
+        match bindings.get_mut(&reflected.binding) {
+          Some(merged) => {
+            if merged.descriptor_type != descriptor_type {
+              return Err(Error::PipelineLayoutMismatch(
+                shader.name.clone(),
+                reflected.set,
+                reflected.binding,
+                format!(
+                  "{}is descriptor type {} but {} has descriptor type {}",
+                  named(&reflected.name),
+                  descriptor_type,
+                  merged.shader,
+                  merged.descriptor_type
+                ),
+              ));
+            }
+            merged.count = u32::max(merged.count, reflected.count);
+            merged.stages |= shader.stage;
+          }
+          None => {
+            bindings.insert(
+              reflected.binding,
+              MergedBinding {
+                descriptor_type,
+                count: reflected.count,
+                stages: shader.stage,
+                shader: shader.name.clone(),
+                name: reflected.name.clone(),
+              },
+            );
+          }
+        }
+      }
+    }
+    Ok(sets)
+  }
+
+  // the given layout has to have every binding the shaders use, visible to them
+  fn check_set_layout(
+    &self,
+    set: u32,
+    layout: VkDescriptorSetLayout,
+    bindings: &BTreeMap<u32, MergedBinding>,
+    layout_cache: &DescriptorLayoutCache,
+  ) -> Result<(), Error> {
+    // a layout that didn't come from the cache can't be looked into
+    let layout_bindings = match layout_cache.layout_bindings(layout) {
+      Some(layout_bindings) => layout_bindings,
+      None => return Err(Error::PipelineSetLayoutUnknown(set)),
+    };
+
+    for (binding, merged) in bindings {
+      let mismatch = |reason: String| {
+        Err(Error::PipelineLayoutMismatch(
+          merged.shader.clone(),
+          set,
+          *binding,
+          format!("{}{}", named(&merged.name), reason),
+        ))
+      };
+      let (_, descriptor_type, count, stages) =
+        match layout_bindings.iter().find(|(b, ..)| b == binding) {
+          Some(layout_binding) => *layout_binding,
+          None => return mismatch("is not in the set layout".to_string()),
+        };
+      if !descriptor_types_compatible(merged.descriptor_type, descriptor_type) {
+        return mismatch(format!(
+          "is descriptor type {} but the set layout has {}",
+          merged.descriptor_type, descriptor_type
+        ));
+      }
+      if count < merged.count {
+        return mismatch(format!(
+          "has {} descriptors but the set layout only {}",
+          merged.count, count
+        ));
+      }
+      if stages & merged.stages != merged.stages {
+        return mismatch(format!(
+          "is used by stages {:#x} but the set layout is only visible to {:#x}",
+          merged.stages, stages
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  // the stages of the range that starts at offset 0, the ones to push constants at 0
+  // with. 0 if none of the shaders has a push constant block there
+  pub fn push_constant_stages(&self) -> VkShaderStageFlags {
+    self
+      .push_constant_ranges()
+      .iter()
+      .find(|range| range.offset == 0)
+      .map_or(0, |range| range.stageFlags)
+  }
+
+  // Blocks that overlap get one range covering both, for all their stages. Vulkan
+  // allows a stage in only one range, so ranges of the same stage are joined too.
+  fn push_constant_ranges(&self) -> Vec<VkPushConstantRange> {
+    let mut ranges: Vec<VkPushConstantRange> = Vec::new();
+    for shader in &self.shaders {
+      if let Some((offset, size)) = shader.push_constants {
+        let mut merged = VkPushConstantRange {
+          stageFlags: shader.stage,
+          offset,
+          size,
+        };
+        // growing the range can make it reach ranges it didn't overlap before
+        while let Some(i) = ranges
+          .iter()
+          .position(|range| overlaps(range, &merged) || range.stageFlags & merged.stageFlags != 0)
+        {
+          let range = ranges.swap_remove(i);
+          let end = u32::max(range.offset + range.size, merged.offset + merged.size);
+          merged.offset = u32::min(range.offset, merged.offset);
+          merged.size = end - merged.offset;
+          merged.stageFlags |= range.stageFlags;
+        }
+        ranges.push(merged);
+      }
+    }
+    ranges
+  }
+}
+
+fn overlaps(a: &VkPushConstantRange, b: &VkPushConstantRange) -> bool {
+  a.offset < b.offset + b.size && b.offset < a.offset + a.size
+}
+
+// the shader only sees a buffer, whether its offset is dynamic is up to the layout
+fn descriptor_types_compatible(shader: VkDescriptorType, layout: VkDescriptorType) -> bool {
+  shader == layout
+    || (shader == VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER
+      && layout == VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER_DYNAMIC)
+    || (shader == VK_DESCRIPTOR_TYPE_STORAGE_BUFFER
+      && layout == VK_DESCRIPTOR_TYPE_STORAGE_BUFFER_DYNAMIC)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn reflect_shader(name: &str) -> ShaderReflection {
+    let bytes = std::fs::read(format!("shaders/{}.spv", name)).unwrap();
+    let code: Vec<u32> = bytes
+      .chunks_exact(4)
+      .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+      .collect();
+    ShaderReflection::reflect(name, &code).unwrap()
+  }
+
+  fn with_push_constants(stage: VkShaderStageFlags, offset: u32, size: u32) -> ShaderReflection {
+    ShaderReflection {
+      name: String::new(),
+      stage,
+      push_constants: Some((offset, size)),
+      bindings: Vec::new(),
+      inputs: Vec::new(),
+    }
+  }
+
+  fn attribute(location: u32, format: VkFormat) -> VkVertexInputAttributeDescription {
+    VkVertexInputAttributeDescription {
+      location,
+      binding: 0,
+      format,
+      offset: 0,
+    }
+  }
+
+  #[test]
+  fn tri_mesh_vertex_shader() {
+    let reflection = reflect_shader("tri_mesh.vert");
+    assert_eq!(reflection.stage, VK_SHADER_STAGE_VERTEX_BIT);
+
+    let inputs: Vec<(u32, NumericType, VkFormat)> = reflection
+      .inputs
+      .iter()
+      .map(|input| (input.location, input.numeric_type, input.format))
+      .collect();
+    assert_eq!(
+      inputs,
+      vec![
+        (0, NumericType::Float, VK_FORMAT_R32G32B32_SFLOAT),
+        (1, NumericType::Float, VK_FORMAT_R32G32B32_SFLOAT),
+        (2, NumericType::Float, VK_FORMAT_R32G32B32_SFLOAT),
+        (3, NumericType::Float, VK_FORMAT_R32G32_SFLOAT),
+      ]
+    );
+
+    assert_eq!(reflection.bindings.len(), 1);
+    let camera = &reflection.bindings[0];
+    assert_eq!((camera.set, camera.binding, camera.count), (0, 0, 1));
+    assert_eq!(camera.descriptor_type, VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER);
+
+    // a vec4 and a mat4
+    assert_eq!(reflection.push_constants, Some((0, 80)));
+  }
+
+  #[test]
+  fn default_lit_fragment_shader() {
+    let reflection = reflect_shader("default_lit.frag");
+    assert_eq!(reflection.stage, VK_SHADER_STAGE_FRAGMENT_BIT);
+    assert_eq!(reflection.push_constants, None);
+
+    assert_eq!(reflection.inputs.len(), 1);
+    assert_eq!(reflection.inputs[0].location, 0);
+    assert_eq!(reflection.inputs[0].format, VK_FORMAT_R32G32B32_SFLOAT);
+
+    assert_eq!(reflection.bindings.len(), 1);
+    let scene = &reflection.bindings[0];
+    assert_eq!((scene.set, scene.binding), (0, 1));
+    assert_eq!(scene.descriptor_type, VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER);
+  }
+
+  #[test]
+  fn textured_lit_fragment_shader() {
+    let reflection = reflect_shader("textured_lit.frag");
+    let bindings: Vec<(u32, u32, VkDescriptorType)> = reflection
+      .bindings
+      .iter()
+      .map(|binding| (binding.set, binding.binding, binding.descriptor_type))
+      .collect();
+    assert_eq!(
+      bindings,
+      vec![
+        (0, 1, VK_DESCRIPTOR_TYPE_UNIFORM_BUFFER),
+        (1, 0, VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER),
+      ]
+    );
+    let locations: Vec<u32> = reflection.inputs.iter().map(|i| i.location).collect();
+    assert_eq!(locations, vec![0, 1]);
+  }
+
+  #[test]
+  fn builtin_inputs_are_not_vertex_attributes() {
+    // colored_triangle.vert only reads gl_VertexIndex
+    let reflection = reflect_shader("colored_triangle.vert");
+    assert!(reflection.inputs.is_empty());
+    assert!(reflection.bindings.is_empty());
+    assert_eq!(reflection.push_constants, None);
+    assert!(reflection
+      .check_vertex_input(&VertexInputDescription::new())
+      .is_ok());
+  }
+
+  #[test]
+  fn not_spirv_is_invalid() {
+    for code in &[Vec::new(), vec![0u32; HEADER_WORDS]] {
+      match ShaderReflection::reflect("empty", code) {
+        Err(Error::InvalidSpirv(..)) => {}
+        _ => panic!("expected InvalidSpirv"),
+      }
+    }
+  }
+
+  #[test]
+  fn truncated_instruction_is_invalid() {
+    // an OpVariable that says it has 4 words with only 2 left
+    let code = vec![SPIRV_MAGIC, 0x0001_0000, 0, 10, 0, 4 << 16 | OP_VARIABLE, 1];
+    match ShaderReflection::reflect("truncated", &code) {
+      Err(Error::InvalidSpirv(_, message)) => assert!(message.contains("truncated")),
+      _ => panic!("expected InvalidSpirv"),
+    }
+  }
+
+  #[test]
+  fn vertex_input_needs_an_attribute_of_the_same_numeric_type() {
+    let reflection = reflect_shader("tri_mesh.vert");
+    let mut description = VertexInputDescription::new();
+    description.attributes = vec![
+      attribute(0, VK_FORMAT_R32G32B32_SFLOAT),
+      attribute(1, VK_FORMAT_R32G32B32_SFLOAT),
+      attribute(2, VK_FORMAT_R8G8B8A8_UNORM),
+      attribute(3, VK_FORMAT_R32G32_SFLOAT),
+    ];
+    assert!(reflection.check_vertex_input(&description).is_ok());
+
+    description.attributes.pop();
+    match reflection.check_vertex_input(&description) {
+      Err(Error::VertexInputMismatch(_, location, _)) => assert_eq!(location, 3),
+      _ => panic!("expected VertexInputMismatch for the missing attribute"),
+    }
+
+    description
+      .attributes
+      .push(attribute(3, VK_FORMAT_R32G32_SFLOAT));
+    description.attributes[0] = attribute(0, VK_FORMAT_R32G32B32_SINT);
+    match reflection.check_vertex_input(&description) {
+      Err(Error::VertexInputMismatch(_, location, _)) => assert_eq!(location, 0),
+      _ => panic!("expected VertexInputMismatch for the integer attribute"),
+    }
+  }
+
+  #[test]
+  fn overlapping_push_constant_ranges_are_merged() {
+    let mut builder = PipelineLayoutBuilder::new();
+    builder
+      .shader(&with_push_constants(VK_SHADER_STAGE_VERTEX_BIT, 0, 80))
+      .shader(&with_push_constants(VK_SHADER_STAGE_FRAGMENT_BIT, 64, 32));
+    let ranges = builder.push_constant_ranges();
+    assert_eq!(ranges.len(), 1);
+    assert_eq!(
+      ranges[0].stageFlags,
+      VK_SHADER_STAGE_VERTEX_BIT | VK_SHADER_STAGE_FRAGMENT_BIT
+    );
+    assert_eq!((ranges[0].offset, ranges[0].size), (0, 96));
+    assert_eq!(
+      builder.push_constant_stages(),
+      VK_SHADER_STAGE_VERTEX_BIT | VK_SHADER_STAGE_FRAGMENT_BIT
+    );
+  }
+
+  #[test]
+  fn separate_push_constant_ranges_stay_apart() {
+    let mut builder = PipelineLayoutBuilder::new();
+    builder
+      .shader(&with_push_constants(VK_SHADER_STAGE_VERTEX_BIT, 0, 64))
+      .shader(&with_push_constants(VK_SHADER_STAGE_FRAGMENT_BIT, 64, 16));
+    let ranges = builder.push_constant_ranges();
+    assert_eq!(ranges.len(), 2);
+    assert_eq!((ranges[0].offset, ranges[0].size), (0, 64));
+    assert_eq!((ranges[1].offset, ranges[1].size), (64, 16));
+    assert_eq!(builder.push_constant_stages(), VK_SHADER_STAGE_VERTEX_BIT);
+
+    // a third block spanning both joins them
+    builder.shader(&with_push_constants(VK_SHADER_STAGE_GEOMETRY_BIT, 32, 40));
+    let ranges = builder.push_constant_ranges();
+    assert_eq!(ranges.len(), 1);
+    assert_eq!((ranges[0].offset, ranges[0].size), (0, 80));
+  }
+}
@@ -0,0 +1,347 @@
+use crate::error::Error;
+
+#[cfg(feature = "hot_reload")]
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  time::{Duration, Instant, SystemTime},
+};
+
+// The pipelines ask for shaders by their .spv name. Normally that is the SPIR-V that
+// compile_shaders.sh wrote next to the GLSL. With the hot_reload feature the GLSL is
+// compiled in process with naga instead, so the pipelines always match the sources
+// and the sources can be edited while the engine runs.
+
+// SPIR-V words for the shader, the GLSL is compiled first with hot_reload
+pub fn load_shader(spv_path: &str) -> Result<Vec<u32>, Error> {
+  #[cfg(feature = "hot_reload")]
+  return compile_glsl(source_path(spv_path));
+
+  #[cfg(not(feature = "hot_reload"))]
+  return load_spirv(spv_path);
+}
+
+// SPIR-V read from disk. Read into u32s, a Vec<u8> is not aligned for vkCreateShaderModule
+#[cfg(not(feature = "hot_reload"))]
+pub fn load_spirv(spv_path: &str) -> Result<Vec<u32>, Error> {
+  let bytes = std::fs::read(spv_path).map_err(|e| Error::FromIO(e))?;
+  if bytes.len() % 4 != 0 {
+    return Err(Error::String(format!(
+      "{} is not SPIR-V, its size is not a multiple of 4",
+      spv_path
+    )));
+  }
+  Ok(
+    bytes
+      .chunks_exact(4)
+      .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+      .collect(),
+  )
+}
+
+// the GLSL a .spv is compiled from, shaders/tri_mesh.vert.spv -> shaders/tri_mesh.vert
+#[cfg(feature = "hot_reload")]
+pub fn source_path(spv_path: &str) -> &Path {
+  Path::new(spv_path.trim_end_matches(".spv"))
+}
+
+// compiles a GLSL file, the stage comes from the extension like it does for glslc
+#[cfg(feature = "hot_reload")]
+pub fn compile_glsl(path: &Path) -> Result<Vec<u32>, Error> {
+  use naga::{
+    back::spv,
+    front::glsl,
+    valid::{Capabilities, ValidationFlags, Validator},
+    ShaderStage,
+  };
+
+  let name = path.to_string_lossy().into_owned();
+  let stage = match path.extension().and_then(|e| e.to_str()) {
+    Some("vert") => ShaderStage::Vertex,
+    Some("frag") => ShaderStage::Fragment,
+    Some("comp") => ShaderStage::Compute,
+    _ => {
+      return Err(Error::String(format!(
+        "{} is not a .vert, .frag or .comp shader",
+        name
+      )))
+    }
+  };
+
+  let source = std::fs::read_to_string(path).map_err(|e| Error::FromIO(e))?;
+  let source = split_combined_samplers(&source);
+
+  // only the first error is reported, the rest are often caused by it
+  let module = glsl::Frontend::default()
+    .parse(&glsl::Options::from(stage), &source)
+    .map_err(|errors| {
+      let error = &errors[0];
+      let location = error.meta.location(&source);
+      Error::ShaderCompile(
+        name.clone(),
+        location.line_number,
+        location.line_position,
+        error.kind.to_string(),
+      )
+    })?;
+
+  let info = Validator::new(ValidationFlags::all(), Capabilities::all())
+    .validate(&module)
+    .map_err(|error| {
+      let (line, column) = match error.location(&source) {
+        Some(location) => (location.line_number, location.line_position),
+        None => (0, 0),
+      };
+      Error::ShaderCompile(name.clone(), line, column, error.as_inner().to_string())
+    })?;
+
+  let mut code = spv::write_vec(&module, &info, &spv::Options::default(), None)
+    .map_err(|e| Error::ShaderCompile(name.clone(), 0, 0, e.to_string()))?;
+  join_sampler_bindings(&mut code);
+  Ok(code)
+}
+
+// naga has no combined image samplers. A sampler2D is split into a texture2D and a
+// sampler before parsing, the sampler at its binding + SPLIT_SAMPLER_BINDING so every
+// binding is still used once. join_sampler_bindings moves it back in the SPIR-V,
+// Vulkan lets the two share the binding of a combined image sampler descriptor.
+#[cfg(feature = "hot_reload")]
+const SPLIT_SAMPLER_BINDING: u32 = 1 << 16;
+
+// The sampler2D declarations get their sampler on the same line, so the line numbers of
+// the errors still match the file. The uses of the sampler2D are made up from the two.
+// This works on lines, not on GLSL tokens, so it has limits:
+// - a declaration has to be on one line, from its layout to the `;`
+// - one sampler2D per declaration, `uniform sampler2D a, b;` isn't split
+// - declarations after a `//` are left alone, ones inside /* */ are still split
+// - the uses are replaced wherever the name is a whole identifier, comments included,
+//   which only changes text the compiler ignores anyway
+#[cfg(feature = "hot_reload")]
+fn split_combined_samplers(source: &str) -> String {
+  let mut names: Vec<String> = Vec::new();
+  let mut lines = Vec::new();
+  for line in source.lines() {
+    match split_sampler_declaration(line) {
+      Some((split, name)) => {
+        lines.push(split);
+        names.push(name);
+      }
+      None => {
+        let mut line = line.to_string();
+        for name in &names {
+          line = replace_identifier(&line, name, &format!("sampler2D({0}, {0}_sampler)", name));
+        }
+        lines.push(line);
+      }
+    }
+  }
+  lines.join("\n")
+}
+
+// `layout(set = 1, binding = 0) uniform sampler2D tex;` -> the texture2D and sampler
+// declarations and the name, None for any other line. Any spacing between the words.
+#[cfg(feature = "hot_reload")]
+fn split_sampler_declaration(line: &str) -> Option<(String, String)> {
+  let code = match line.find("//") {
+    Some(comment) => &line[..comment],
+    None => line,
+  };
+  let uniform = code.find("uniform")?;
+  let rest = code[uniform + "uniform".len()..].trim_start();
+  if !rest.starts_with("sampler2D") {
+    return None;
+  }
+  let rest = &rest["sampler2D".len()..];
+  // sampler2DArray and friends aren't split
+  if !rest.starts_with(char::is_whitespace) {
+    return None;
+  }
+  let name = rest[..rest.find(';')?].trim();
+  if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+    return None;
+  }
+
+  // the layout qualifiers with the number after binding = moved
+  let layout = &code[..uniform];
+  let binding = layout.find("binding")?;
+  let after_binding = &layout[binding..];
+  let value = after_binding[after_binding.find('=')? + 1..].trim_start();
+  let digits: String = value.chars().take_while(|c| c.is_ascii_digit()).collect();
+  let binding_number: u32 = digits.parse().ok()?;
+  let value_start = layout.len() - value.len();
+  let sampler_layout = format!(
+    "{}{}{}",
+    &layout[..value_start],
+    binding_number + SPLIT_SAMPLER_BINDING,
+    &layout[value_start + digits.len()..]
+  );
+
+  Some((
+    format!(
+      "{0}uniform texture2D {1}; {2}uniform sampler {1}_sampler;",
+      layout, name, sampler_layout
+    ),
+    name.to_string(),
+  ))
+}
+
+// replaces name where it's a whole identifier, not part of a longer one
+#[cfg(feature = "hot_reload")]
+fn replace_identifier(line: &str, name: &str, replacement: &str) -> String {
+  let is_identifier = |c: char| c.is_ascii_alphanumeric() || c == '_';
+  let mut result = String::with_capacity(line.len());
+  let mut rest = line;
+  while let Some(at) = rest.find(name) {
+    result.push_str(&rest[..at]);
+    let before = result.chars().next_back();
+    let after = rest[at + name.len()..].chars().next();
+    if before.map_or(false, is_identifier) || after.map_or(false, is_identifier) {
+      result.push_str(name);
+    } else {
+      result.push_str(replacement);
+    }
+    rest = &rest[at + name.len()..];
+  }
+  result.push_str(rest);
+  result
+}
+
+// moves the samplers split_combined_samplers made back to the binding of their texture
+#[cfg(feature = "hot_reload")]
+fn join_sampler_bindings(code: &mut [u32]) {
+  const OP_DECORATE: u32 = 71;
+  const DECORATION_BINDING: u32 = 33;
+  // the instructions start after the 5 word header
+  let mut i = 5;
+  while i < code.len() {
+    let word_count = (code[i] >> 16) as usize;
+    if word_count == 0 || i + word_count > code.len() {
+      break;
+    }
+    if code[i] & 0xffff == OP_DECORATE
+      && word_count == 4
+      && code[i + 2] == DECORATION_BINDING
+      && code[i + 3] >= SPLIT_SAMPLER_BINDING
+    {
+      code[i + 3] -= SPLIT_SAMPLER_BINDING;
+    }
+    i += word_count;
+  }
+}
+
+// how often the shader directory is looked at, checking every frame is wasteful
+#[cfg(feature = "hot_reload")]
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+// Watches the GLSL sources of a directory by their modification time. Polling keeps
+// it free of platform specific file notification APIs.
+#[cfg(feature = "hot_reload")]
+pub struct ShaderWatcher {
+  directory: PathBuf,
+  modified: HashMap<PathBuf, SystemTime>,
+  last_poll: Instant,
+}
+
+#[cfg(feature = "hot_reload")]
+impl ShaderWatcher {
+  pub fn new(directory: &str) -> ShaderWatcher {
+    let mut watcher = ShaderWatcher {
+      directory: PathBuf::from(directory),
+      modified: HashMap::new(),
+      last_poll: Instant::now(),
+    };
+    // the first look only records the times, nothing has changed yet
+    watcher.modified = watcher.scan();
+    watcher
+  }
+
+  // the sources that were written since the last poll
+  pub fn poll(&mut self) -> Vec<PathBuf> {
+    if self.last_poll.elapsed() < POLL_INTERVAL {
+      return Vec::new();
+    }
+    self.last_poll = Instant::now();
+
+    let modified = self.scan();
+    let changed = modified
+      .iter()
+      .filter(|(path, time)| self.modified.get(*path) != Some(*time))
+      .map(|(path, _)| path.clone())
+      .collect();
+    self.modified = modified;
+    changed
+  }
+
+  fn scan(&self) -> HashMap<PathBuf, SystemTime> {
+    let mut modified = HashMap::new();
+    let entries = match std::fs::read_dir(&self.directory) {
+      Ok(entries) => entries,
+      Err(_) => return modified,
+    };
+    for entry in entries.flatten() {
+      let path = entry.path();
+      let is_source = match path.extension().and_then(|e| e.to_str()) {
+        Some("vert") | Some("frag") | Some("comp") => true,
+        _ => false,
+      };
+      if let (true, Ok(time)) = (is_source, entry.metadata().and_then(|m| m.modified())) {
+        modified.insert(path, time);
+      }
+    }
+    modified
+  }
+}
+
+#[cfg(all(test, feature = "hot_reload"))]
+mod tests {
+  use {super::*, crate::vk_reflect::ShaderReflection, vkcapi::core::v1_0::*};
+
+  #[test]
+  fn sampler2d_is_split_on_the_same_line() {
+    let source = "layout(set = 1, binding = 0) uniform sampler2D tex;\n\
+                  vec4 a = texture(tex, uv) + texture(tex2, uv);";
+    assert_eq!(
+      split_combined_samplers(source),
+      "layout(set = 1, binding = 0) uniform texture2D tex; \
+       layout(set = 1, binding = 65536) uniform sampler tex_sampler;\n\
+       vec4 a = texture(sampler2D(tex, tex_sampler), uv) + texture(tex2, uv);"
+    );
+  }
+
+  #[test]
+  fn split_sampler_goes_back_to_the_texture_binding() {
+    let path = Path::new("shaders/textured_lit.frag");
+    let code = compile_glsl(path).unwrap();
+    let reflection = ShaderReflection::reflect("textured_lit.frag", &code).unwrap();
+    let texture = reflection
+      .bindings
+      .iter()
+      .find(|binding| binding.set == 1)
+      .unwrap();
+    assert_eq!(texture.binding, 0);
+    assert_eq!(
+      texture.descriptor_type,
+      VK_DESCRIPTOR_TYPE_COMBINED_IMAGE_SAMPLER
+    );
+  }
+
+  #[test]
+  fn sampler2d_declarations_can_have_any_spacing() {
+    let (split, name) =
+      split_sampler_declaration("layout(binding = 2) uniform  sampler2D\ttex ;").unwrap();
+    assert_eq!(name, "tex");
+    assert_eq!(
+      split,
+      "layout(binding = 2) uniform texture2D tex; \
+       layout(binding = 65538) uniform sampler tex_sampler;"
+    );
+  }
+
+  #[test]
+  fn only_sampler2d_declarations_are_split() {
+    assert!(split_sampler_declaration("// layout(binding = 0) uniform sampler2D tex;").is_none());
+    assert!(split_sampler_declaration("layout(binding = 0) uniform sampler2DArray tex;").is_none());
+    assert!(split_sampler_declaration("layout(binding = 0) uniform sampler2D a, b;").is_none());
+    assert!(split_sampler_declaration("uniform sampler2D tex;").is_none());
+  }
+}
@@ -0,0 +1,302 @@
+use {
+  crate::{
+    error::Error,
+    vk_types::{Image, ImageView, Sampler},
+  },
+  std::ptr::null,
+  vkcapi::core::v1_0::*,
+};
+
+// Textures are always uploaded as 8 bit RGBA. Whatever the file had gets converted
+// on the CPU, the GPU then builds the mip chain by blitting each level from the one
+// above it.
+
+// Colors are stored in sRGB, sampling turns them back into linear values. Normal maps,
+// roughness and the like are data, they are sampled as they are.
+pub const COLOR_TEXTURE_FORMAT: VkFormat = VK_FORMAT_R8G8B8A8_SRGB;
+pub const DATA_TEXTURE_FORMAT: VkFormat = VK_FORMAT_R8G8B8A8_UNORM;
+
+// decoded pixels, 4 bytes per pixel, rows top to bottom
+#[derive(Clone)]
+pub struct TextureData {
+  pub width: u32,
+  pub height: u32,
+  pub pixels: Vec<u8>,
+  pub srgb: bool, // the pixels are colors, not data
+}
+
+impl TextureData {
+  // loads a PNG or JPEG file
+  pub fn load(filename: &str, srgb: bool) -> Result<TextureData, Error> {
+    let image = image::open(filename)
+      .map_err(|e| Error::FromImage(e))?
+      .to_rgba8();
+    Ok(TextureData {
+      width: image.width(),
+      height: image.height(),
+      pixels: image.into_raw(),
+      srgb,
+    })
+  }
+
+  // gltf::import has already decoded the images, they only need to become RGBA
+  pub fn from_gltf(data: &gltf::image::Data, name: &str, srgb: bool) -> Result<TextureData, Error> {
+    use gltf::image::Format;
+
+    let pixel_count = (data.width * data.height) as usize;
+    let mut pixels = Vec::with_capacity(pixel_count * 4);
+    match data.format {
+      Format::R8 => {
+        for r in &data.pixels {
+          pixels.extend_from_slice(&[*r, *r, *r, 255]);
+        }
+      }
+      Format::R8G8 => {
+        for rg in data.pixels.chunks_exact(2) {
+          pixels.extend_from_slice(&[rg[0], rg[1], 0, 255]);
+        }
+      }
+      Format::R8G8B8 => {
+        for rgb in data.pixels.chunks_exact(3) {
+          pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+        }
+      }
+      Format::R8G8B8A8 => pixels.extend_from_slice(&data.pixels),
+      Format::B8G8R8 => {
+        for bgr in data.pixels.chunks_exact(3) {
+          pixels.extend_from_slice(&[bgr[2], bgr[1], bgr[0], 255]);
+        }
+      }
+      Format::B8G8R8A8 => {
+        for bgra in data.pixels.chunks_exact(4) {
+          pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+        }
+      }
+      // 16 bit images would lose precision, better to know about it
+      format => {
+        return Err(Error::UnsupportedImageFormat(
+          name.to_string(),
+          format!("{:?}", format),
+        ))
+      }
+    }
+
+    Ok(TextureData {
+      width: data.width,
+      height: data.height,
+      pixels,
+      srgb,
+    })
+  }
+
+  // the format the texture is created with
+  pub fn format(&self) -> VkFormat {
+    if self.srgb {
+      COLOR_TEXTURE_FORMAT
+    } else {
+      DATA_TEXTURE_FORMAT
+    }
+  }
+
+  // number of levels until the image is 1x1
+  pub fn mip_levels(&self) -> u32 {
+    32 - u32::max(self.width, self.height).max(1).leading_zeros()
+  }
+}
+
+// a sampled image with its mip chain, ready to be bound as a combined image sampler
+pub struct Texture {
+  pub image: Image,
+  pub image_view: ImageView,
+  pub sampler: Sampler,
+  pub mip_levels: u32,
+}
+
+// Records the copy from the staging buffer into mip 0 and the blits that fill the
+// other levels. Every level ends up in SHADER_READ_ONLY_OPTIMAL.
+pub unsafe fn cmd_upload_texture(
+  cmd: VkCommandBuffer,
+  staging_buffer: VkBuffer,
+  image: VkImage,
+  width: u32,
+  height: u32,
+  mip_levels: u32,
+) {
+  // the whole image goes to transfer destination first, the copy and the blits write to it
+  image_barrier(
+    cmd,
+    image,
+    0,
+    mip_levels,
+    VK_IMAGE_LAYOUT_UNDEFINED,
+    VK_IMAGE_LAYOUT_TRANSFER_DST_OPTIMAL,
+    0,
+    VK_ACCESS_TRANSFER_WRITE_BIT,
+    VK_PIPELINE_STAGE_TOP_OF_PIPE_BIT,
+    VK_PIPELINE_STAGE_TRANSFER_BIT,
+  );
+
+  let copy_region = VkBufferImageCopy {
+    bufferOffset: 0,
+    bufferRowLength: 0,
+    bufferImageHeight: 0,
+    imageSubresource: VkImageSubresourceLayers {
+      aspectMask: VK_IMAGE_ASPECT_COLOR_BIT,
+      mipLevel: 0,
+      baseArrayLayer: 0,
+      layerCount: 1,
+    },
+    imageOffset: VkOffset3D { x: 0, y: 0, z: 0 },
+    imageExtent: VkExtent3D {
+      width,
+      height,
+      depth: 1,
+    },
+  };
+  // copy the buffer into mip 0
+  vkCmdCopyBufferToImage(
+    cmd,
+    staging_buffer,
+    image,
+    VK_IMAGE_LAYOUT_TRANSFER_DST_OPTIMAL,
+    1,
+    &copy_region,
+  );
+
+  let mut mip_width = width as i32;
+  let mut mip_height = height as i32;
+  for level in 1..mip_levels {
+    // the level above is done being written, now it's read by the blit
+    image_barrier(
+      cmd,
+      image,
+      level - 1,
+      1,
+      VK_IMAGE_LAYOUT_TRANSFER_DST_OPTIMAL,
+      VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+      VK_ACCESS_TRANSFER_WRITE_BIT,
+      VK_ACCESS_TRANSFER_READ_BIT,
+      VK_PIPELINE_STAGE_TRANSFER_BIT,
+      VK_PIPELINE_STAGE_TRANSFER_BIT,
+    );
+
+    let next_width = i32::max(mip_width / 2, 1);
+    let next_height = i32::max(mip_height / 2, 1);
+    let blit = VkImageBlit {
+      srcSubresource: VkImageSubresourceLayers {
+        aspectMask: VK_IMAGE_ASPECT_COLOR_BIT,
+        mipLevel: level - 1,
+        baseArrayLayer: 0,
+        layerCount: 1,
+      },
+      srcOffsets: [
+        VkOffset3D { x: 0, y: 0, z: 0 },
+        VkOffset3D {
+          x: mip_width,
+          y: mip_height,
+          z: 1,
+        },
+      ],
+      dstSubresource: VkImageSubresourceLayers {
+        aspectMask: VK_IMAGE_ASPECT_COLOR_BIT,
+        mipLevel: level,
+        baseArrayLayer: 0,
+        layerCount: 1,
+      },
+      dstOffsets: [
+        VkOffset3D { x: 0, y: 0, z: 0 },
+        VkOffset3D {
+          x: next_width,
+          y: next_height,
+          z: 1,
+        },
+      ],
+    };
+    vkCmdBlitImage(
+      cmd,
+      image,
+      VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+      image,
+      VK_IMAGE_LAYOUT_TRANSFER_DST_OPTIMAL,
+      1,
+      &blit,
+      VK_FILTER_LINEAR,
+    );
+
+    // the level above won't be touched again, hand it to the shaders
+    image_barrier(
+      cmd,
+      image,
+      level - 1,
+      1,
+      VK_IMAGE_LAYOUT_TRANSFER_SRC_OPTIMAL,
+      VK_IMAGE_LAYOUT_SHADER_READ_ONLY_OPTIMAL,
+      VK_ACCESS_TRANSFER_READ_BIT,
+      VK_ACCESS_SHADER_READ_BIT,
+      VK_PIPELINE_STAGE_TRANSFER_BIT,
+      VK_PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+    );
+
+    mip_width = next_width;
+    mip_height = next_height;
+  }
+
+  // the last level was only written, it never became a blit source
+  image_barrier(
+    cmd,
+    image,
+    mip_levels - 1,
+    1,
+    VK_IMAGE_LAYOUT_TRANSFER_DST_OPTIMAL,
+    VK_IMAGE_LAYOUT_SHADER_READ_ONLY_OPTIMAL,
+    VK_ACCESS_TRANSFER_WRITE_BIT,
+    VK_ACCESS_SHADER_READ_BIT,
+    VK_PIPELINE_STAGE_TRANSFER_BIT,
+    VK_PIPELINE_STAGE_FRAGMENT_SHADER_BIT,
+  );
+}
+
+// layout transition of a range of mip levels of a color image
+pub unsafe fn image_barrier(
+  cmd: VkCommandBuffer,
+  image: VkImage,
+  base_mip_level: u32,
+  level_count: u32,
+  old_layout: VkImageLayout,
+  new_layout: VkImageLayout,
+  src_access_mask: VkAccessFlags,
+  dst_access_mask: VkAccessFlags,
+  src_stage_mask: VkPipelineStageFlags,
+  dst_stage_mask: VkPipelineStageFlags,
+) {
+  let barrier = VkImageMemoryBarrier {
+    sType: VK_STRUCTURE_TYPE_IMAGE_MEMORY_BARRIER,
+    pNext: null(),
+    srcAccessMask: src_access_mask,
+    dstAccessMask: dst_access_mask,
+    oldLayout: old_layout,
+    newLayout: new_layout,
+    srcQueueFamilyIndex: VK_QUEUE_FAMILY_IGNORED,
+    dstQueueFamilyIndex: VK_QUEUE_FAMILY_IGNORED,
+    image,
+    subresourceRange: VkImageSubresourceRange {
+      aspectMask: VK_IMAGE_ASPECT_COLOR_BIT,
+      baseMipLevel: base_mip_level,
+      levelCount: level_count,
+      baseArrayLayer: 0,
+      layerCount: 1,
+    },
+  };
+  vkCmdPipelineBarrier(
+    cmd,
+    src_stage_mask,
+    dst_stage_mask,
+    0,
+    0,
+    null(),
+    0,
+    null(),
+    1,
+    &barrier,
+  );
+}
@@ -1,34 +1,248 @@
 use {
-  std::ptr::null,
-  {vkcapi::core::v1_0::*, vma::*},
+  sdl2::*,
+  std::{any::Any, ptr::null, rc::Rc},
+  vkcapi::{
+    core::v1_0::*,
+    ext::{vk_khr_surface::*, vk_khr_swapchain::*},
+  },
+  vma::*,
 };
 
-#[derive(Clone, Copy)]
-pub struct AllocatedBuffer {
+#[cfg(feature = "validation")]
+use vkcapi::ext::vk_ext_debug_utils::*;
+
+// Every Vulkan object the engine creates is owned by one of the types below and is
+// destroyed when it's dropped. They all hold on to the DeviceContext they were made
+// from, so the device outlives everything created from it no matter in which order
+// things are dropped, and whatever got created before a failed init is cleaned up too.
+//
+// The null() versions stand in for things that aren't created yet, dropping them does
+// nothing.
+
+// The instance and device level handles. They are destroyed together when the last
+// handle referring to them is gone. A handle that is still null wasn't created, so
+// a context from a failed init only destroys what it got.
+pub struct DeviceContext {
+  pub instance: VkInstance,
+  #[cfg(feature = "validation")]
+  pub debug_messenger: VkDebugUtilsMessengerEXT,
+  pub surface: VkSurfaceKHR, // null in headless mode
+  pub device: VkDevice,
+  pub allocator: VmaAllocator,
+}
+
+impl DeviceContext {
+  pub fn null() -> DeviceContext {
+    DeviceContext {
+      instance: null(),
+      #[cfg(feature = "validation")]
+      debug_messenger: null(),
+      surface: null(),
+      device: null(),
+      allocator: null(),
+    }
+  }
+}
+
+impl Drop for DeviceContext {
+  fn drop(&mut self) {
+    unsafe {
+      if !self.allocator.is_null() {
+        vmaDestroyAllocator(self.allocator);
+      }
+      if !self.device.is_null() {
+        vkDestroyDevice(self.device, null());
+      }
+      if !self.surface.is_null() {
+        vkDestroySurfaceKHR(self.instance, self.surface, null());
+      }
+      #[cfg(feature = "validation")]
+      {
+        if !self.debug_messenger.is_null() {
+          vkDestroyDebugUtilsMessengerEXT(self.instance, self.debug_messenger, null());
+        }
+      }
+      if !self.instance.is_null() {
+        vkDestroyInstance(self.instance, null());
+      }
+    }
+  }
+}
+
+// a handle destroyed with vkDestroy<Name>(device, handle, null)
+macro_rules! device_handle {
+  ($name:ident, $handle:ty, $destroy:ident) => {
+    pub struct $name {
+      context: Option<Rc<DeviceContext>>,
+      pub handle: $handle,
+    }
+
+    impl $name {
+      // takes over a handle created from the device of context
+      pub fn new(context: &Rc<DeviceContext>, handle: $handle) -> $name {
+        $name {
+          context: Some(context.clone()),
+          handle,
+        }
+      }
+
+      pub fn null() -> $name {
+        $name {
+          context: None,
+          handle: null(),
+        }
+      }
+    }
+
+    impl Drop for $name {
+      fn drop(&mut self) {
+        if let Some(context) = &self.context {
+          unsafe { $destroy(context.device, self.handle, null()) };
+        }
+      }
+    }
+  };
+}
+
+device_handle!(Swapchain, VkSwapchainKHR, vkDestroySwapchainKHR);
+device_handle!(ImageView, VkImageView, vkDestroyImageView);
+device_handle!(Sampler, VkSampler, vkDestroySampler);
+device_handle!(RenderPass, VkRenderPass, vkDestroyRenderPass);
+device_handle!(Framebuffer, VkFramebuffer, vkDestroyFramebuffer);
+device_handle!(CommandPool, VkCommandPool, vkDestroyCommandPool);
+device_handle!(Fence, VkFence, vkDestroyFence);
+device_handle!(Semaphore, VkSemaphore, vkDestroySemaphore);
+device_handle!(ShaderModule, VkShaderModule, vkDestroyShaderModule);
+device_handle!(PipelineLayout, VkPipelineLayout, vkDestroyPipelineLayout);
+device_handle!(Pipeline, VkPipeline, vkDestroyPipeline);
+device_handle!(PipelineCache, VkPipelineCache, vkDestroyPipelineCache);
+device_handle!(
+  DescriptorSetLayout,
+  VkDescriptorSetLayout,
+  vkDestroyDescriptorSetLayout
+);
+device_handle!(DescriptorPool, VkDescriptorPool, vkDestroyDescriptorPool);
+
+// a buffer and the memory VMA gave it
+pub struct Buffer {
+  context: Option<Rc<DeviceContext>>,
   pub buffer: VkBuffer,
   pub allocation: VmaAllocation,
 }
 
-impl AllocatedBuffer {
-  pub fn null() -> AllocatedBuffer {
-    AllocatedBuffer {
+impl Buffer {
+  // takes over a buffer created with the allocator of context
+  pub fn new(context: &Rc<DeviceContext>, buffer: VkBuffer, allocation: VmaAllocation) -> Buffer {
+    Buffer {
+      context: Some(context.clone()),
+      buffer,
+      allocation,
+    }
+  }
+
+  pub fn null() -> Buffer {
+    Buffer {
+      context: None,
       buffer: null(),
       allocation: null(),
     }
   }
 }
 
-#[derive(Clone, Copy)]
-pub struct AllocatedImage {
+impl Drop for Buffer {
+  fn drop(&mut self) {
+    if let Some(context) = &self.context {
+      unsafe { vmaDestroyBuffer(context.allocator, self.buffer, self.allocation) };
+    }
+  }
+}
+
+// an image and the memory VMA gave it
+pub struct Image {
+  context: Option<Rc<DeviceContext>>,
   pub image: VkImage,
   pub allocation: VmaAllocation,
 }
 
-impl AllocatedImage {
-  pub fn null() -> AllocatedImage {
-    AllocatedImage {
+impl Image {
+  // takes over an image created with the allocator of context
+  pub fn new(context: &Rc<DeviceContext>, image: VkImage, allocation: VmaAllocation) -> Image {
+    Image {
+      context: Some(context.clone()),
+      image,
+      allocation,
+    }
+  }
+
+  pub fn null() -> Image {
+    Image {
+      context: None,
       image: null(),
       allocation: null(),
     }
   }
 }
+
+impl Drop for Image {
+  fn drop(&mut self) {
+    if let Some(context) = &self.context {
+      unsafe { vmaDestroyImage(context.allocator, self.image, self.allocation) };
+    }
+  }
+}
+
+// The SDL window, SDL is shut down with it. The surface is made from the window, so
+// it has to be dropped after the DeviceContext.
+pub struct Window {
+  pub handle: *mut SDL_Window,
+}
+
+impl Window {
+  // takes over a window created after SDL_Init
+  pub fn new(handle: *mut SDL_Window) -> Window {
+    Window { handle }
+  }
+
+  pub fn null() -> Window {
+    Window {
+      handle: std::ptr::null_mut(),
+    }
+  }
+}
+
+impl Drop for Window {
+  fn drop(&mut self) {
+    if !self.handle.is_null() {
+      unsafe {
+        SDL_DestroyWindow(self.handle);
+        SDL_Quit();
+      }
+    }
+  }
+}
+
+// Resources the GPU may still be reading, e.g. pipelines replaced while frames are in
+// flight. Each frame has one, it's flushed once the frame's fence says the GPU is done.
+pub struct DeletionQueue {
+  resources: Vec<Box<dyn Any>>,
+}
+
+impl DeletionQueue {
+  pub fn new() -> DeletionQueue {
+    DeletionQueue {
+      resources: Vec::new(),
+    }
+  }
+
+  // anything owning handles, like a Pipeline or a Vec of them
+  pub fn push<T: 'static>(&mut self, resource: T) {
+    self.resources.push(Box::new(resource));
+  }
+
+  // drops the resources, newest first
+  pub fn flush(&mut self) {
+    while let Some(resource) = self.resources.pop() {
+      drop(resource);
+    }
+  }
+}